@@ -0,0 +1,61 @@
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{DeriveInput, Lit, Meta};
+
+/// Implements `net::protocol::ProtocolMessage`, binding a type to the
+/// protocol id given in the `protocol_id` attribute:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, ProtocolMessage)]
+/// #[protocol_id = 42]
+/// struct Ping {
+///     nonce: u64,
+/// }
+/// ```
+#[proc_macro_derive(ProtocolMessage, attributes(protocol_id))]
+pub fn derive_protocol_message(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).expect("ProtocolMessage: cannot parse input");
+    let protocol_id = protocol_id(&input);
+
+    let name = &input.ident;
+    let name_str = name.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics ::net::protocol::ProtocolMessage for #name #ty_generics #where_clause {
+            const PROTOCOL_ID: u16 = #protocol_id;
+            const NAME: &'static str = #name_str;
+        }
+    };
+
+    expanded.into()
+}
+
+fn protocol_id(input: &DeriveInput) -> u16 {
+    let mut attrs = input
+        .attrs
+        .iter()
+        .filter_map(|a| a.interpret_meta())
+        .filter(|m| m.name() == "protocol_id");
+
+    let meta = match attrs.next() {
+        Some(meta) => meta,
+        None => panic!("ProtocolMessage: missing #[protocol_id = <u16>] attribute"),
+    };
+    if attrs.next().is_some() {
+        panic!("ProtocolMessage: duplicate protocol_id attribute");
+    }
+
+    match meta {
+        Meta::NameValue(ref nv) => match nv.lit {
+            Lit::Int(ref i) if i.value() <= u16::max_value() as u64 => i.value() as u16,
+            _ => panic!("ProtocolMessage: protocol_id must be an integer in 0..=65535"),
+        },
+        _ => panic!("ProtocolMessage: expected #[protocol_id = <u16>]"),
+    }
+}
@@ -0,0 +1,39 @@
+extern crate net;
+#[macro_use]
+extern crate net_derive;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+use net::protocol::{ProtocolMessage, ProtocolRegistry};
+
+#[derive(Serialize, Deserialize, ProtocolMessage, Debug, PartialEq)]
+#[protocol_id = 42]
+struct Ping {
+    nonce: u64,
+}
+
+#[derive(Serialize, Deserialize, ProtocolMessage, Debug, PartialEq)]
+#[protocol_id = 42]
+struct Pong {
+    nonce: u64,
+}
+
+#[test]
+fn test_derive() {
+    assert_eq!(42, Ping::PROTOCOL_ID);
+    assert_eq!("Ping", Ping::NAME);
+
+    let ping = Ping { nonce: 7 };
+    let encapsulated = ping.encapsulate().unwrap();
+    assert_eq!(42, encapsulated.protocol_id);
+    assert_eq!(ping, Ping::decapsulate(&encapsulated).unwrap());
+}
+
+#[test]
+fn test_collision() {
+    let mut registry = ProtocolRegistry::new();
+    assert!(registry.register::<Ping>().is_ok());
+    assert!(registry.register::<Ping>().is_ok());
+    assert!(registry.register::<Pong>().is_err());
+}
@@ -0,0 +1,90 @@
+use std::io::{Read, Write};
+
+use lz4;
+use zstd;
+
+use codec::error::CodecError;
+
+/// zstd compression level
+const ZSTD_LEVEL: i32 = 3;
+/// lz4 compression level
+const LZ4_LEVEL: u32 = 4;
+
+/// Payload compression algorithms
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+/// Algorithm preference, most preferred first. Both peers pick the first
+/// entry they have in common, so negotiation yields the same result on
+/// either end.
+const PREFERENCE: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+impl Compression {
+    pub fn supported() -> Vec<Compression> {
+        PREFERENCE.to_vec()
+    }
+
+    pub fn negotiate(local: &[Compression], remote: &[Compression]) -> Option<Compression> {
+        PREFERENCE
+            .iter()
+            .find(|c| local.contains(*c) && remote.contains(*c))
+            .cloned()
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CodecError> {
+        match *self {
+            Compression::Zstd => Ok(zstd::stream::encode_all(data, ZSTD_LEVEL)?),
+            Compression::Lz4 => {
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .level(LZ4_LEVEL)
+                    .build(Vec::with_capacity(data.len()))?;
+                encoder.write_all(data)?;
+
+                let (compressed, result) = encoder.finish();
+                result?;
+                Ok(compressed)
+            }
+        }
+    }
+
+    /// Decompresses `data`, refusing to produce more than `limit` bytes
+    pub fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, CodecError> {
+        let mut decompressed = Vec::new();
+        // read one byte past the limit to detect overflow
+        let take = limit as u64 + 1;
+
+        match *self {
+            Compression::Zstd => {
+                let decoder = zstd::stream::Decoder::new(data)?;
+                decoder.take(take).read_to_end(&mut decompressed)?;
+            }
+            Compression::Lz4 => {
+                let decoder = lz4::Decoder::new(data)?;
+                decoder.take(take).read_to_end(&mut decompressed)?;
+            }
+        };
+
+        if decompressed.len() > limit {
+            return Err(CodecError::DecompressedTooLarge { max: limit });
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Compression counters
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompressionStats {
+    pub frames_compressed_tx: u64,
+    pub bytes_saved_tx: u64,
+    pub frames_compressed_rx: u64,
+    pub bytes_saved_rx: u64,
+}
+
+impl CompressionStats {
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_saved_tx + self.bytes_saved_rx
+    }
+}
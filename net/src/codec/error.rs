@@ -2,27 +2,109 @@ use bincode::ErrorKind;
 use std::{convert, error, fmt, io};
 
 #[derive(Debug, Clone)]
-pub struct CodecError {
-    message: String,
+pub enum CodecError {
+    /// Frame length exceeds the configured maximum
+    FrameTooLarge { size: usize, max: usize },
+    /// Frame does not start with the protocol magic bytes
+    InvalidMagic([u8; 2]),
+    /// Frame uses an unknown wire format version
+    UnsupportedVersion(u8),
+    /// Frame sets flags this implementation does not understand
+    UnsupportedFlags(u8),
+    /// Payload does not match the frame checksum
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// Compressed frame received before an algorithm was negotiated
+    CompressionNotNegotiated,
+    /// Payload expands past the decompression limit
+    DecompressedTooLarge { max: usize },
+    /// Handshake, encryption or decryption failure
+    Encryption(String),
+    /// Frame is not authenticated with the network pre-shared key
+    Unauthenticated,
+    Other(String),
 }
 
 impl CodecError {
     pub fn new(message: &str) -> Self {
-        CodecError {
-            message: String::from(message.clone()),
+        CodecError::Other(String::from(message))
+    }
+
+    /// Whether the error leaves a byte stream unusable, i.e. out of sync
+    /// with frame boundaries or with the session cipher
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            CodecError::FrameTooLarge { .. }
+            | CodecError::InvalidMagic(_)
+            | CodecError::UnsupportedVersion(_)
+            | CodecError::UnsupportedFlags(_)
+            | CodecError::Encryption(_)
+            | CodecError::Unauthenticated => true,
+            _ => false,
         }
     }
 }
 
 impl fmt::Display for CodecError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "message codec error: {}", &self.message[..])
+        match self {
+            CodecError::FrameTooLarge { size, max } => write!(
+                f,
+                "message codec error: frame too large ({} > {} bytes)",
+                size, max
+            ),
+            CodecError::InvalidMagic(magic) => write!(
+                f,
+                "message codec error: invalid magic bytes {:02x}{:02x}",
+                magic[0], magic[1]
+            ),
+            CodecError::UnsupportedVersion(version) => write!(
+                f,
+                "message codec error: unsupported wire version {}",
+                version
+            ),
+            CodecError::UnsupportedFlags(flags) => write!(
+                f,
+                "message codec error: unsupported frame flags {:08b}",
+                flags
+            ),
+            CodecError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "message codec error: checksum mismatch ({:08x} != {:08x})",
+                actual, expected
+            ),
+            CodecError::CompressionNotNegotiated => {
+                write!(f, "message codec error: compression not negotiated")
+            }
+            CodecError::DecompressedTooLarge { max } => write!(
+                f,
+                "message codec error: decompressed payload exceeds {} bytes",
+                max
+            ),
+            CodecError::Encryption(message) => {
+                write!(f, "message codec error: encryption: {}", &message[..])
+            }
+            CodecError::Unauthenticated => {
+                write!(f, "message codec error: frame not authenticated with the network key")
+            }
+            CodecError::Other(message) => write!(f, "message codec error: {}", &message[..]),
+        }
     }
 }
 
 impl error::Error for CodecError {
     fn description(&self) -> &str {
-        &self.message[..]
+        match self {
+            CodecError::FrameTooLarge { .. } => "frame too large",
+            CodecError::InvalidMagic(_) => "invalid magic bytes",
+            CodecError::UnsupportedVersion(_) => "unsupported wire version",
+            CodecError::UnsupportedFlags(_) => "unsupported frame flags",
+            CodecError::ChecksumMismatch { .. } => "checksum mismatch",
+            CodecError::CompressionNotNegotiated => "compression not negotiated",
+            CodecError::DecompressedTooLarge { .. } => "decompressed payload too large",
+            CodecError::Encryption(message) => &message[..],
+            CodecError::Unauthenticated => "frame not authenticated",
+            CodecError::Other(message) => &message[..],
+        }
     }
 
     fn cause(&self) -> Option<&error::Error> {
@@ -0,0 +1,212 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use crc::{crc32, Hasher32};
+
+use codec::error::CodecError;
+
+/// Frame magic bytes ("GM")
+pub const MAGIC: [u8; 2] = [0x47, 0x4D];
+/// Current wire format version
+pub const VERSION: u8 = 1;
+/// Version reported for headerless (length-prefixed only) frames
+pub const LEGACY_VERSION: u8 = 0;
+
+/// Header is followed by a CRC32 (IEEE) of the payload
+pub const FLAG_CHECKSUM: u8 = 0b0000_0001;
+/// Payload is compressed with the algorithm negotiated for the session
+pub const FLAG_COMPRESSED: u8 = 0b0000_0010;
+/// Payload is a big-endian `u16` protocol id followed by the raw
+/// encapsulated message, bypassing the serializer
+pub const FLAG_RAW: u8 = 0b0000_0100;
+/// Payload is encrypted with the session cipher
+pub const FLAG_ENCRYPTED: u8 = 0b0000_1000;
+/// Payload is followed by an HMAC-SHA256 tag keyed by the network
+/// pre-shared key
+pub const FLAG_AUTHENTICATED: u8 = 0b0001_0000;
+/// Raw payload carries a message signature between the protocol id and
+/// the message
+pub const FLAG_SIGNED: u8 = 0b0010_0000;
+/// Payload is prefixed with a datagram sequence number and timestamp
+pub const FLAG_SEQUENCED: u8 = 0b0100_0000;
+/// Flags understood by this implementation
+const FLAGS_SUPPORTED: u8 = FLAG_CHECKSUM
+    | FLAG_COMPRESSED
+    | FLAG_RAW
+    | FLAG_ENCRYPTED
+    | FLAG_AUTHENTICATED
+    | FLAG_SIGNED
+    | FLAG_SEQUENCED;
+
+/// magic (2) + version (1) + flags (1) + length (4)
+const HEADER_SZ: usize = 8;
+const CHECKSUM_SZ: usize = 4;
+const LEGACY_HEADER_SZ: usize = 4;
+/// sequence number (8) + timestamp (8)
+pub const SEQUENCE_SZ: usize = 16;
+
+/// Anti-replay sequence of a datagram
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sequence {
+    /// Per-peer, strictly increasing number
+    pub number: u64,
+    /// Send time, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl Sequence {
+    pub fn encode(&self) -> [u8; SEQUENCE_SZ] {
+        let mut bytes = [0u8; SEQUENCE_SZ];
+        BigEndian::write_u64(&mut bytes[..8], self.number);
+        BigEndian::write_u64(&mut bytes[8..], self.timestamp);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Self {
+        Sequence {
+            number: BigEndian::read_u64(&bytes[..8]),
+            timestamp: BigEndian::read_u64(&bytes[8..SEQUENCE_SZ]),
+        }
+    }
+}
+
+/// Wire frame header
+///
+/// Legacy frames carry a big-endian `u32` length only. Their first two bytes
+/// cannot match `MAGIC` unless the announced length exceeds 1.1 GiB.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub length: usize,
+    pub checksum: Option<u32>,
+}
+
+impl FrameHeader {
+    /// Creates a header for a payload written as consecutive parts
+    pub fn new(flags: u8, payload: &[&[u8]]) -> Self {
+        let checksum = match flags & FLAG_CHECKSUM {
+            0 => None,
+            _ => Some(checksum(payload)),
+        };
+
+        FrameHeader {
+            version: VERSION,
+            flags,
+            length: payload.iter().map(|p| p.len()).sum(),
+            checksum,
+        }
+    }
+
+    pub fn legacy(length: usize) -> Self {
+        FrameHeader {
+            version: LEGACY_VERSION,
+            flags: 0,
+            length,
+            checksum: None,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+
+    /// Encoded header size, in bytes
+    pub fn size(&self) -> usize {
+        if self.is_legacy() {
+            return LEGACY_HEADER_SZ;
+        }
+        match self.checksum {
+            Some(_) => HEADER_SZ + CHECKSUM_SZ,
+            None => HEADER_SZ,
+        }
+    }
+
+    pub fn write(&self, bytes: &mut BytesMut) {
+        bytes.reserve(self.size());
+
+        if !self.is_legacy() {
+            bytes.put_slice(&MAGIC);
+            bytes.put_u8(self.version);
+            bytes.put_u8(self.flags);
+        }
+
+        bytes.put_u32_be(self.length as u32);
+        if let Some(checksum) = self.checksum {
+            bytes.put_u32_be(checksum);
+        }
+    }
+
+    /// Reads a header from the beginning of a buffer, without consuming it.
+    /// Returns `None` if more bytes are needed.
+    pub fn read(bytes: &[u8], accept_legacy: bool) -> Result<Option<Self>, CodecError> {
+        if bytes.len() < MAGIC.len() {
+            return Ok(None);
+        }
+
+        if &bytes[..MAGIC.len()] != &MAGIC[..] {
+            if !accept_legacy {
+                return Err(CodecError::InvalidMagic([bytes[0], bytes[1]]));
+            }
+            if bytes.len() < LEGACY_HEADER_SZ {
+                return Ok(None);
+            }
+
+            let length = BigEndian::read_u32(bytes) as usize;
+            return Ok(Some(FrameHeader::legacy(length)));
+        }
+
+        if bytes.len() < HEADER_SZ {
+            return Ok(None);
+        }
+
+        let version = bytes[2];
+        if version != VERSION {
+            return Err(CodecError::UnsupportedVersion(version));
+        }
+
+        let flags = bytes[3];
+        if flags & !FLAGS_SUPPORTED != 0 {
+            return Err(CodecError::UnsupportedFlags(flags));
+        }
+
+        let length = BigEndian::read_u32(&bytes[4..]) as usize;
+        let checksum = match flags & FLAG_CHECKSUM {
+            0 => None,
+            _ => {
+                if bytes.len() < HEADER_SZ + CHECKSUM_SZ {
+                    return Ok(None);
+                }
+                Some(BigEndian::read_u32(&bytes[HEADER_SZ..]))
+            }
+        };
+
+        Ok(Some(FrameHeader {
+            version,
+            flags,
+            length,
+            checksum,
+        }))
+    }
+
+    /// Verifies the payload against the header checksum, if present
+    pub fn verify(&self, payload: &[u8]) -> Result<(), CodecError> {
+        match self.checksum {
+            Some(expected) => {
+                let actual = checksum(&[payload]);
+                if actual != expected {
+                    return Err(CodecError::ChecksumMismatch { expected, actual });
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn checksum(payload: &[&[u8]]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    for part in payload {
+        digest.write(part);
+    }
+    digest.sum32()
+}
@@ -1,11 +1,235 @@
+use std::net::SocketAddr;
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+
+use codec::compression::Compression;
+use codec::error::CodecError;
+use identity::{PeerId, PUBLIC_KEY_SZ, SIGNATURE_SZ};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Encapsulated {
     pub protocol_id: u16,
-    pub message: Vec<u8>,
+    pub message: Bytes,
+}
+
+/// Originator signature of an `Encapsulated` message, verifiable by every
+/// node the message is forwarded through
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Signature {
+    pub signer: PeerId,
+    /// Ed25519 public key `signer` derives from
+    pub public_key: Bytes,
+    /// Signature of the protocol id, timestamp and payload
+    pub signature: Bytes,
+    /// Signing time, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl Signature {
+    /// Size of an encoded signature: signer, public key, signature and
+    /// big-endian timestamp
+    pub const ENCODED_SZ: usize = 32 + PUBLIC_KEY_SZ + SIGNATURE_SZ + 8;
+
+    pub fn encode(&self) -> Result<Vec<u8>, CodecError> {
+        if self.public_key.len() != PUBLIC_KEY_SZ || self.signature.len() != SIGNATURE_SZ {
+            return Err(CodecError::new("malformed message signature"));
+        }
+
+        let mut timestamp = [0u8; 8];
+        BigEndian::write_u64(&mut timestamp, self.timestamp);
+
+        let parts: [&[u8]; 4] = [&self.signer.0, &self.public_key, &self.signature, &timestamp];
+        Ok(parts.concat())
+    }
+
+    pub fn decode(bytes: &Bytes) -> Result<Self, CodecError> {
+        if bytes.len() != Signature::ENCODED_SZ {
+            return Err(CodecError::new("truncated message signature"));
+        }
+
+        let mut signer = [0u8; 32];
+        signer.copy_from_slice(&bytes[..32]);
+        let signature_at = 32 + PUBLIC_KEY_SZ;
+        let timestamp_at = signature_at + SIGNATURE_SZ;
+
+        Ok(Signature {
+            signer: PeerId(signer),
+            public_key: bytes.slice(32, signature_at),
+            signature: bytes.slice(signature_at, timestamp_at),
+            timestamp: BigEndian::read_u64(&bytes[timestamp_at..]),
+        })
+    }
+}
+
+/// Signed `Encapsulated` message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Signed {
+    pub encapsulated: Encapsulated,
+    pub signature: Signature,
+}
+
+/// Session features supported by the sender
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Capabilities {
+    pub compression: Vec<Compression>,
+}
+
+/// Ports a node accepts connections on
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ListenPorts {
+    pub tcp: Option<u16>,
+    pub udp: Option<u16>,
+}
+
+/// Node introduction, sent once the session is established
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub version: String,
+    /// Ed25519 public key the sender's `PeerId` derives from
+    pub public_key: Bytes,
+    /// Challenge the remote peer signs in its `HelloAck`
+    pub nonce: Bytes,
+    pub listen_ports: ListenPorts,
+    /// Address validation cookie of the remote node, echoed back over UDP;
+    /// empty if none
+    #[serde(default)]
+    pub cookie: Bytes,
+}
+
+/// Answer to a `Hello`, proving ownership of the announced key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HelloAck {
+    /// Signature of the session's `HelloTranscript`
+    pub signature: Bytes,
+}
+
+/// Proof-of-work challenge sent to connecting peers under load
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Puzzle {
+    pub challenge: Bytes,
+    /// Required number of leading zero bits
+    pub difficulty: u8,
+}
+
+/// Answer to a `Puzzle`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Solution {
+    pub nonce: u64,
+}
+
+/// Message retransmitted over UDP until acknowledged
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reliable {
+    /// Per-session sequence number, starting at 0
+    pub sequence: u64,
+    /// Deliver only after every message with a lower sequence number
+    pub ordered: bool,
+    pub message: Payload,
+}
+
+/// Application message carried by a `Reliable` one. Not a `Message`, so
+/// that reliable messages cannot nest.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Payload {
+    Encapsulated(Encapsulated),
+    Signed(Signed),
+}
+
+impl Payload {
+    /// Payload of an application message; `None` for other messages
+    pub fn from_message(message: Message) -> Option<Self> {
+        match message {
+            Message::Encapsulated(e) => Some(Payload::Encapsulated(e)),
+            Message::Signed(s) => Some(Payload::Signed(s)),
+            _ => None,
+        }
+    }
+
+    pub fn into_message(self) -> Message {
+        match self {
+            Payload::Encapsulated(e) => Message::Encapsulated(e),
+            Payload::Signed(s) => Message::Signed(s),
+        }
+    }
+}
+
+/// Selective acknowledgement of `Reliable` messages
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Ack {
+    /// Every sequence number below this one was received
+    pub cumulative: u64,
+    /// Bit `i` is set if `cumulative + 1 + i` was received
+    pub selective: u64,
+}
+
+/// Padded datagram probing the path MTU; never fragmented
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Probe {
+    pub id: u32,
+    /// Random, so that compression cannot shrink the datagram
+    pub padding: Bytes,
+}
+
+/// Acknowledgement of a `Probe`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ProbeAck {
+    pub id: u32,
+    /// Size of the probe datagram as received
+    pub size: u32,
+}
+
+/// Challenge sent to a new address of a UDP session's remote node
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PathChallenge {
+    /// Connection ID of the challenged node's end of the session
+    pub connection_id: u64,
+    pub nonce: Bytes,
+    /// Address the challenge is sent to
+    pub address: SocketAddr,
+}
+
+/// Answer to a `PathChallenge`, sent from the challenged address
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PathResponse {
+    /// Signature of the session's `PathTranscript`
+    pub signature: Bytes,
+}
+
+/// Answer to a UDP `Hello` from an address without a session; the Hello
+/// is accepted once repeated with the cookie
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Cookie {
+    pub cookie: Bytes,
+}
+
+/// Teardown of a UDP session, signed by the node closing it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Goodbye {
+    pub signature: Bytes,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Message)]
 pub enum Message {
     Encapsulated(Encapsulated),
     Disconnect,
+    Capabilities(Capabilities),
+    /// Noise handshake message
+    Noise(Bytes),
+    Hello(Hello),
+    HelloAck(HelloAck),
+    Signed(Signed),
+    Puzzle(Puzzle),
+    Solution(Solution),
+    Reliable(Reliable),
+    Ack(Ack),
+    Probe(Probe),
+    ProbeAck(ProbeAck),
+    PathChallenge(PathChallenge),
+    PathResponse(PathResponse),
+    Cookie(Cookie),
+    Goodbye(Goodbye),
+    /// Private network session nonce, the first frame sent each way over
+    /// a stream session
+    SessionNonce(Bytes),
 }
@@ -1,67 +1,1022 @@
-use std::mem;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
 
-use bincode::internal::{deserialize, serialize};
-use bincode::Bounded;
-use byteorder::{BigEndian as Order, ByteOrder};
-use bytes::{BigEndian, BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
 use tokio_codec::{Decoder, Encoder};
 
+pub mod compression;
 pub mod error;
+pub mod frame;
 pub mod message;
+pub mod noise;
+pub mod psk;
+pub mod serializer;
 
+use self::compression::{Compression, CompressionStats};
 use self::error::CodecError;
-use self::message::Message;
+pub use self::frame::Sequence;
+use self::frame::{
+    FrameHeader, FLAG_AUTHENTICATED, FLAG_CHECKSUM, FLAG_COMPRESSED, FLAG_ENCRYPTED, FLAG_RAW,
+    FLAG_SEQUENCED, FLAG_SIGNED, SEQUENCE_SZ, VERSION,
+};
+use self::message::{Encapsulated, Message, Signature, Signed};
+use self::noise::{encrypted_len, SessionCipher};
+use self::psk::{PreSharedKey, PskSession, TAG_SZ};
+use self::serializer::{Bincode, Serializer};
+use identity::NONCE_SZ;
 
 type LenType = u32;
-const LEN_SZ: usize = mem::size_of::<LenType>();
 const LEN_MAX: LenType = LenType::max_value();
+const PROTOCOL_ID_SZ: usize = 2;
+/// Size of the frame header fields authenticated by the session cipher
+const HEADER_AD_SZ: usize = 2;
 
+/// Default maximum frame size (16 MiB)
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// Read buffer space reserved ahead of a partially received frame; larger
+/// frames grow the buffer as their payload arrives, so that a header alone
+/// cannot make the peer allocate the whole frame
+const MAX_RESERVE: usize = 64 * 1024;
 
-pub struct MessageCodec;
+/// Message codec configuration
+#[derive(Clone, Debug)]
+pub struct CodecConfig {
+    /// Maximum size of a frame payload, in bytes
+    pub max_frame_size: usize,
+    /// Append a CRC32 of the payload to outgoing frame headers
+    pub checksum: bool,
+    /// Accept length-prefixed frames without a header (migration window)
+    pub accept_legacy: bool,
+    /// Compression algorithms offered during the session handshake
+    pub compression: Vec<Compression>,
+    /// Minimum payload size eligible for compression, in bytes
+    pub compression_threshold: usize,
+    /// Maximum size of a decompressed payload, in bytes
+    pub max_decompressed_size: usize,
+    /// Private network key; frames without a valid tag are rejected
+    pub psk: Option<PreSharedKey>,
+}
 
-impl Encoder for MessageCodec {
-    type Item = Message;
-    type Error = CodecError;
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig {
+            max_frame_size: MAX_FRAME_SIZE,
+            checksum: false,
+            accept_legacy: true,
+            compression: Compression::supported(),
+            compression_threshold: 1024,
+            max_decompressed_size: MAX_FRAME_SIZE,
+            psk: None,
+        }
+    }
+}
 
-    fn encode(&mut self, value: Self::Item, bytes: &mut BytesMut) -> Result<(), Self::Error> {
-        let size_limit = Bounded(LEN_MAX as u64);
-        let result = serialize::<Message, Bounded, Order>(&value, size_limit);
-        let ser = match result {
-            Ok(ser) => ser,
-            Err(e) => {
-                return Err(CodecError::from(e));
+/// Codec state shared by the read and write halves of a session
+#[derive(Debug, Default)]
+pub struct CodecState {
+    /// Compression algorithm agreed on with the remote peer
+    pub compression: Option<Compression>,
+    pub compression_stats: CompressionStats,
+    /// Frame cipher, installed once the Noise handshake completes
+    pub cipher: Option<SessionCipher>,
+    /// Binding of private network tags to a stream session; datagrams are
+    /// tagged without one
+    pub psk_session: Option<PskSession>,
+    /// Since when a partially received frame has been buffered
+    pub partial_since: Option<Instant>,
+}
+
+pub type SharedCodecState = Rc<RefCell<CodecState>>;
+
+pub struct MessageCodec<S = Bincode>
+where
+    S: Serializer,
+{
+    config: CodecConfig,
+    serializer: S,
+    state: SharedCodecState,
+}
+
+impl<S> MessageCodec<S>
+where
+    S: Serializer,
+{
+    pub fn new(config: CodecConfig, serializer: S) -> Self {
+        let state = Rc::new(RefCell::new(CodecState::default()));
+        MessageCodec::with_state(config, serializer, state)
+    }
+
+    pub fn with_state(config: CodecConfig, serializer: S, state: SharedCodecState) -> Self {
+        MessageCodec {
+            config,
+            serializer,
+            state,
+        }
+    }
+
+    pub fn state(&self) -> SharedCodecState {
+        self.state.clone()
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), CodecError> {
+        let max = self.config.max_frame_size;
+        if size > max || size > LEN_MAX as usize {
+            return Err(CodecError::FrameTooLarge { size, max });
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> u8 {
+        match self.config.checksum {
+            true => FLAG_CHECKSUM,
+            false => 0,
+        }
+    }
+
+    /// Compresses the payload with the negotiated algorithm. Returns `None`
+    /// when compression is disabled, not worth it or not yet negotiated.
+    fn compress(&self, payload: &[u8]) -> Result<Option<Vec<u8>>, CodecError> {
+        let mut state = self.state.borrow_mut();
+        let compression = match state.compression {
+            Some(compression) => compression,
+            None => return Ok(None),
+        };
+
+        if payload.len() < self.config.compression_threshold {
+            return Ok(None);
+        }
+
+        let compressed = compression.compress(payload)?;
+        // incompressible payloads are sent as-is
+        if compressed.len() >= payload.len() {
+            return Ok(None);
+        }
+
+        let stats = &mut state.compression_stats;
+        stats.frames_compressed_tx += 1;
+        stats.bytes_saved_tx += (payload.len() - compressed.len()) as u64;
+        Ok(Some(compressed))
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Bytes, CodecError> {
+        let mut state = self.state.borrow_mut();
+        let compression = match state.compression {
+            Some(compression) => compression,
+            None => return Err(CodecError::CompressionNotNegotiated),
+        };
+
+        let limit = self.config.max_decompressed_size;
+        let decompressed = compression.decompress(payload, limit)?;
+
+        let stats = &mut state.compression_stats;
+        stats.frames_compressed_rx += 1;
+        stats.bytes_saved_rx += decompressed.len().saturating_sub(payload.len()) as u64;
+        Ok(Bytes::from(decompressed))
+    }
+
+    /// Size of the authentication tag appended to outgoing frames
+    fn tag_size(&self) -> usize {
+        match self.config.psk {
+            Some(_) => TAG_SZ,
+            None => 0,
+        }
+    }
+
+    /// Writes a frame, appending an authentication tag in private
+    /// network mode
+    fn put_frame(
+        &self,
+        flags: u8,
+        payload: &[&[u8]],
+        bytes: &mut BytesMut,
+    ) -> Result<(), CodecError> {
+        let length: usize = payload.iter().map(|p| p.len()).sum();
+        self.check_size(length + self.tag_size())?;
+
+        let psk = match self.config.psk {
+            Some(ref psk) => psk,
+            None => return Ok(put_frame_parts(flags, payload, bytes)),
+        };
+
+        let flags = flags | FLAG_AUTHENTICATED;
+        let binding = match self.state.borrow_mut().psk_session {
+            Some(ref mut session) => session.next_sent(),
+            None => Vec::new(),
+        };
+        let tag = psk.tag(&binding, flags, payload);
+        let mut parts = payload.to_vec();
+        parts.push(&tag[..]);
+
+        Ok(put_frame_parts(flags, &parts, bytes))
+    }
+
+    /// Writes a frame, encrypting the payload once a cipher is installed.
+    /// The sequence, if any, is part of the encrypted payload.
+    fn write_frame(
+        &self,
+        mut flags: u8,
+        sequence: Option<Sequence>,
+        payload: &[&[u8]],
+        bytes: &mut BytesMut,
+    ) -> Result<(), CodecError> {
+        let prefix = sequence.map(|s| s.encode());
+        let mut parts = Vec::with_capacity(payload.len() + 1);
+        if let Some(ref prefix) = prefix {
+            flags |= FLAG_SEQUENCED;
+            parts.push(&prefix[..]);
+        }
+        parts.extend_from_slice(payload);
+        let payload = &parts[..];
+
+        if self.state.borrow().cipher.is_none() {
+            return self.put_frame(flags, payload, bytes);
+        }
+
+        // checked up front; a frame dropped after encryption would leave
+        // the remote cipher out of step
+        let length: usize = payload.iter().map(|p| p.len()).sum();
+        self.check_size(encrypted_len(HEADER_AD_SZ + length) + self.tag_size())?;
+
+        // the header is sealed along with the payload, with the flags it is
+        // written with
+        let mut flags = flags | FLAG_ENCRYPTED;
+        if self.config.psk.is_some() {
+            flags |= FLAG_AUTHENTICATED;
+        }
+        let ciphertext = match self.state.borrow_mut().cipher {
+            Some(ref mut cipher) => cipher.encrypt(&header_ad(VERSION, flags), payload)?,
+            None => return Err(CodecError::Encryption("no session cipher".to_owned())),
+        };
+        self.put_frame(flags, &[&ciphertext], bytes)
+    }
+
+    /// Verifies and strips the authentication tag in private network mode
+    fn authenticate(&self, flags: u8, payload: Bytes) -> Result<Bytes, CodecError> {
+        let tagged = flags & FLAG_AUTHENTICATED != 0;
+        let psk = match self.config.psk {
+            Some(ref psk) => psk,
+            None if tagged => return Err(CodecError::Unauthenticated),
+            None => return Ok(payload),
+        };
+
+        if !tagged || payload.len() < TAG_SZ {
+            return Err(CodecError::Unauthenticated);
+        }
+
+        let mut state = self.state.borrow_mut();
+        let binding = match state.psk_session {
+            Some(ref session) => session.next_received(),
+            None => Vec::new(),
+        };
+
+        let length = payload.len() - TAG_SZ;
+        if !psk.verify(&binding, flags, &payload[..length], &payload[length..]) {
+            return Err(CodecError::Unauthenticated);
+        }
+        if let Some(ref mut session) = state.psk_session {
+            session.received();
+        }
+        Ok(payload.slice_to(length))
+    }
+
+    /// Sends nothing but the session nonce until the remote one arrives,
+    /// in private network mode
+    fn send_psk_session(&self, message: &Message) -> Result<(), CodecError> {
+        let mut state = self.state.borrow_mut();
+        let session = match (&self.config.psk, state.psk_session.as_mut()) {
+            (&Some(_), Some(session)) => session,
+            _ => return Ok(()),
+        };
+
+        match *message {
+            Message::SessionNonce(ref nonce) if !session.has_local_nonce() => {
+                session.set_local_nonce(nonce.clone());
+                Ok(())
             }
+            Message::SessionNonce(_) => Err(CodecError::new("session nonce already sent")),
+            _ if session.is_established() => Ok(()),
+            _ => Err(CodecError::new("message sent before the session nonces")),
+        }
+    }
+
+    /// Accepts nothing but the remote session nonce until it arrives, in
+    /// private network mode
+    fn receive_psk_session(&self, message: &Message) -> Result<(), CodecError> {
+        let mut state = self.state.borrow_mut();
+        let session = match (&self.config.psk, state.psk_session.as_mut()) {
+            (&Some(_), Some(session)) => session,
+            _ => return Ok(()),
         };
 
-        let ser_ref: &[u8] = ser.as_ref();
-        bytes.reserve(ser_ref.len() + LEN_SZ);
-        bytes.put_u32_be(ser_ref.len() as LenType);
-        bytes.put(ser_ref);
+        match *message {
+            Message::SessionNonce(ref nonce)
+                if !session.has_remote_nonce() && nonce.len() == NONCE_SZ =>
+            {
+                session.set_remote_nonce(nonce.clone());
+                Ok(())
+            }
+            Message::SessionNonce(_) => Err(CodecError::Unauthenticated),
+            _ if session.is_established() => Ok(()),
+            _ => Err(CodecError::Unauthenticated),
+        }
+    }
 
-        Ok(())
+    fn decrypt(&self, header: &FrameHeader, payload: Bytes) -> Result<Bytes, CodecError> {
+        let ad = header_ad(header.version, header.flags);
+        let mut state = self.state.borrow_mut();
+        match (header.flags & FLAG_ENCRYPTED != 0, state.cipher.as_mut()) {
+            (false, None) => Ok(payload),
+            (true, Some(cipher)) => Ok(Bytes::from(cipher.decrypt(&ad, &payload)?)),
+            (true, None) => Err(CodecError::Encryption(
+                "encrypted frame without a session cipher".to_owned(),
+            )),
+            (false, Some(_)) => Err(CodecError::Encryption(
+                "plaintext frame on an encrypted session".to_owned(),
+            )),
+        }
+    }
+
+    /// Writes the encapsulated payload and its optional signature directly
+    /// into the frame buffer, bypassing the serializer if it allows
+    fn encode_raw(
+        &self,
+        value: Encapsulated,
+        signature: Option<Signature>,
+        sequence: Option<Sequence>,
+        bytes: &mut BytesMut,
+    ) -> Result<(), CodecError> {
+        let mut flags = self.flags() | FLAG_RAW;
+        let envelope = match signature {
+            Some(ref signature) => {
+                flags |= FLAG_SIGNED;
+                signature.encode()?
+            }
+            None => Vec::new(),
+        };
+
+        self.check_size(PROTOCOL_ID_SZ + envelope.len() + value.message.len())?;
+
+        let compressed = self.compress(&value.message)?;
+        let body: &[u8] = match compressed {
+            Some(ref c) => {
+                flags |= FLAG_COMPRESSED;
+                c
+            }
+            None => &value.message[..],
+        };
+
+        let mut protocol_id = [0u8; PROTOCOL_ID_SZ];
+        BigEndian::write_u16(&mut protocol_id, value.protocol_id);
+
+        self.write_frame(flags, sequence, &[&protocol_id, &envelope, body], bytes)
+    }
+
+    fn decode_raw(&self, flags: u8, payload: Bytes) -> Result<Message, CodecError> {
+        if payload.len() < PROTOCOL_ID_SZ {
+            return Err(CodecError::new("truncated encapsulated frame"));
+        }
+
+        let protocol_id = BigEndian::read_u16(&payload);
+        let mut body = payload.slice_from(PROTOCOL_ID_SZ);
+
+        let signature = match flags & FLAG_SIGNED {
+            0 => None,
+            _ if body.len() < Signature::ENCODED_SZ => {
+                return Err(CodecError::new("truncated message signature"))
+            }
+            _ => {
+                let envelope = body.split_to(Signature::ENCODED_SZ);
+                Some(Signature::decode(&envelope)?)
+            }
+        };
+
+        let message = match flags & FLAG_COMPRESSED {
+            0 => body,
+            _ => self.decompress(&body)?,
+        };
+
+        let encapsulated = Encapsulated {
+            protocol_id,
+            message,
+        };
+
+        Ok(match signature {
+            Some(signature) => Message::Signed(Signed {
+                encapsulated,
+                signature,
+            }),
+            None => Message::Encapsulated(encapsulated),
+        })
+    }
+}
+
+/// Frame header fields authenticated by the session cipher. The length is
+/// implied by the ciphertext and the checksum does not need protecting.
+fn header_ad(version: u8, flags: u8) -> [u8; HEADER_AD_SZ] {
+    [version, flags]
+}
+
+fn put_frame_parts(flags: u8, payload: &[&[u8]], bytes: &mut BytesMut) {
+    let header = FrameHeader::new(flags, payload);
+    bytes.reserve(header.size() + header.length);
+    header.write(bytes);
+    for part in payload {
+        bytes.put_slice(part);
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        MessageCodec::new(CodecConfig::default(), Bincode)
     }
 }
 
-impl Decoder for MessageCodec {
+impl<S> Encoder for MessageCodec<S>
+where
+    S: Serializer,
+{
+    type Item = Message;
+    type Error = CodecError;
+
+    fn encode(&mut self, value: Self::Item, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_message(value, None, bytes)
+    }
+}
+
+impl<S> Decoder for MessageCodec<S>
+where
+    S: Serializer,
+{
     type Item = Message;
     type Error = CodecError;
 
     fn decode(&mut self, bytes: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if bytes.len() < LEN_SZ {
+        let decoded = self.decode_sequenced(bytes)?;
+
+        // time incomplete frames, so that stalled streams can be closed
+        let mut state = self.state.borrow_mut();
+        let since = state.partial_since;
+        state.partial_since = match (&decoded, bytes.is_empty()) {
+            (&None, false) => Some(since.unwrap_or_else(Instant::now)),
+            _ => None,
+        };
+
+        Ok(decoded.map(|(message, _)| message))
+    }
+}
+
+impl<S> MessageCodec<S>
+where
+    S: Serializer,
+{
+    /// Encodes a message, optionally tagged with a datagram sequence
+    pub fn encode_message(
+        &mut self,
+        value: Message,
+        sequence: Option<Sequence>,
+        bytes: &mut BytesMut,
+    ) -> Result<(), CodecError> {
+        self.send_psk_session(&value)?;
+
+        let value = match (value, self.serializer.raw_payloads()) {
+            (Message::Encapsulated(e), true) => return self.encode_raw(e, None, sequence, bytes),
+            (Message::Signed(s), true) => {
+                return self.encode_raw(s.encapsulated, Some(s.signature), sequence, bytes)
+            }
+            (value, _) => value,
+        };
+
+        let ser = self.serializer.serialize(&value)?;
+        self.check_size(ser.len())?;
+
+        let mut flags = self.flags();
+        let compressed = self.compress(&ser)?;
+        let payload: &[u8] = match compressed {
+            Some(ref c) => {
+                flags |= FLAG_COMPRESSED;
+                c
+            }
+            None => &ser,
+        };
+
+        self.write_frame(flags, sequence, &[payload], bytes)
+    }
+
+    /// Decodes a message along with its datagram sequence, if tagged
+    pub fn decode_sequenced(
+        &mut self,
+        bytes: &mut BytesMut,
+    ) -> Result<Option<(Message, Option<Sequence>)>, CodecError> {
+        let header = match FrameHeader::read(bytes.as_ref(), self.config.accept_legacy)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        // reject the frame before buffering its payload
+        let size = header.length;
+        self.check_size(size)?;
+
+        let header_size = header.size();
+        if bytes.len() < size + header_size {
+            let missing = size + header_size - bytes.len();
+            bytes.reserve(missing.min(MAX_RESERVE));
             return Ok(None);
         }
 
-        let size = BigEndian::read_u32(bytes.as_ref()) as usize;
-        if bytes.len() < size + LEN_SZ {
-            return Ok(None);
+        bytes.split_to(header_size);
+
+        // shares the read buffer instead of copying the payload out
+        let msg = bytes.split_to(size).freeze();
+        header.verify(&msg)?;
+        // reject frames from outside the private network before anything
+        // else looks at the payload
+        let msg = self.authenticate(header.flags, msg)?;
+        let mut msg = self.decrypt(&header, msg)?;
+
+        let sequence = match header.flags & FLAG_SEQUENCED {
+            0 => None,
+            _ if msg.len() < SEQUENCE_SZ => return Err(CodecError::new("truncated sequence")),
+            _ => Some(Sequence::decode(&msg.split_to(SEQUENCE_SZ))),
+        };
+
+        if header.flags & FLAG_RAW != 0 {
+            let message = self.decode_raw(header.flags, msg)?;
+            self.receive_psk_session(&message)?;
+            return Ok(Some((message, sequence)));
+        }
+
+        let msg = match header.flags & FLAG_COMPRESSED {
+            0 => msg,
+            _ => self.decompress(&msg)?,
+        };
+
+        let message = self.serializer.deserialize(&msg)?;
+        self.receive_psk_session(&message)?;
+        Ok(Some((message, sequence)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+    use tokio_codec::{Decoder, Encoder};
+
+    use codec::compression::Compression;
+    use codec::error::CodecError;
+    use codec::frame::{FrameHeader, FLAG_COMPRESSED, FLAG_RAW, MAGIC};
+    use codec::message::{Capabilities, Encapsulated, Message};
+    use codec::noise::{NoiseConfig, NoiseHandshake};
+    use codec::psk::{PreSharedKey, PskSession};
+    use identity::{verify_message, Identity, SignatureStatus, NONCE_SZ};
+    use codec::serializer::{Bincode, Serialization, Serializer};
+    use codec::{CodecConfig, MessageCodec, Sequence, MAX_FRAME_SIZE, MAX_RESERVE};
+
+    fn codec(max_frame_size: usize) -> MessageCodec {
+        let config = CodecConfig {
+            max_frame_size,
+            ..CodecConfig::default()
+        };
+        MessageCodec::new(config, Bincode)
+    }
+
+    fn encapsulated(size: usize) -> Message {
+        Message::Encapsulated(Encapsulated {
+            protocol_id: 1,
+            message: Bytes::from(vec![0xAB; size]),
+        })
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut codec = codec(1024);
+        let mut bytes = BytesMut::new();
+
+        codec.encode(encapsulated(128), &mut bytes).unwrap();
+        match codec.decode(&mut bytes).unwrap() {
+            Some(Message::Encapsulated(e)) => {
+                assert_eq!(1, e.protocol_id);
+                assert_eq!(&vec![0xAB; 128][..], &e.message[..]);
+            }
+            _ => panic!("invalid message"),
+        }
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_decode_too_large() {
+        let mut codec = codec(1024);
+        let mut bytes = BytesMut::with_capacity(64);
+        bytes.put_slice(&MAGIC);
+        bytes.put_u8(1);
+        bytes.put_u8(0);
+        bytes.put_u32_be(1025);
+
+        match codec.decode(&mut bytes) {
+            Err(CodecError::FrameTooLarge { size, max }) => {
+                assert_eq!(1025, size);
+                assert_eq!(1024, max);
+            }
+            _ => panic!("frame accepted"),
+        }
+    }
+
+    #[test]
+    fn test_partial_frame() {
+        let mut codec = codec(MAX_FRAME_SIZE);
+        let mut frame = BytesMut::new();
+        codec.encode(encapsulated(MAX_RESERVE * 4), &mut frame).unwrap();
+
+        // the header of a large frame reserves a bounded amount of memory
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&frame[..64]);
+        assert!(codec.decode(&mut bytes).unwrap().is_none());
+        assert!(bytes.capacity() <= 64 + MAX_RESERVE * 2);
+
+        let mut offset = 64;
+        while offset < frame.len() {
+            let end = (offset + MAX_RESERVE).min(frame.len());
+            bytes.extend_from_slice(&frame[offset..end]);
+            offset = end;
+            if offset < frame.len() {
+                assert!(codec.decode(&mut bytes).unwrap().is_none());
+            }
+        }
+        match codec.decode(&mut bytes).unwrap() {
+            Some(Message::Encapsulated(e)) => assert_eq!(MAX_RESERVE * 4, e.message.len()),
+            _ => panic!("invalid message"),
         }
+    }
+
+    #[test]
+    fn test_encode_too_large() {
+        let mut codec = codec(1024);
+        let mut bytes = BytesMut::new();
+
+        match codec.encode(encapsulated(1024), &mut bytes) {
+            Err(CodecError::FrameTooLarge { .. }) => assert!(bytes.is_empty()),
+            _ => panic!("frame accepted"),
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        let config = CodecConfig {
+            checksum: true,
+            ..CodecConfig::default()
+        };
+        let mut codec = MessageCodec::new(config, Bincode);
+        let mut bytes = BytesMut::new();
+
+        codec.encode(encapsulated(16), &mut bytes).unwrap();
+        let mut corrupted = bytes.clone();
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        match codec.decode(&mut corrupted) {
+            Err(CodecError::ChecksumMismatch { .. }) => {}
+            _ => panic!("corrupted frame accepted"),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut codec = MessageCodec::default();
+        let mut bytes = BytesMut::with_capacity(64);
+        bytes.put_slice(&MAGIC);
+        bytes.put_u8(2);
+        bytes.put_u8(0);
+        bytes.put_u32_be(0);
+
+        match codec.decode(&mut bytes) {
+            Err(CodecError::UnsupportedVersion(2)) => {}
+            _ => panic!("frame accepted"),
+        }
+    }
+
+    #[test]
+    fn test_legacy() {
+        let mut codec = MessageCodec::default();
+        let payload = Bincode.serialize(&encapsulated(16)).unwrap();
+
+        let mut bytes = BytesMut::with_capacity(payload.len() + 4);
+        bytes.put_u32_be(payload.len() as u32);
+        bytes.put_slice(&payload);
+        let mut rejected = bytes.clone();
+
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+
+        let config = CodecConfig {
+            accept_legacy: false,
+            ..CodecConfig::default()
+        };
+        let mut codec = MessageCodec::new(config, Bincode);
+        match codec.decode(&mut rejected) {
+            Err(CodecError::InvalidMagic(_)) => {}
+            _ => panic!("legacy frame accepted"),
+        }
+    }
+
+    #[test]
+    fn test_serializers() {
+        let serializations = vec![
+            Serialization::Bincode,
+            Serialization::Cbor,
+            Serialization::MessagePack,
+        ];
+
+        for serialization in serializations {
+            let serializer = serialization.serializer();
+            let mut codec = MessageCodec::new(CodecConfig::default(), serializer);
+            let mut bytes = BytesMut::new();
+
+            let capabilities = Capabilities {
+                compression: vec![Compression::Lz4],
+            };
+
+            codec.encode(Message::Capabilities(capabilities), &mut bytes).unwrap();
+            match codec.decode(&mut bytes).unwrap() {
+                Some(Message::Capabilities(c)) => assert_eq!(vec![Compression::Lz4], c.compression),
+                _ => panic!("invalid message ({:?})", serialization),
+            }
+
+            // application payloads stay in the selected format, except
+            // for bincode
+            codec.encode(encapsulated(16), &mut bytes).unwrap();
+            let header = FrameHeader::read(&bytes, false).unwrap().unwrap();
+            let raw = header.flags & FLAG_RAW != 0;
+            assert_eq!(serialization == Serialization::Bincode, raw);
+
+            if !raw {
+                let payload = &bytes[header.size()..header.size() + header.length];
+                match serialization.serializer().deserialize(payload).unwrap() {
+                    Message::Encapsulated(e) => assert_eq!(&[0xAB; 16][..], &e.message[..]),
+                    _ => panic!("invalid message ({:?})", serialization),
+                }
+            }
+
+            match codec.decode(&mut bytes).unwrap() {
+                Some(Message::Encapsulated(e)) => assert_eq!(&[0xAB; 16][..], &e.message[..]),
+                _ => panic!("invalid message ({:?})", serialization),
+            }
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        for compression in Compression::supported() {
+            let mut codec = MessageCodec::default();
+            codec.state().borrow_mut().compression = Some(compression);
+
+            let mut bytes = BytesMut::new();
+            codec.encode(encapsulated(64 * 1024), &mut bytes).unwrap();
+            assert!(bytes.len() < 64 * 1024);
+
+            match codec.decode(&mut bytes).unwrap() {
+                Some(Message::Encapsulated(e)) => {
+                    assert_eq!(&vec![0xAB; 64 * 1024][..], &e.message[..])
+                }
+                _ => panic!("invalid message ({:?})", compression),
+            }
+
+            let state = codec.state();
+            let stats = state.borrow().compression_stats;
+            assert_eq!(1, stats.frames_compressed_tx);
+            assert_eq!(1, stats.frames_compressed_rx);
+            assert_eq!(stats.bytes_saved_tx, stats.bytes_saved_rx);
+        }
+    }
+
+    #[test]
+    fn test_decompression_limit() {
+        let mut encoder = MessageCodec::default();
+        encoder.state().borrow_mut().compression = Some(Compression::Zstd);
+
+        let mut bytes = BytesMut::new();
+        encoder.encode(encapsulated(64 * 1024), &mut bytes).unwrap();
+        let mut unnegotiated = bytes.clone();
+
+        let config = CodecConfig {
+            max_decompressed_size: 1024,
+            ..CodecConfig::default()
+        };
+        let mut decoder = MessageCodec::new(config, Bincode);
+        decoder.state().borrow_mut().compression = Some(Compression::Zstd);
+
+        match decoder.decode(&mut bytes) {
+            Err(CodecError::DecompressedTooLarge { max: 1024 }) => {}
+            _ => panic!("decompression limit exceeded"),
+        }
+
+        match MessageCodec::default().decode(&mut unnegotiated) {
+            Err(CodecError::CompressionNotNegotiated) => {}
+            _ => panic!("compressed frame accepted"),
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let mut initiator = NoiseHandshake::new(&NoiseConfig::generate().unwrap(), true).unwrap();
+        let mut responder = NoiseHandshake::new(&NoiseConfig::generate().unwrap(), false).unwrap();
+        responder.read(&initiator.write().unwrap()).unwrap();
+        initiator.read(&responder.write().unwrap()).unwrap();
+        responder.read(&initiator.write().unwrap()).unwrap();
+
+        let mut encoder = MessageCodec::default();
+        let mut decoder = MessageCodec::default();
+        encoder.state().borrow_mut().cipher = Some(initiator.into_cipher().unwrap());
+        decoder.state().borrow_mut().cipher = Some(responder.into_cipher().unwrap());
+
+        let mut bytes = BytesMut::new();
+        encoder.encode(encapsulated(128), &mut bytes).unwrap();
+        assert!(!bytes.windows(128).any(|w| w == &[0xAB; 128][..]));
+
+        match decoder.decode(&mut bytes).unwrap() {
+            Some(Message::Encapsulated(e)) => assert_eq!(&vec![0xAB; 128][..], &e.message[..]),
+            _ => panic!("invalid message"),
+        }
+
+        let mut plaintext = BytesMut::new();
+        MessageCodec::default().encode(encapsulated(16), &mut plaintext).unwrap();
+        match decoder.decode(&mut plaintext) {
+            Err(CodecError::Encryption(_)) => {}
+            _ => panic!("plaintext frame accepted"),
+        }
+
+        // the header flags are authenticated along with the payload
+        let mut bytes = BytesMut::new();
+        encoder.encode(encapsulated(16), &mut bytes).unwrap();
+        bytes[MAGIC.len() + 1] ^= FLAG_COMPRESSED;
+        match decoder.decode(&mut bytes) {
+            Err(CodecError::Encryption(_)) => {}
+            _ => panic!("tampered header accepted"),
+        }
+    }
+
+    #[test]
+    fn test_psk() {
+        let codec = |secret: Option<&str>| {
+            let config = CodecConfig {
+                psk: secret.map(|s| PreSharedKey::new(s.as_bytes())),
+                ..CodecConfig::default()
+            };
+            MessageCodec::new(config, Bincode)
+        };
+
+        let mut bytes = BytesMut::new();
+        codec(Some("staging")).encode(encapsulated(64), &mut bytes).unwrap();
+        let foreign = bytes.clone();
+        let open = bytes.clone();
+
+        match codec(Some("staging")).decode(&mut bytes).unwrap() {
+            Some(Message::Encapsulated(e)) => assert_eq!(&vec![0xAB; 64][..], &e.message[..]),
+            _ => panic!("invalid message"),
+        }
+
+        let mut plaintext = BytesMut::new();
+        codec(None).encode(encapsulated(64), &mut plaintext).unwrap();
+
+        let mut rejected = vec![
+            (codec(Some("production")), foreign),
+            (codec(None), open),
+            (codec(Some("staging")), plaintext),
+        ];
+        for &mut (ref mut codec, ref mut bytes) in rejected.iter_mut() {
+            match codec.decode(bytes) {
+                Err(CodecError::Unauthenticated) => {}
+                _ => panic!("unauthenticated frame accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_psk_session() {
+        let session = || {
+            let config = CodecConfig {
+                psk: Some(PreSharedKey::new(b"staging")),
+                ..CodecConfig::default()
+            };
+            let codec = MessageCodec::new(config, Bincode);
+            codec.state().borrow_mut().psk_session = Some(PskSession::default());
+            codec
+        };
+        let open = |a: &mut MessageCodec, b: &mut MessageCodec, nonces: (u8, u8)| {
+            let mut bytes = BytesMut::new();
+            a.encode(Message::SessionNonce(Bytes::from(vec![nonces.0; NONCE_SZ])), &mut bytes).unwrap();
+            b.encode(Message::SessionNonce(Bytes::from(vec![nonces.1; NONCE_SZ])), &mut bytes).unwrap();
+            let half = bytes.len() / 2;
+            assert!(b.decode(&mut bytes.split_to(half)).unwrap().is_some());
+            assert!(a.decode(&mut bytes).unwrap().is_some());
+        };
+
+        let (mut alice, mut bob) = (session(), session());
+        // nothing but the nonce is sent before the exchange
+        assert!(alice.encode(encapsulated(16), &mut BytesMut::new()).is_err());
+        open(&mut alice, &mut bob, (1, 2));
+
+        let mut first = BytesMut::new();
+        let mut second = BytesMut::new();
+        alice.encode(encapsulated(16), &mut first).unwrap();
+        alice.encode(encapsulated(16), &mut second).unwrap();
+
+        assert!(bob.decode(&mut first.clone()).unwrap().is_some());
+        // replayed within the session
+        match bob.decode(&mut first.clone()) {
+            Err(CodecError::Unauthenticated) => {}
+            _ => panic!("replayed frame accepted"),
+        }
+        assert!(bob.decode(&mut second).unwrap().is_some());
+
+        // and into another session
+        let (mut mallory, mut carol) = (session(), session());
+        open(&mut mallory, &mut carol, (1, 3));
+        match carol.decode(&mut first) {
+            Err(CodecError::Unauthenticated) => {}
+            _ => panic!("frame of another session accepted"),
+        }
+    }
+
+    #[test]
+    fn test_signed() {
+        let identity = Identity::generate().unwrap();
+        let signed = match encapsulated(128) {
+            Message::Encapsulated(e) => identity.sign_message(e),
+            _ => unreachable!(),
+        };
+
+        let mut codec = MessageCodec::default();
+        let mut bytes = BytesMut::new();
+        codec.encode(Message::Signed(signed), &mut bytes).unwrap();
+
+        match codec.decode(&mut bytes).unwrap() {
+            Some(Message::Signed(s)) => {
+                assert_eq!(&vec![0xAB; 128][..], &s.encapsulated.message[..]);
+                match verify_message(&s) {
+                    SignatureStatus::Valid(signer, _) => assert_eq!(identity.peer_id(), signer),
+                    status => panic!("invalid signature ({:?})", status),
+                }
+            }
+            _ => panic!("invalid message"),
+        }
+    }
+
+    #[test]
+    fn test_sequenced() {
+        let mut codec = MessageCodec::default();
+        let sequence = Sequence {
+            number: 7,
+            timestamp: 1_500_000_000_000,
+        };
+
+        let mut bytes = BytesMut::new();
+        codec.encode_message(encapsulated(16), Some(sequence), &mut bytes).unwrap();
+        codec.encode(encapsulated(16), &mut bytes).unwrap();
+
+        match codec.decode_sequenced(&mut bytes).unwrap() {
+            Some((Message::Encapsulated(e), Some(s))) => {
+                assert_eq!(sequence, s);
+                assert_eq!(&vec![0xAB; 16][..], &e.message[..]);
+            }
+            _ => panic!("invalid message"),
+        }
+
+        match codec.decode_sequenced(&mut bytes).unwrap() {
+            Some((Message::Encapsulated(_), None)) => {}
+            _ => panic!("invalid message"),
+        }
+    }
+
+    #[test]
+    fn test_nested_reliable() {
+        // Message::Reliable { sequence, ordered, message: Message::Reliable { .. } },
+        // nested 100000 times: reliable payloads are not messages
+        let mut payload = BytesMut::with_capacity(100_000 * 13);
+        for _ in 0..100_000 {
+            payload.put_u32_be(9);
+            payload.put_u64_be(0);
+            payload.put_u8(0);
+        }
+
+        let mut bytes = BytesMut::new();
+        FrameHeader::new(0, &[&payload[..]]).write(&mut bytes);
+        bytes.extend_from_slice(&payload);
+
+        assert!(MessageCodec::default().decode(&mut bytes).is_err());
+    }
 
-        bytes.split_to(LEN_SZ);
+    #[test]
+    fn test_zero_copy() {
+        let mut codec = MessageCodec::default();
+        let mut bytes = BytesMut::new();
+        codec.encode(encapsulated(128), &mut bytes).unwrap();
 
-        let msg = bytes.split_to(size);
-        match deserialize::<Message, Order>(msg.as_ref()) {
-            Ok(m) => Ok(Some(m)),
-            Err(e) => Err(CodecError::from(e)),
+        // header (8) + protocol id (2)
+        let payload = bytes[10..].as_ptr();
+        match codec.decode(&mut bytes).unwrap() {
+            Some(Message::Encapsulated(e)) => assert_eq!(payload, e.message.as_ptr()),
+            _ => panic!("invalid message"),
         }
     }
 }
@@ -0,0 +1,270 @@
+use std::fmt;
+
+use snow::params::NoiseParams;
+use snow::{Builder, Session};
+
+use codec::error::CodecError;
+
+/// Noise protocol name
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Maximum Noise message size
+const MAX_MESSAGE_SZ: usize = 65535;
+/// Poly1305 authentication tag size
+const TAG_SZ: usize = 16;
+const MAX_PLAINTEXT_SZ: usize = MAX_MESSAGE_SZ - TAG_SZ;
+
+/// Size of `len` bytes of plaintext once encrypted
+pub fn encrypted_len(len: usize) -> usize {
+    len + (len / MAX_PLAINTEXT_SZ + 1) * TAG_SZ
+}
+
+fn noise_error<E: fmt::Debug>(e: E) -> CodecError {
+    CodecError::Encryption(format!("{:?}", e))
+}
+
+/// Noise session encryption configuration
+#[derive(Clone)]
+pub struct NoiseConfig {
+    /// Static X25519 private key
+    pub private_key: Vec<u8>,
+    /// Number of frames sent (or received) between rekeys; 0 disables rekeying
+    pub rekey_after: u64,
+}
+
+impl NoiseConfig {
+    /// Creates a configuration with a freshly generated static key
+    pub fn generate() -> Result<Self, CodecError> {
+        let params: NoiseParams = NOISE_PARAMS.parse().map_err(noise_error)?;
+        let keypair = Builder::new(params).generate_keypair().map_err(noise_error)?;
+
+        Ok(NoiseConfig {
+            private_key: keypair.private,
+            rekey_after: 65536,
+        })
+    }
+}
+
+impl fmt::Debug for NoiseConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NoiseConfig(rekey_after: {})", self.rekey_after)
+    }
+}
+
+/// Noise XX handshake in progress
+pub struct NoiseHandshake {
+    state: Session,
+    /// Whether the next handshake message is ours to write
+    my_turn: bool,
+    rekey_after: u64,
+}
+
+impl NoiseHandshake {
+    pub fn new(config: &NoiseConfig, initiator: bool) -> Result<Self, CodecError> {
+        let params: NoiseParams = NOISE_PARAMS.parse().map_err(noise_error)?;
+        let builder = Builder::new(params).local_private_key(&config.private_key);
+        let state = match initiator {
+            true => builder.build_initiator(),
+            false => builder.build_responder(),
+        };
+
+        Ok(NoiseHandshake {
+            state: state.map_err(noise_error)?,
+            my_turn: initiator,
+            rekey_after: config.rekey_after,
+        })
+    }
+
+    pub fn is_my_turn(&self) -> bool {
+        self.my_turn
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_handshake_finished()
+    }
+
+    /// Produces the next handshake message
+    pub fn write(&mut self) -> Result<Vec<u8>, CodecError> {
+        let mut buf = vec![0u8; MAX_MESSAGE_SZ];
+        let len = self.state.write_message(&[], &mut buf).map_err(noise_error)?;
+        buf.truncate(len);
+        self.my_turn = false;
+        Ok(buf)
+    }
+
+    /// Consumes a handshake message received from the remote peer
+    pub fn read(&mut self, message: &[u8]) -> Result<(), CodecError> {
+        let mut buf = vec![0u8; MAX_MESSAGE_SZ];
+        self.state.read_message(message, &mut buf).map_err(noise_error)?;
+        self.my_turn = true;
+        Ok(())
+    }
+
+    /// Remote peer's static public key, once revealed
+    pub fn remote_static(&self) -> Option<Vec<u8>> {
+        self.state.get_remote_static().map(|k| k.to_vec())
+    }
+
+    /// Hash of the handshake transcript, identical on both ends once the
+    /// handshake is finished
+    pub fn handshake_hash(&self) -> Result<Vec<u8>, CodecError> {
+        let hash = self.state.get_handshake_hash().map_err(noise_error)?;
+        Ok(hash.to_vec())
+    }
+
+    pub fn into_cipher(self) -> Result<SessionCipher, CodecError> {
+        let state = self.state.into_transport_mode().map_err(noise_error)?;
+        Ok(SessionCipher {
+            state,
+            rekey_after: self.rekey_after,
+            sent: 0,
+            received: 0,
+        })
+    }
+}
+
+/// ChaCha20-Poly1305 frame encryption established by a Noise handshake.
+///
+/// Payloads are sealed in chunks of at most 65535 bytes (tag included), so
+/// chunk boundaries in a ciphertext are implied by its length. Noise
+/// transport messages take no associated data, so it is sealed as a prefix
+/// of the plaintext instead and compared on decryption.
+pub struct SessionCipher {
+    state: Session,
+    rekey_after: u64,
+    sent: u64,
+    received: u64,
+}
+
+impl SessionCipher {
+    pub fn encrypt(&mut self, ad: &[u8], payload: &[&[u8]]) -> Result<Vec<u8>, CodecError> {
+        let mut plaintext = ad.to_vec();
+        for part in payload {
+            plaintext.extend_from_slice(part);
+        }
+        let chunks = plaintext.len() / MAX_PLAINTEXT_SZ + 1;
+        let mut ciphertext = vec![0u8; encrypted_len(plaintext.len())];
+
+        let mut written = 0;
+        for i in 0..chunks {
+            let start = i * MAX_PLAINTEXT_SZ;
+            let end = plaintext.len().min(start + MAX_PLAINTEXT_SZ);
+            written += self
+                .state
+                .write_message(&plaintext[start..end], &mut ciphertext[written..])
+                .map_err(noise_error)?;
+        }
+
+        ciphertext.truncate(written);
+        self.sent += 1;
+        if self.rekey_after > 0 && self.sent % self.rekey_after == 0 {
+            self.state.rekey_outgoing().map_err(noise_error)?;
+        }
+
+        Ok(ciphertext)
+    }
+
+    pub fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let mut read = 0;
+
+        for chunk in ciphertext.chunks(MAX_MESSAGE_SZ) {
+            read += self
+                .state
+                .read_message(chunk, &mut plaintext[read..])
+                .map_err(noise_error)?;
+        }
+
+        plaintext.truncate(read);
+        self.received += 1;
+        if self.rekey_after > 0 && self.received % self.rekey_after == 0 {
+            self.state.rekey_incoming().map_err(noise_error)?;
+        }
+
+        if plaintext.len() < ad.len() || &plaintext[..ad.len()] != ad {
+            return Err(CodecError::Encryption(
+                "associated data mismatch".to_owned(),
+            ));
+        }
+        Ok(plaintext.split_off(ad.len()))
+    }
+}
+
+impl fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SessionCipher(sent: {}, received: {})", self.sent, self.received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::noise::{encrypted_len, NoiseConfig, NoiseHandshake, SessionCipher};
+
+    fn handshake(rekey_after: u64) -> (SessionCipher, SessionCipher) {
+        let mut configs = vec![];
+        for _ in 0..2 {
+            let mut config = NoiseConfig::generate().unwrap();
+            config.rekey_after = rekey_after;
+            configs.push(config);
+        }
+
+        let mut initiator = NoiseHandshake::new(&configs[0], true).unwrap();
+        let mut responder = NoiseHandshake::new(&configs[1], false).unwrap();
+
+        // -> e
+        responder.read(&initiator.write().unwrap()).unwrap();
+        // <- e, ee, s, es
+        initiator.read(&responder.write().unwrap()).unwrap();
+        // -> s, se
+        responder.read(&initiator.write().unwrap()).unwrap();
+
+        assert!(initiator.is_finished() && responder.is_finished());
+        assert!(initiator.remote_static().is_some());
+        assert_eq!(initiator.handshake_hash().unwrap(), responder.handshake_hash().unwrap());
+        (initiator.into_cipher().unwrap(), responder.into_cipher().unwrap())
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let (mut initiator, mut responder) = handshake(0);
+        let payload = vec![7u8; 200 * 1024];
+
+        let ciphertext = initiator.encrypt(&[], &[&payload[..10], &payload[10..]]).unwrap();
+        assert_eq!(encrypted_len(payload.len()), ciphertext.len());
+        assert_eq!(payload, responder.decrypt(&[], &ciphertext).unwrap());
+
+        let empty = responder.encrypt(&[], &[]).unwrap();
+        assert!(initiator.decrypt(&[], &empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tampering() {
+        let (mut initiator, mut responder) = handshake(0);
+
+        let mut ciphertext = initiator.encrypt(&[], &[&b"payload"[..]]).unwrap();
+        ciphertext[0] ^= 0xFF;
+        assert!(responder.decrypt(&[], &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_associated_data() {
+        let (mut initiator, mut responder) = handshake(0);
+
+        let ciphertext = initiator.encrypt(b"ad", &[&b"payload"[..]]).unwrap();
+        assert_eq!(encrypted_len(b"adpayload".len()), ciphertext.len());
+        assert_eq!(b"payload".to_vec(), responder.decrypt(b"ad", &ciphertext).unwrap());
+
+        let ciphertext = initiator.encrypt(b"ad", &[&b"payload"[..]]).unwrap();
+        assert!(responder.decrypt(b"da", &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let (mut initiator, mut responder) = handshake(2);
+
+        for _ in 0..5 {
+            let ciphertext = initiator.encrypt(&[], &[&b"payload"[..]]).unwrap();
+            assert_eq!(b"payload".to_vec(), responder.decrypt(&[], &ciphertext).unwrap());
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use bincode::internal::{deserialize, serialize};
+use bincode::Bounded;
+use byteorder::BigEndian as Order;
+use rmp_serde;
+use serde_cbor;
+
+use codec::error::CodecError;
+use codec::message::Message;
+
+/// Message (de)serialization backend
+pub trait Serializer {
+    fn serialize(&self, message: &Message) -> Result<Vec<u8>, CodecError>;
+    fn deserialize(&self, bytes: &[u8]) -> Result<Message, CodecError>;
+
+    /// Whether application payloads are written to frames as they are,
+    /// bypassing the serializer. Formats shared with other implementations
+    /// keep every frame in the format, so that peers need nothing else to
+    /// decode it.
+    fn raw_payloads(&self) -> bool {
+        false
+    }
+}
+
+impl<S> Serializer for Box<S>
+where
+    S: Serializer + ?Sized,
+{
+    fn serialize(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        (**self).serialize(message)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Message, CodecError> {
+        (**self).deserialize(bytes)
+    }
+
+    fn raw_payloads(&self) -> bool {
+        (**self).raw_payloads()
+    }
+}
+
+/// Big-endian bincode
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl Serializer for Bincode {
+    fn serialize(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        let size_limit = Bounded(u32::max_value() as u64);
+        serialize::<Message, Bounded, Order>(message, size_limit).map_err(CodecError::from)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Message, CodecError> {
+        deserialize::<Message, Order>(bytes).map_err(CodecError::from)
+    }
+
+    /// Only nodes of this implementation speak bincode
+    fn raw_payloads(&self) -> bool {
+        true
+    }
+}
+
+/// CBOR (RFC 7049)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+impl Serializer for Cbor {
+    fn serialize(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        serde_cbor::to_vec(message).map_err(|e| CodecError::new(&format!("cbor: {}", e)))
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Message, CodecError> {
+        serde_cbor::from_slice(bytes).map_err(|e| CodecError::new(&format!("cbor: {}", e)))
+    }
+}
+
+/// MessagePack, with structs encoded as maps
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+impl Serializer for MessagePack {
+    fn serialize(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(message)
+            .map_err(|e| CodecError::new(&format!("msgpack: {}", e)))
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> Result<Message, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(|e| CodecError::new(&format!("msgpack: {}", e)))
+    }
+}
+
+/// Serialization backend selector
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Serialization {
+    Bincode,
+    Cbor,
+    MessagePack,
+}
+
+impl Serialization {
+    pub fn serializer(&self) -> Box<Serializer> {
+        match *self {
+            Serialization::Bincode => Box::new(Bincode),
+            Serialization::Cbor => Box::new(Cbor),
+            Serialization::MessagePack => Box::new(MessagePack),
+        }
+    }
+}
+
+impl Default for Serialization {
+    fn default() -> Self {
+        Serialization::Bincode
+    }
+}
@@ -1,16 +1,38 @@
 use std::net::SocketAddr;
 
 use error::Error;
-use codec::message::Encapsulated;
-use transport::TransportProtocol;
+use identity::{PeerInfo, SignatureStatus};
+use codec::message::{Encapsulated, Signature};
+use protocol::TypedMessage;
+use transport::tls::CertFingerprint;
+use transport::{DisconnectReason, RefusalReason, TransportProtocol};
 
 #[derive(Debug)]
 pub enum Event {
     Exiting,
     Started(TransportProtocol, SocketAddr),
     Stopped(TransportProtocol, SocketAddr),
-    Connected(TransportProtocol, SocketAddr, bool),
-    Disconnected(TransportProtocol, SocketAddr),
-    Message(TransportProtocol, SocketAddr, Encapsulated),
-    Error(Error),
+    /// Session established and the remote node authenticated; carries
+    /// whether it was initiated locally and the TLS peer certificate
+    /// fingerprint
+    Connected(TransportProtocol, SocketAddr, bool, Option<CertFingerprint>, PeerInfo),
+    /// Session closed; also emitted without a preceding `Connected` when
+    /// the session setup fails
+    Disconnected(TransportProtocol, SocketAddr, DisconnectReason),
+    /// Message, with the outcome of verifying its originator signature and
+    /// the signature, to forward the message with
+    Message(TransportProtocol, SocketAddr, Encapsulated, SignatureStatus, Option<Signature>),
+    /// Message of a registered protocol, decoded into its type
+    Typed(TransportProtocol, SocketAddr, TypedMessage, SignatureStatus, Option<Signature>),
+    /// Error, with the transport session it originated from
+    Error(Option<(TransportProtocol, SocketAddr)>, Error),
+    /// Incoming or outgoing connection refused by the firewall or
+    /// admission limits
+    Refused(TransportProtocol, SocketAddr, RefusalReason),
+    /// Session moved from the first address to the second one, after the
+    /// remote node proved its identity there
+    Migrated(TransportProtocol, SocketAddr, SocketAddr),
+    /// Node announced on the LAN; carries the address the announcement
+    /// came from
+    PeerDiscovered(SocketAddr, PeerInfo),
 }
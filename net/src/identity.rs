@@ -0,0 +1,427 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair};
+use untrusted::Input;
+
+use codec::message::{Encapsulated, ListenPorts, Signature, Signed};
+use error::{Error, ErrorKind, ErrorSeverity};
+
+/// Size of a handshake nonce, in bytes
+pub const NONCE_SZ: usize = 32;
+/// Size of an Ed25519 public key, in bytes
+pub const PUBLIC_KEY_SZ: usize = 32;
+/// Size of an Ed25519 signature, in bytes
+pub const SIGNATURE_SZ: usize = 64;
+
+/// Domain separation prefix of signed Hello transcripts
+const HELLO_CONTEXT: &[u8] = b"golem-net-hello";
+/// Domain separation prefix of signed messages
+const MESSAGE_CONTEXT: &[u8] = b"golem-net-message";
+/// Domain separation prefix of signed path challenges
+const PATH_CONTEXT: &[u8] = b"golem-net-path";
+/// Domain separation prefix of signed session teardowns
+const GOODBYE_CONTEXT: &[u8] = b"golem-net-goodbye";
+
+fn identity_error(message: &str) -> Error {
+    Error::new(ErrorKind::Other, ErrorSeverity::High, message)
+}
+
+/// Node identifier: SHA-256 digest of the node's Ed25519 public key
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 32]);
+
+impl PeerId {
+    pub fn from_public_key(public_key: &[u8]) -> Self {
+        let mut id = [0u8; 32];
+        id.copy_from_slice(digest::digest(&digest::SHA256, public_key).as_ref());
+        PeerId(id)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerId({})", self.to_hex())
+    }
+}
+
+/// Session handshake transcript a `HelloAck` signs, from the signer's
+/// point of view. A signature is only valid for the session it was made in,
+/// so a node cannot be used to answer challenges of other sessions.
+#[derive(Clone, Copy, Debug)]
+pub struct HelloTranscript<'a> {
+    /// Whether the signer initiated the session
+    pub initiator: bool,
+    pub signer_key: &'a [u8],
+    pub verifier_key: &'a [u8],
+    /// Nonce of the signer's Hello
+    pub signer_nonce: &'a [u8],
+    /// Nonce of the verifier's Hello
+    pub verifier_nonce: &'a [u8],
+    /// Channel binding: the Noise handshake hash and the TLS exporter
+    /// value, if the session has either
+    pub binding: &'a [u8],
+}
+
+impl<'a> HelloTranscript<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let role: &[u8] = match self.initiator {
+            true => b"I",
+            false => b"R",
+        };
+
+        let parts: [&[u8]; 7] = [
+            HELLO_CONTEXT,
+            role,
+            self.signer_key,
+            self.verifier_key,
+            self.signer_nonce,
+            self.verifier_nonce,
+            self.binding,
+        ];
+        parts.concat()
+    }
+
+    /// Keys and nonces have fixed sizes, so that the encoding is unambiguous
+    fn is_valid(&self) -> bool {
+        self.signer_key.len() == PUBLIC_KEY_SZ
+            && self.verifier_key.len() == PUBLIC_KEY_SZ
+            && self.signer_nonce.len() == NONCE_SZ
+            && self.verifier_nonce.len() == NONCE_SZ
+    }
+}
+
+/// Path challenge a `PathResponse` signs, binding the answer to a single
+/// session and to the address that was challenged
+#[derive(Clone, Copy, Debug)]
+pub struct PathTranscript<'a> {
+    /// Connection ID of the challenged node's end of the session
+    pub connection_id: u64,
+    /// Hello nonce of the challenging node
+    pub challenger_nonce: &'a [u8],
+    /// Hello nonce of the challenged node
+    pub responder_nonce: &'a [u8],
+    pub nonce: &'a [u8],
+    /// Address the challenge was sent to
+    pub address: SocketAddr,
+}
+
+impl<'a> PathTranscript<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let mut connection_id = [0u8; 8];
+        BigEndian::write_u64(&mut connection_id, self.connection_id);
+        let address = self.address.to_string();
+
+        let parts: [&[u8]; 6] = [
+            PATH_CONTEXT,
+            &connection_id,
+            self.challenger_nonce,
+            self.responder_nonce,
+            self.nonce,
+            address.as_bytes(),
+        ];
+        parts.concat()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.challenger_nonce.len() == NONCE_SZ
+            && self.responder_nonce.len() == NONCE_SZ
+            && self.nonce.len() == NONCE_SZ
+    }
+}
+
+/// Authenticated remote node
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    pub public_key: Vec<u8>,
+    /// Node version reported by the peer
+    pub version: String,
+    /// Ports the peer accepts connections on
+    pub listen_ports: ListenPorts,
+}
+
+/// Node Ed25519 keypair
+pub struct Identity {
+    keypair: Ed25519KeyPair,
+    pkcs8: Vec<u8>,
+    peer_id: PeerId,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self, Error> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| identity_error("cannot generate a keypair"))?;
+        Identity::from_pkcs8(&pkcs8[..])
+    }
+
+    /// Loads a keypair from its PKCS#8 document
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, Error> {
+        let keypair = Ed25519KeyPair::from_pkcs8(Input::from(pkcs8))
+            .map_err(|_| identity_error("invalid PKCS#8 keypair"))?;
+        let peer_id = PeerId::from_public_key(keypair.public_key_bytes());
+
+        Ok(Identity {
+            keypair,
+            pkcs8: pkcs8.to_vec(),
+            peer_id,
+        })
+    }
+
+    /// PKCS#8 document, for storage
+    pub fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.keypair.public_key_bytes()
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.keypair.sign(message).as_ref().to_vec()
+    }
+
+    /// Signs the transcript of a session handshake, answering the remote
+    /// node's Hello
+    pub fn sign_hello(&self, transcript: &HelloTranscript) -> Vec<u8> {
+        self.sign(&transcript.encode())
+    }
+
+    /// Signs a path challenge of our new address
+    pub fn sign_path_challenge(&self, transcript: &PathTranscript) -> Vec<u8> {
+        self.sign(&transcript.encode())
+    }
+
+    /// Signs the teardown of the session with the given Hello nonces
+    pub fn sign_goodbye(&self, nonce: &[u8], remote_nonce: &[u8]) -> Vec<u8> {
+        self.sign(&[GOODBYE_CONTEXT, nonce, remote_nonce].concat())
+    }
+
+    /// Wraps a message in a signed envelope
+    pub fn sign_message(&self, encapsulated: Encapsulated) -> Signed {
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => 0,
+        };
+
+        let content = signed_content(&encapsulated, timestamp);
+        let signature = Signature {
+            signer: self.peer_id,
+            public_key: Bytes::from(self.public_key()),
+            signature: Bytes::from(self.sign(&content)),
+            timestamp,
+        };
+
+        Signed {
+            encapsulated,
+            signature,
+        }
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Identity({})", self.peer_id.to_hex())
+    }
+}
+
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    signature::verify(
+        &signature::ED25519,
+        Input::from(public_key),
+        Input::from(message),
+        Input::from(signature),
+    ).is_ok()
+}
+
+/// Verifies a transcript signature produced by `Identity::sign_hello`
+/// with the transcript's signer key
+pub fn verify_hello(transcript: &HelloTranscript, signature: &[u8]) -> bool {
+    transcript.is_valid() && verify(transcript.signer_key, &transcript.encode(), signature)
+}
+
+/// Verifies a signature produced by `Identity::sign_path_challenge`
+pub fn verify_path_challenge(public_key: &[u8], transcript: &PathTranscript, signature: &[u8]) -> bool {
+    transcript.is_valid() && verify(public_key, &transcript.encode(), signature)
+}
+
+/// Verifies a signature produced by `Identity::sign_goodbye`, given the
+/// signer's and the verifier's Hello nonces
+pub fn verify_goodbye(public_key: &[u8], signer_nonce: &[u8], verifier_nonce: &[u8], signature: &[u8]) -> bool {
+    signer_nonce.len() == NONCE_SZ
+        && verifier_nonce.len() == NONCE_SZ
+        && verify(public_key, &[GOODBYE_CONTEXT, signer_nonce, verifier_nonce].concat(), signature)
+}
+
+/// Outcome of message signature verification
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignatureStatus {
+    Unsigned,
+    /// Signature is valid; carries the signer and the signing time
+    Valid(PeerId, u64),
+    /// Signature does not match the message or the claimed signer
+    Invalid(PeerId),
+}
+
+fn signed_content(encapsulated: &Encapsulated, timestamp: u64) -> Vec<u8> {
+    let mut header = [0u8; 10];
+    BigEndian::write_u16(&mut header[..2], encapsulated.protocol_id);
+    BigEndian::write_u64(&mut header[2..], timestamp);
+
+    let parts: [&[u8]; 3] = [MESSAGE_CONTEXT, &header, &encapsulated.message];
+    parts.concat()
+}
+
+pub fn verify_message(signed: &Signed) -> SignatureStatus {
+    let signature = &signed.signature;
+    let content = signed_content(&signed.encapsulated, signature.timestamp);
+
+    let valid = PeerId::from_public_key(&signature.public_key) == signature.signer
+        && verify(&signature.public_key, &content, &signature.signature);
+
+    match valid {
+        true => SignatureStatus::Valid(signature.signer, signature.timestamp),
+        false => SignatureStatus::Invalid(signature.signer),
+    }
+}
+
+pub fn nonce() -> Result<[u8; NONCE_SZ], Error> {
+    let mut nonce = [0u8; NONCE_SZ];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| identity_error("cannot generate a nonce"))?;
+    Ok(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use codec::message::Encapsulated;
+    use identity::{
+        nonce, verify_hello, verify_message, verify_path_challenge, HelloTranscript, Identity,
+        PathTranscript, PeerId, SignatureStatus,
+    };
+
+    #[test]
+    fn test_sign_hello() {
+        let identity = Identity::generate().unwrap();
+        let other = Identity::generate().unwrap();
+        let (nonce, other_nonce) = (nonce().unwrap(), nonce().unwrap());
+
+        let transcript = HelloTranscript {
+            initiator: true,
+            signer_key: identity.public_key(),
+            verifier_key: other.public_key(),
+            signer_nonce: &nonce,
+            verifier_nonce: &other_nonce,
+            binding: b"handshake hash",
+        };
+        let signature = identity.sign_hello(&transcript);
+        assert!(verify_hello(&transcript, &signature));
+
+        // the signature does not answer challenges of other sessions
+        let third = Identity::generate().unwrap();
+        let other_sessions = vec![
+            HelloTranscript { initiator: false, ..transcript },
+            HelloTranscript { verifier_key: third.public_key(), ..transcript },
+            HelloTranscript { signer_key: other.public_key(), ..transcript },
+            HelloTranscript { verifier_nonce: &nonce, ..transcript },
+            HelloTranscript { binding: b"", ..transcript },
+        ];
+        for t in other_sessions {
+            assert!(!verify_hello(&t, &signature));
+        }
+    }
+
+    #[test]
+    fn test_sign_path_challenge() {
+        let identity = Identity::generate().unwrap();
+        let (challenger, responder, challenge) = (nonce().unwrap(), nonce().unwrap(), nonce().unwrap());
+
+        let transcript = PathTranscript {
+            connection_id: 7,
+            challenger_nonce: &challenger,
+            responder_nonce: &responder,
+            nonce: &challenge,
+            address: "10.0.0.1:40102".parse().unwrap(),
+        };
+        let signature = identity.sign_path_challenge(&transcript);
+        assert!(verify_path_challenge(identity.public_key(), &transcript, &signature));
+
+        // answers to challenges relayed through other sessions, or to other
+        // addresses, are rejected
+        let other = nonce().unwrap();
+        let relayed = vec![
+            PathTranscript { connection_id: 8, ..transcript },
+            PathTranscript { challenger_nonce: &other, ..transcript },
+            PathTranscript { responder_nonce: &other, ..transcript },
+            PathTranscript { address: "10.0.0.2:40102".parse().unwrap(), ..transcript },
+        ];
+        for t in relayed {
+            assert!(!verify_path_challenge(identity.public_key(), &t, &signature));
+        }
+        let impostor = Identity::generate().unwrap();
+        assert!(!verify_path_challenge(impostor.public_key(), &transcript, &signature));
+    }
+
+    #[test]
+    fn test_sign_message() {
+        let identity = Identity::generate().unwrap();
+        let encapsulated = Encapsulated {
+            protocol_id: 7,
+            message: Bytes::from(&b"payload"[..]),
+        };
+
+        let signed = identity.sign_message(encapsulated);
+        let timestamp = signed.signature.timestamp;
+        match verify_message(&signed) {
+            SignatureStatus::Valid(signer, t) => {
+                assert_eq!(identity.peer_id(), signer);
+                assert_eq!(timestamp, t);
+            }
+            status => panic!("invalid signature ({:?})", status),
+        }
+
+        let mut tampered = signed.clone();
+        tampered.encapsulated.protocol_id = 8;
+        assert_eq!(SignatureStatus::Invalid(identity.peer_id()), verify_message(&tampered));
+
+        // a valid signature claimed by another node
+        let mut impersonated = signed;
+        impersonated.signature.signer = Identity::generate().unwrap().peer_id();
+        match verify_message(&impersonated) {
+            SignatureStatus::Invalid(_) => {}
+            status => panic!("impersonation accepted ({:?})", status),
+        }
+    }
+
+    #[test]
+    fn test_pkcs8() {
+        let identity = Identity::generate().unwrap();
+        let restored = Identity::from_pkcs8(identity.pkcs8()).unwrap();
+
+        assert_eq!(identity.peer_id(), restored.peer_id());
+        assert_eq!(PeerId::from_public_key(identity.public_key()), restored.peer_id());
+        assert!(Identity::from_pkcs8(&[0u8; 16]).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bincode::internal::{deserialize, serialize};
+use bincode::Infinite;
+use byteorder::{BigEndian, ByteOrder};
+use ring::aead::{self, OpeningKey, SealingKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use scrypt::{scrypt, ScryptParams};
+
+use error::{Error, ErrorKind, ErrorSeverity};
+use identity::{self, Identity, PeerId};
+use write_atomically;
+
+/// Keystore file magic
+const MAGIC: &[u8] = b"GNKS";
+/// Keystore file format version
+pub const VERSION: u8 = 1;
+
+const SALT_SZ: usize = 32;
+const NONCE_SZ: usize = 12;
+const KEY_SZ: usize = 32;
+/// Magic, version, scrypt parameters, salt and nonce
+const HEADER_SZ: usize = 4 + 1 + 9 + SALT_SZ + NONCE_SZ;
+
+/// Largest scrypt memory cost, `128 * r * 2^log_n` bytes, accepted from a
+/// keystore file
+const MAX_KDF_MEMORY: u64 = 1024 * 1024 * 1024;
+/// Largest scrypt parallelization accepted from a keystore file
+const MAX_KDF_P: u32 = 16;
+
+/// Domain separation prefix of key rotation signatures
+const ROTATION_CONTEXT: &[u8] = b"golem-net-rotation";
+
+fn keystore_error(message: &str) -> Error {
+    Error::new(ErrorKind::Other, ErrorSeverity::High, message)
+}
+
+/// scrypt key derivation parameters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KdfParams {
+    /// Base 2 logarithm of the CPU/memory cost
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Checks the cost of the parameters, which are read from the keystore
+    /// file before it is authenticated
+    fn check(&self) -> Result<(), Error> {
+        let affordable = self.log_n < 64
+            && (128 * u64::from(self.r))
+                .checked_mul(1 << self.log_n)
+                .map_or(false, |memory| memory <= MAX_KDF_MEMORY);
+
+        if !affordable || self.p > MAX_KDF_P {
+            return Err(keystore_error("key derivation parameters too costly"));
+        }
+        Ok(())
+    }
+
+    fn derive(&self, passphrase: &[u8], salt: &[u8]) -> Result<[u8; KEY_SZ], Error> {
+        self.check()?;
+        let params = ScryptParams::new(self.log_n, self.r, self.p)
+            .map_err(|_| keystore_error("invalid key derivation parameters"))?;
+
+        let mut key = [0u8; KEY_SZ];
+        scrypt(passphrase, salt, &params, &mut key)
+            .map_err(|_| keystore_error("key derivation failed"))?;
+        Ok(key)
+    }
+}
+
+/// Replacement of a node key, signed by the replaced key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyRotation {
+    pub previous_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    /// Rotation time, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+impl KeyRotation {
+    fn content(public_key: &[u8], timestamp: u64) -> Vec<u8> {
+        let mut encoded = [0u8; 8];
+        BigEndian::write_u64(&mut encoded, timestamp);
+
+        let parts: [&[u8]; 3] = [ROTATION_CONTEXT, public_key, &encoded];
+        parts.concat()
+    }
+
+    pub fn previous_peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.previous_key)
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.public_key)
+    }
+
+    /// Checks that the previous key signed the new one
+    pub fn verify(&self) -> bool {
+        let content = KeyRotation::content(&self.public_key, self.timestamp);
+        identity::verify(&self.previous_key, &content, &self.signature)
+    }
+}
+
+/// Encrypted file contents
+#[derive(Serialize, Deserialize)]
+struct Contents {
+    pkcs8: Vec<u8>,
+    rotations: Vec<KeyRotation>,
+}
+
+/// Node keypair along with the chain of rotations leading to it
+pub struct Keystore {
+    identity: Arc<Identity>,
+    rotations: Vec<KeyRotation>,
+}
+
+impl Keystore {
+    pub fn generate() -> Result<Self, Error> {
+        Ok(Keystore {
+            identity: Arc::new(Identity::generate()?),
+            rotations: Vec::new(),
+        })
+    }
+
+    pub fn identity(&self) -> Arc<Identity> {
+        self.identity.clone()
+    }
+
+    /// Rotations, oldest first
+    pub fn rotations(&self) -> &[KeyRotation] {
+        &self.rotations
+    }
+
+    /// Replaces the keypair with a new one, signed by the current one
+    pub fn rotate(&mut self) -> Result<&KeyRotation, Error> {
+        let next = Identity::generate()?;
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => 0,
+        };
+
+        let content = KeyRotation::content(next.public_key(), timestamp);
+        self.rotations.push(KeyRotation {
+            previous_key: self.identity.public_key().to_vec(),
+            public_key: next.public_key().to_vec(),
+            timestamp,
+            signature: self.identity.sign(&content),
+        });
+        self.identity = Arc::new(next);
+
+        Ok(&self.rotations[self.rotations.len() - 1])
+    }
+
+    /// Encrypts the keystore with a key derived from `passphrase`
+    pub fn encrypt(&self, passphrase: &[u8], params: KdfParams) -> Result<Vec<u8>, Error> {
+        let contents = Contents {
+            pkcs8: self.identity.pkcs8().to_vec(),
+            rotations: self.rotations.clone(),
+        };
+        let plaintext = serialize::<_, Infinite, BigEndian>(&contents, Infinite)
+            .map_err(|_| keystore_error("cannot serialize the keystore"))?;
+
+        let mut header = [0u8; HEADER_SZ];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = VERSION;
+        header[5] = params.log_n;
+        BigEndian::write_u32(&mut header[6..10], params.r);
+        BigEndian::write_u32(&mut header[10..14], params.p);
+        SystemRandom::new()
+            .fill(&mut header[14..])
+            .map_err(|_| keystore_error("cannot generate a salt"))?;
+
+        let (salt, nonce) = header[14..].split_at(SALT_SZ);
+        let key = params.derive(passphrase, salt)?;
+        let key = SealingKey::new(&aead::CHACHA20_POLY1305, &key)
+            .map_err(|_| keystore_error("invalid encryption key"))?;
+
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        let mut sealed = plaintext;
+        sealed.extend(vec![0u8; tag_len]);
+        // the header is authenticated along with the contents
+        let len = aead::seal_in_place(&key, nonce, &header, &mut sealed, tag_len)
+            .map_err(|_| keystore_error("encryption failed"))?;
+        sealed.truncate(len);
+
+        Ok([&header[..], &sealed].concat())
+    }
+
+    pub fn decrypt(bytes: &[u8], passphrase: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < HEADER_SZ || &bytes[..4] != MAGIC {
+            return Err(keystore_error("not a keystore"));
+        }
+        if bytes[4] != VERSION {
+            return Err(keystore_error(&format!("unsupported keystore version: {}", bytes[4])));
+        }
+
+        let params = KdfParams {
+            log_n: bytes[5],
+            r: BigEndian::read_u32(&bytes[6..10]),
+            p: BigEndian::read_u32(&bytes[10..14]),
+        };
+        let (header, sealed) = bytes.split_at(HEADER_SZ);
+        let (salt, nonce) = header[14..].split_at(SALT_SZ);
+
+        let key = params.derive(passphrase, salt)?;
+        let key = OpeningKey::new(&aead::CHACHA20_POLY1305, &key)
+            .map_err(|_| keystore_error("invalid encryption key"))?;
+
+        let mut sealed = sealed.to_vec();
+        let plaintext = aead::open_in_place(&key, nonce, header, 0, &mut sealed)
+            .map_err(|_| keystore_error("wrong passphrase or corrupted keystore"))?;
+        let contents: Contents = deserialize::<_, BigEndian>(plaintext)
+            .map_err(|_| keystore_error("malformed keystore contents"))?;
+
+        let identity = Identity::from_pkcs8(&contents.pkcs8)?;
+        let chained = contents.rotations.iter().all(KeyRotation::verify)
+            && contents
+                .rotations
+                .windows(2)
+                .all(|w| w[0].public_key == w[1].previous_key)
+            && contents
+                .rotations
+                .last()
+                .map_or(true, |r| &r.public_key[..] == identity.public_key());
+
+        if !chained {
+            return Err(keystore_error("invalid key rotation chain"));
+        }
+
+        Ok(Keystore {
+            identity: Arc::new(identity),
+            rotations: contents.rotations,
+        })
+    }
+
+    /// Encrypts the keystore to a new file; fails if the file exists
+    pub fn create<P: AsRef<Path>>(
+        &self,
+        path: P,
+        passphrase: &[u8],
+        params: KdfParams,
+    ) -> Result<(), Error> {
+        let bytes = self.encrypt(passphrase, params)?;
+
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Encrypts the keystore to a file, replacing it atomically
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        passphrase: &[u8],
+        params: KdfParams,
+    ) -> Result<(), Error> {
+        let bytes = self.encrypt(passphrase, params)?;
+        write_atomically(path.as_ref(), &bytes)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> Result<Self, Error> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Keystore::decrypt(&bytes, passphrase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use keystore::{KdfParams, Keystore};
+
+    fn params() -> KdfParams {
+        KdfParams {
+            log_n: 8,
+            ..KdfParams::default()
+        }
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let keystore = Keystore::generate().unwrap();
+        let bytes = keystore.encrypt(b"passphrase", params()).unwrap();
+
+        let restored = Keystore::decrypt(&bytes, b"passphrase").unwrap();
+        assert_eq!(keystore.identity().peer_id(), restored.identity().peer_id());
+        assert!(Keystore::decrypt(&bytes, b"wrong").is_err());
+
+        let mut tampered = bytes.clone();
+        // scrypt parameters are authenticated too
+        tampered[9] ^= 1;
+        assert!(Keystore::decrypt(&tampered, b"passphrase").is_err());
+
+        let mut future = bytes.clone();
+        future[4] = 2;
+        assert!(Keystore::decrypt(&future, b"passphrase").is_err());
+
+        // rejected before the key is derived: 2 GiB of memory
+        let mut costly = bytes;
+        costly[5] = 21;
+        assert!(Keystore::decrypt(&costly, b"passphrase").is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut keystore = Keystore::generate().unwrap();
+        let first = keystore.identity().peer_id();
+
+        keystore.rotate().unwrap();
+        let second = keystore.identity().peer_id();
+        keystore.rotate().unwrap();
+
+        let rotations = keystore.rotations();
+        assert_eq!(2, rotations.len());
+        assert_eq!(first, rotations[0].previous_peer_id());
+        assert_eq!(second, rotations[0].peer_id());
+        assert_eq!(keystore.identity().peer_id(), rotations[1].peer_id());
+        assert!(rotations.iter().all(|r| r.verify()));
+
+        let mut forged = rotations[1].clone();
+        forged.public_key = rotations[0].public_key.clone();
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn test_save() {
+        let path = env::temp_dir().join(format!("golem-net-keystore-{}", process::id()));
+        let mut keystore = Keystore::generate().unwrap();
+        keystore.rotate().unwrap();
+        keystore.save(&path, b"passphrase", params()).unwrap();
+
+        let loaded = Keystore::load(&path, b"passphrase").unwrap();
+        assert_eq!(keystore.identity().peer_id(), loaded.identity().peer_id());
+        assert_eq!(1, loaded.rotations().len());
+
+        // an existing keystore is never replaced by a new one
+        let other = Keystore::generate().unwrap();
+        assert!(other.create(&path, b"passphrase", params()).is_err());
+        let loaded = Keystore::load(&path, b"passphrase").unwrap();
+        assert_eq!(keystore.identity().peer_id(), loaded.identity().peer_id());
+
+        fs::remove_file(&path).unwrap();
+        other.create(&path, b"passphrase", params()).unwrap();
+        let loaded = Keystore::load(&path, b"passphrase").unwrap();
+        assert_eq!(other.identity().peer_id(), loaded.identity().peer_id());
+
+        fs::remove_file(path).unwrap();
+    }
+}
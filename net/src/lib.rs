@@ -1,13 +1,26 @@
 extern crate bincode;
 extern crate byteorder;
 extern crate bytes;
+extern crate crc;
 extern crate futures;
+extern crate lz4;
+extern crate net2;
+extern crate ring;
+extern crate rmp_serde;
+extern crate rustls;
+extern crate scrypt;
 extern crate serde;
+extern crate serde_cbor;
+extern crate snow;
 extern crate tokio;
 extern crate tokio_codec;
 extern crate tokio_io;
+extern crate tokio_rustls;
 extern crate tokio_tcp;
 extern crate tokio_udp;
+extern crate untrusted;
+extern crate webpki;
+extern crate zstd;
 
 #[macro_use]
 extern crate actix;
@@ -16,13 +29,48 @@ extern crate serde_derive;
 
 pub mod error;
 pub mod event;
+pub mod identity;
+pub mod keystore;
 pub mod codec;
 pub mod network;
+pub mod protocol;
 pub mod transport;
 
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::path::Path;
 
 pub fn socket_address(host: &String, port: u16) -> Result<SocketAddr, AddrParseError> {
     let ip: IpAddr = host.parse()?;
     Ok(SocketAddr::new(ip, port))
 }
+
+/// Replaces a file atomically: writes a `<file>.tmp` file next to it and
+/// renames it over the file once synced, then syncs the directory so that
+/// the rename survives a crash
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut name = match path.file_name() {
+        Some(name) => name.to_os_string(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file path")),
+    };
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+
+    {
+        let mut file = File::create(&temp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp, path)?;
+
+    // directories can only be opened for syncing on Unix
+    if cfg!(unix) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
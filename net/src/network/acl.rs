@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use identity::{PeerId, PeerInfo};
+use transport::firewall::IpNet;
+
+/// Sender allowed by an access control entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum AclRule {
+    /// Authenticated node
+    Peer(PeerId),
+    /// Authenticated node with this Ed25519 public key
+    PublicKey(Vec<u8>),
+    /// Authenticated node with a session from within the address range.
+    /// The address is the one the session was authenticated from; datagrams
+    /// outside of sessions never match, as their source can be spoofed.
+    Range(IpNet),
+}
+
+impl AclRule {
+    fn matches(&self, address: &SocketAddr, peer: Option<&PeerInfo>) -> bool {
+        match (self, peer) {
+            (&AclRule::Peer(ref id), Some(peer)) => peer.peer_id == *id,
+            (&AclRule::PublicKey(ref key), Some(peer)) => peer.public_key == *key,
+            (&AclRule::Range(ref range), Some(_)) => range.contains(&address.ip()),
+            _ => false,
+        }
+    }
+}
+
+/// Senders allowed to use a protocol id
+#[derive(Clone, Debug, Default)]
+pub struct AclEntry {
+    pub allowed: Vec<AclRule>,
+    /// Close the session of a sender violating the entry
+    pub disconnect: bool,
+}
+
+/// Outcome of an access control check
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AclVerdict {
+    Accept,
+    Drop,
+    /// Drop the message and close the session
+    Disconnect,
+}
+
+/// Access control table keyed by protocol id. Protocol ids without an
+/// entry are open to every sender.
+#[derive(Debug, Default)]
+pub struct Acl {
+    entries: HashMap<u16, AclEntry>,
+    violations: HashMap<u16, u64>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Acl::default()
+    }
+
+    /// Sets, or with `None` removes, the entry of a protocol id
+    pub fn set(&mut self, protocol_id: u16, entry: Option<AclEntry>) {
+        match entry {
+            Some(entry) => self.entries.insert(protocol_id, entry),
+            None => self.entries.remove(&protocol_id),
+        };
+    }
+
+    /// Checks a message sent from `address`, by `peer` if the sender is
+    /// authenticated. Violations are counted.
+    pub fn check(
+        &mut self,
+        protocol_id: u16,
+        address: &SocketAddr,
+        peer: Option<&PeerInfo>,
+    ) -> AclVerdict {
+        let disconnect = match self.entries.get(&protocol_id) {
+            None => return AclVerdict::Accept,
+            Some(entry) if entry.allowed.iter().any(|r| r.matches(address, peer)) => {
+                return AclVerdict::Accept
+            }
+            Some(entry) => entry.disconnect,
+        };
+
+        *self.violations.entry(protocol_id).or_insert(0) += 1;
+        match disconnect {
+            true => AclVerdict::Disconnect,
+            false => AclVerdict::Drop,
+        }
+    }
+
+    /// Number of dropped messages, per protocol id
+    pub fn violations(&self) -> &HashMap<u16, u64> {
+        &self.violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use codec::message::ListenPorts;
+    use identity::{Identity, PeerInfo};
+    use network::acl::{Acl, AclEntry, AclRule, AclVerdict};
+
+    fn peer(identity: &Identity) -> PeerInfo {
+        PeerInfo {
+            peer_id: identity.peer_id(),
+            public_key: identity.public_key().to_vec(),
+            version: String::new(),
+            listen_ports: ListenPorts::default(),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let contractor = Identity::generate().unwrap();
+        let other = Identity::generate().unwrap();
+        let lan: SocketAddr = "10.0.0.7:40102".parse().unwrap();
+        let wan: SocketAddr = "8.8.8.8:40102".parse().unwrap();
+
+        let mut acl = Acl::new();
+        acl.set(
+            7,
+            Some(AclEntry {
+                allowed: vec![
+                    AclRule::Peer(contractor.peer_id()),
+                    AclRule::Range("10.0.0.0/8".parse().unwrap()),
+                ],
+                disconnect: false,
+            }),
+        );
+        acl.set(
+            8,
+            Some(AclEntry {
+                allowed: vec![AclRule::PublicKey(contractor.public_key().to_vec())],
+                disconnect: true,
+            }),
+        );
+
+        assert_eq!(AclVerdict::Accept, acl.check(1, &wan, None));
+        assert_eq!(AclVerdict::Accept, acl.check(7, &wan, Some(&peer(&contractor))));
+        assert_eq!(AclVerdict::Accept, acl.check(7, &lan, Some(&peer(&other))));
+        assert_eq!(AclVerdict::Drop, acl.check(7, &wan, Some(&peer(&other))));
+        assert_eq!(AclVerdict::Drop, acl.check(7, &wan, None));
+        // addresses alone are not trusted
+        assert_eq!(AclVerdict::Drop, acl.check(7, &lan, None));
+        assert_eq!(AclVerdict::Accept, acl.check(8, &wan, Some(&peer(&contractor))));
+        assert_eq!(AclVerdict::Disconnect, acl.check(8, &lan, Some(&peer(&other))));
+
+        assert_eq!(Some(&3), acl.violations().get(&7));
+        assert_eq!(Some(&1), acl.violations().get(&8));
+
+        acl.set(7, None);
+        assert_eq!(AclVerdict::Accept, acl.check(7, &wan, None));
+    }
+}
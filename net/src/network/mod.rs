@@ -1,15 +1,23 @@
+use std::net::SocketAddr;
 use std::sync::mpsc;
 
 use actix::msgs;
 use actix::prelude::*;
 use futures::Future;
 
+pub mod acl;
 pub mod session;
 
-use codec::message::Message;
+use codec::message::{Message, Signature};
+use error::Error;
 use event::*;
+use identity::{self, SignatureStatus};
+use network::acl::{Acl, AclVerdict};
 use network::session::*;
+use protocol::{ProtocolMessage, ProtocolRegistry};
 use transport::*;
+use transport::admission::SharedAdmission;
+use transport::firewall::SharedFirewall;
 use transport::message::*;
 use transport::tcp::TcpActorAddr;
 use transport::udp::UdpActorAddr;
@@ -20,6 +28,7 @@ pub trait Network:
     Actor
     + Handler<Listening<Self>>
     + Handler<ReceivedMessage>
+    + Handler<TransportError>
     + Handler<SendMessage>
     + Handler<Stop>
     + Handler<Stopped<Self>>
@@ -27,6 +36,9 @@ pub trait Network:
     + Handler<Connected<Self>>
     + Handler<Disconnect>
     + Handler<Disconnected>
+    + Handler<Refused>
+    + Handler<Migrated>
+    + Handler<PeerDiscovered>
 where
     Self::Context: AsyncContext<Self>,
 {
@@ -36,15 +48,33 @@ pub struct NetworkCore {
     sessions: Sessions<NetworkCore>,
     tcp: Option<TcpActorAddr<NetworkCore>>,
     udp: Option<UdpActorAddr<NetworkCore>>,
+    /// Firewall of the transports, once one is listening
+    firewall: Option<SharedFirewall>,
+    /// Admission counters of the transports, once one is listening
+    admission: Option<SharedAdmission>,
+    registry: ProtocolRegistry,
+    acl: Acl,
     tx: mpsc::SyncSender<Event>,
 }
 
 impl NetworkCore {
     pub fn run(tx: mpsc::SyncSender<Event>) -> (Addr<Unsync, Self>, Addr<Syn, Self>) {
+        NetworkCore::run_with_registry(tx, ProtocolRegistry::new())
+    }
+
+    /// Messages of protocols in the registry are emitted as `Event::Typed`
+    pub fn run_with_registry(
+        tx: mpsc::SyncSender<Event>,
+        registry: ProtocolRegistry,
+    ) -> (Addr<Unsync, Self>, Addr<Syn, Self>) {
         NetworkCore::create(|_| NetworkCore {
             sessions: Sessions::new(),
             tcp: None,
             udp: None,
+            firewall: None,
+            admission: None,
+            registry,
+            acl: Acl::new(),
             tx,
         })
     }
@@ -73,6 +103,36 @@ impl NetworkCore {
         }
     }
 
+    /// TCP session with the node of a UDP session, when a message is
+    /// larger than the session's path MTU
+    fn tcp_fallback(&self, address: &SocketAddr, message: &Message) -> Option<SocketAddr> {
+        let udp = TransportProtocol::Udp;
+        let path_mtu = self.sessions.stats(&udp, address)?.congestion?.path_mtu;
+        if payload_size(message) <= path_mtu {
+            return None;
+        }
+
+        let peer_id = self.sessions.peer(&udp, address)?.peer_id;
+        self.sessions
+            .find(&peer_id)
+            .into_iter()
+            .find(|&(transport, _)| transport == TransportProtocol::Tcp)
+            .map(|(_, address)| address)
+    }
+
+    fn close(&self, transport: &TransportProtocol, address: &SocketAddr, reason: DisconnectReason) {
+        let message = Close {
+            address: address.clone(),
+            reason,
+        };
+
+        match self.sessions.get(transport, address) {
+            Some(TransportSession::Tcp(s)) => Self::session_send(s, message),
+            Some(TransportSession::Udp(s)) => Self::session_send(s, message),
+            None => {}
+        }
+    }
+
     fn session_send<M, D>(session: &Addr<Unsync, D>, message: M)
         where
             M: actix::Message + 'static,
@@ -90,6 +150,15 @@ impl NetworkCore {
 
 impl Network for NetworkCore {}
 
+/// Size of a message's payload, approximating its encoded size
+fn payload_size(message: &Message) -> usize {
+    match *message {
+        Message::Encapsulated(ref e) => e.message.len(),
+        Message::Signed(ref s) => s.encapsulated.message.len() + Signature::ENCODED_SZ,
+        _ => 0,
+    }
+}
+
 impl Actor for NetworkCore {
     type Context = Context<Self>;
 
@@ -139,13 +208,47 @@ impl Handler<ReceivedMessage> for NetworkCore {
     type Result = NoResult;
 
     fn handle(&mut self, m: ReceivedMessage, _ctx: &mut Self::Context) {
-        match m.message {
-            Message::Encapsulated(e) => {
-                let event = Event::Message(m.transport, m.address, e);
-                self.emit(event);
+        let (e, status, signature) = match m.message {
+            Message::Encapsulated(e) => (e, SignatureStatus::Unsigned, None),
+            Message::Signed(s) => {
+                let status = identity::verify_message(&s);
+                (s.encapsulated, status, Some(s.signature))
             }
-            _ => {}
+            _ => return,
+        };
+
+        let verdict = {
+            let peer = self.sessions.peer(&m.transport, &m.address);
+            self.acl.check(e.protocol_id, &m.address, peer)
         };
+
+        match verdict {
+            AclVerdict::Accept => {}
+            AclVerdict::Drop => return,
+            AclVerdict::Disconnect => {
+                return self.close(&m.transport, &m.address, DisconnectReason::AccessDenied)
+            }
+        }
+
+        let event = match self.registry.decode(&e) {
+            None => Event::Message(m.transport, m.address, e, status, signature),
+            Some(Ok(typed)) => Event::Typed(m.transport, m.address, typed, status, signature),
+            Some(Err(err)) => {
+                let origin = Some((m.transport, m.address));
+                Event::Error(origin, Error::from(err))
+            }
+        };
+        self.emit(event);
+    }
+}
+
+// Event
+impl Handler<TransportError> for NetworkCore {
+    type Result = NoResult;
+
+    fn handle(&mut self, m: TransportError, _ctx: &mut Self::Context) {
+        let event = Event::Error(Some((m.transport, m.address)), m.error);
+        self.emit(event);
     }
 }
 
@@ -155,10 +258,8 @@ impl Handler<Connect> for NetworkCore {
 
     fn handle(&mut self, m: Connect, _ctx: &mut Self::Context) -> Self::Result {
         match m.transport {
-            TransportProtocol::Tcp => {
-                self.transport_send(&self.tcp, m)?;
-                Ok(())
-            }
+            TransportProtocol::Tcp => self.transport_send(&self.tcp, m),
+            TransportProtocol::Udp => self.transport_send(&self.udp, m),
             _ => Err(MailboxError::Closed),
         }
     }
@@ -169,8 +270,14 @@ impl Handler<Connected<NetworkCore>> for NetworkCore {
     type Result = NoResult;
 
     fn handle(&mut self, m: Connected<NetworkCore>, _ctx: &mut Self::Context) {
-        let event = Event::Connected(m.transport.clone(), m.address.clone(), m.initiator);
-        self.sessions.add(m.transport, m.address, m.session);
+        let event = Event::Connected(
+            m.transport.clone(),
+            m.address.clone(),
+            m.initiator,
+            m.fingerprint,
+            m.peer.clone(),
+        );
+        self.sessions.add(m.transport, m.address, m.session, m.codec, m.peer, m.congestion);
         self.emit(event);
     }
 }
@@ -180,36 +287,173 @@ impl Handler<Disconnect> for NetworkCore {
     type Result = EmptyResult;
 
     fn handle(&mut self, m: Disconnect, _ctx: &mut Self::Context) -> Self::Result {
-        let message = Stop(m.transport);
         match self.sessions.get(&m.transport, &m.address) {
             Some(session) => match session {
                 TransportSession::Tcp(s) => {
+                    Self::session_send(&s, Stop(m.transport));
+                    Ok(())
+                }
+                TransportSession::Udp(s) => {
+                    let message = Close {
+                        address: m.address,
+                        reason: DisconnectReason::Requested,
+                    };
                     Self::session_send(&s, message);
                     Ok(())
                 }
-                TransportSession::Udp(_) => Err(MailboxError::Closed),
             },
             None => Err(MailboxError::Closed),
         }
     }
 }
 
+// Request
+impl Handler<Ban> for NetworkCore {
+    type Result = EmptyResult;
+
+    fn handle(&mut self, m: Ban, _ctx: &mut Self::Context) -> Self::Result {
+        let firewall = match self.firewall {
+            Some(ref firewall) => firewall,
+            None => return Err(MailboxError::Closed),
+        };
+        // the ban is in effect even if it could not be persisted
+        if let Err(e) = firewall.ban(&m.address, m.duration) {
+            eprintln!("Core: cannot save the ban list: {}", e);
+        }
+
+        for (transport, address) in self.sessions.find_ip(&m.address) {
+            self.close(&transport, &address, DisconnectReason::Banned);
+        }
+        Ok(())
+    }
+}
+
+// Request
+impl Handler<Unban> for NetworkCore {
+    type Result = EmptyResult;
+
+    fn handle(&mut self, m: Unban, _ctx: &mut Self::Context) -> Self::Result {
+        let firewall = match self.firewall {
+            Some(ref firewall) => firewall,
+            None => return Err(MailboxError::Closed),
+        };
+        if let Err(e) = firewall.unban(&m.address) {
+            eprintln!("Core: cannot save the ban list: {}", e);
+        }
+        Ok(())
+    }
+}
+
+// Request
+impl Handler<SetFirewall> for NetworkCore {
+    type Result = EmptyResult;
+
+    fn handle(&mut self, m: SetFirewall, _ctx: &mut Self::Context) -> Self::Result {
+        let firewall = match self.firewall {
+            Some(ref firewall) => firewall.clone(),
+            None => return Err(MailboxError::Closed),
+        };
+        firewall.set_rules(m.allow, m.deny);
+
+        for (transport, address) in self.sessions.all() {
+            if !firewall.allows(&address.ip()) {
+                self.close(&transport, &address, DisconnectReason::Banned);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Request
+impl Handler<SetAcl> for NetworkCore {
+    type Result = EmptyResult;
+
+    fn handle(&mut self, m: SetAcl, _ctx: &mut Self::Context) -> Self::Result {
+        self.acl.set(m.protocol_id, m.entry);
+        Ok(())
+    }
+}
+
 // Event
 impl Handler<Disconnected> for NetworkCore {
     type Result = NoResult;
 
     fn handle(&mut self, m: Disconnected, _ctx: &mut Self::Context) {
-        let event = Event::Disconnected(m.transport.clone(), m.address.clone());
+        let event = Event::Disconnected(m.transport.clone(), m.address.clone(), m.reason);
         self.sessions.remove(&m.transport, &m.address);
         self.emit(event);
     }
 }
 
+// Event
+impl Handler<Refused> for NetworkCore {
+    type Result = NoResult;
+
+    fn handle(&mut self, m: Refused, _ctx: &mut Self::Context) {
+        self.emit(Event::Refused(m.transport, m.address, m.reason));
+    }
+}
+
+// Event
+impl Handler<Migrated> for NetworkCore {
+    type Result = NoResult;
+
+    fn handle(&mut self, m: Migrated, _ctx: &mut Self::Context) {
+        if self.sessions.migrate(&m.transport, &m.from, m.to) {
+            self.emit(Event::Migrated(m.transport, m.from, m.to));
+        }
+    }
+}
+
+// Event
+impl Handler<PeerDiscovered> for NetworkCore {
+    type Result = NoResult;
+
+    fn handle(&mut self, m: PeerDiscovered, _ctx: &mut Self::Context) {
+        self.emit(Event::PeerDiscovered(m.address, m.peer));
+    }
+}
+
+// Query
+impl Handler<GetSessionStats> for NetworkCore {
+    type Result = StatsResult;
+
+    fn handle(&mut self, m: GetSessionStats, _ctx: &mut Self::Context) -> Self::Result {
+        match self.sessions.stats(&m.transport, &m.address) {
+            Some(stats) => Ok(stats),
+            None => Err(MailboxError::Closed),
+        }
+    }
+}
+
+// Query
+impl Handler<GetNodeStats> for NetworkCore {
+    type Result = NodeStatsResult;
+
+    fn handle(&mut self, _: GetNodeStats, _ctx: &mut Self::Context) -> Self::Result {
+        let admission = match self.admission {
+            Some(ref admission) => admission.lock(),
+            None => return Err(MailboxError::Closed),
+        };
+
+        Ok(NodeStats {
+            sessions: admission.sessions(),
+            handshakes: admission.handshakes(),
+            puzzle_difficulty: admission.difficulty(),
+            acl_violations: self.acl.violations().values().sum(),
+        })
+    }
+}
+
 // Event
 impl Handler<Listening<NetworkCore>> for NetworkCore {
     type Result = NoResult;
 
     fn handle(&mut self, m: Listening<NetworkCore>, _ctx: &mut Self::Context) {
+        // both transports share the firewall and admission counters of
+        // their configuration
+        self.firewall = Some(m.firewall);
+        self.admission = Some(m.admission);
         let transport = match m.actor {
             Transport::Tcp(t) => {
                 self.tcp = Some(t);
@@ -234,9 +478,26 @@ impl Handler<SendMessage> for NetworkCore {
         let message = SessionSendMessage {
             address: m.address,
             message: m.message,
+            delivery: m.delivery,
         };
 
         if let TransportProtocol::Udp = m.transport {
+            // messages a datagram cannot carry whole go over TCP, if the
+            // node has a TCP session too
+            if let Some(address) = self.tcp_fallback(&m.address, &message.message) {
+                let message = SessionSendMessage { address, ..message };
+                match self.sessions.get(&TransportProtocol::Tcp, &address) {
+                    Some(TransportSession::Tcp(s)) => Self::session_send(s, message),
+                    _ => return Err(MailboxError::Closed),
+                }
+                return Ok(());
+            }
+
+            // only sessions acknowledge messages
+            let session = self.sessions.get(&m.transport, &m.address);
+            if m.delivery != Delivery::Unreliable && session.is_none() {
+                return Err(MailboxError::Closed);
+            }
             return self.transport_send(&self.udp, message);
         }
 
@@ -254,4 +515,29 @@ impl Handler<SendMessage> for NetworkCore {
     }
 }
 
+// Forward
+impl<M> Handler<SendTyped<M>> for NetworkCore
+where
+    M: ProtocolMessage,
+{
+    type Result = EmptyResult;
 
+    fn handle(&mut self, m: SendTyped<M>, ctx: &mut Self::Context) -> Self::Result {
+        let encapsulated = match m.message.encapsulate() {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Core: cannot encode '{}' message: {}", M::NAME, e);
+                return Err(MailboxError::Closed);
+            }
+        };
+
+        let message = SendMessage {
+            transport: m.transport,
+            address: m.address,
+            message: Message::Encapsulated(encapsulated),
+            delivery: m.delivery,
+        };
+
+        <Self as Handler<SendMessage>>::handle(self, message, ctx)
+    }
+}
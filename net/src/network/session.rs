@@ -2,10 +2,49 @@ use actix;
 use std::collections::HashMap;
 use std::net;
 
+use codec::compression::{Compression, CompressionStats};
+use codec::SharedCodecState;
+use identity::{PeerId, PeerInfo};
 use network::Network;
+use transport::congestion::{CongestionStats, SharedCongestionStats};
+use transport::firewall::canonical;
 use transport::{TransportProtocol, TransportSession};
 
-pub struct Sessions<N>(HashMap<(TransportProtocol, net::SocketAddr), TransportSession<N>>)
+/// Session statistics snapshot
+#[derive(Clone, Debug)]
+pub struct SessionStats {
+    pub peer_id: PeerId,
+    pub compression: Option<Compression>,
+    pub compression_stats: CompressionStats,
+    /// Congestion control state of a UDP session
+    pub congestion: Option<CongestionStats>,
+}
+
+/// Node statistics snapshot
+#[derive(Clone, Debug, Default)]
+pub struct NodeStats {
+    /// TCP and UDP sessions, including those in the handshake phase
+    pub sessions: usize,
+    /// TCP and UDP sessions in the handshake phase
+    pub handshakes: usize,
+    /// Proof-of-work difficulty currently required from connecting peers
+    pub puzzle_difficulty: u8,
+    /// Messages dropped by the access control table
+    pub acl_violations: u64,
+}
+
+struct SessionEntry<N>
+where
+    N: Network + 'static,
+    N::Context: actix::AsyncContext<N>,
+{
+    session: TransportSession<N>,
+    codec: SharedCodecState,
+    peer: PeerInfo,
+    congestion: Option<SharedCongestionStats>,
+}
+
+pub struct Sessions<N>(HashMap<(TransportProtocol, net::SocketAddr), SessionEntry<N>>)
 where
     N: Network + 'static,
     N::Context: actix::AsyncContext<N>;
@@ -25,7 +64,30 @@ where
         address: &net::SocketAddr,
     ) -> Option<&TransportSession<N>> {
         let key = (*protocol, *address);
-        self.0.get(&key)
+        self.0.get(&key).map(|e| &e.session)
+    }
+
+    /// Authenticated node of a session
+    pub fn peer(&self, protocol: &TransportProtocol, address: &net::SocketAddr) -> Option<&PeerInfo> {
+        let key = (*protocol, *address);
+        self.0.get(&key).map(|e| &e.peer)
+    }
+
+    pub fn stats(
+        &self,
+        protocol: &TransportProtocol,
+        address: &net::SocketAddr,
+    ) -> Option<SessionStats> {
+        let key = (*protocol, *address);
+        self.0.get(&key).map(|e| {
+            let codec = e.codec.borrow();
+            SessionStats {
+                peer_id: e.peer.peer_id,
+                compression: codec.compression,
+                compression_stats: codec.compression_stats,
+                congestion: e.congestion.as_ref().map(|c| *c.borrow()),
+            }
+        })
     }
 
     pub fn add(
@@ -33,8 +95,56 @@ where
         transport: TransportProtocol,
         address: net::SocketAddr,
         session: TransportSession<N>,
+        codec: SharedCodecState,
+        peer: PeerInfo,
+        congestion: Option<SharedCongestionStats>,
     ) -> () {
-        self.0.insert((transport, address), session);
+        let entry = SessionEntry {
+            session,
+            codec,
+            peer,
+            congestion,
+        };
+        self.0.insert((transport, address), entry);
+    }
+
+    /// Finds the sessions of an authenticated node
+    pub fn find(&self, peer_id: &PeerId) -> Vec<(TransportProtocol, net::SocketAddr)> {
+        self.0
+            .iter()
+            .filter(|&(_, e)| e.peer.peer_id == *peer_id)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Every session, by transport and remote address
+    pub fn all(&self) -> Vec<(TransportProtocol, net::SocketAddr)> {
+        self.0.keys().cloned().collect()
+    }
+
+    /// Finds the sessions with a remote IP address
+    pub fn find_ip(&self, ip: &net::IpAddr) -> Vec<(TransportProtocol, net::SocketAddr)> {
+        self.0
+            .keys()
+            .filter(|&&(_, address)| canonical(address.ip()) == canonical(*ip))
+            .cloned()
+            .collect()
+    }
+
+    /// Moves a session to a new remote address; returns false if unknown
+    pub fn migrate(
+        &mut self,
+        protocol: &TransportProtocol,
+        from: &net::SocketAddr,
+        to: net::SocketAddr,
+    ) -> bool {
+        match self.0.remove(&(*protocol, *from)) {
+            Some(entry) => {
+                self.0.insert((*protocol, to), entry);
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn remove(
@@ -43,6 +153,6 @@ where
         address: &net::SocketAddr,
     ) -> Option<TransportSession<N>> {
         let key = (*protocol, *address);
-        self.0.remove(&key)
+        self.0.remove(&key).map(|e| e.session)
     }
 }
@@ -0,0 +1,194 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+use bincode::internal::{deserialize, serialize};
+use bincode::Infinite;
+use byteorder::BigEndian as Order;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use codec::error::CodecError;
+use codec::message::Encapsulated;
+use error::{Error, ErrorKind, ErrorSeverity};
+
+/// Message type bound to a protocol id. Usually implemented with
+/// `#[derive(ProtocolMessage)]` from the `net-derive` crate.
+pub trait ProtocolMessage: Serialize + DeserializeOwned + Send + 'static {
+    const PROTOCOL_ID: u16;
+    const NAME: &'static str;
+
+    fn encapsulate(&self) -> Result<Encapsulated, CodecError> {
+        let message = serialize::<Self, Infinite, Order>(self, Infinite)?;
+        Ok(Encapsulated {
+            protocol_id: Self::PROTOCOL_ID,
+            message: Bytes::from(message),
+        })
+    }
+
+    fn decapsulate(encapsulated: &Encapsulated) -> Result<Self, CodecError> {
+        if encapsulated.protocol_id != Self::PROTOCOL_ID {
+            return Err(CodecError::new(&format!(
+                "protocol id {} does not match '{}' ({})",
+                encapsulated.protocol_id,
+                Self::NAME,
+                Self::PROTOCOL_ID
+            )));
+        }
+        Ok(deserialize::<Self, Order>(&encapsulated.message)?)
+    }
+}
+
+/// Incoming message decoded into its registered type
+pub struct TypedMessage {
+    pub protocol_id: u16,
+    pub name: &'static str,
+    pub value: Box<Any + Send>,
+    /// Message as received
+    pub raw: Encapsulated,
+}
+
+impl TypedMessage {
+    pub fn is<M: ProtocolMessage>(&self) -> bool {
+        self.value.is::<M>()
+    }
+
+    pub fn downcast<M: ProtocolMessage>(self) -> Result<M, Self> {
+        if !self.is::<M>() {
+            return Err(self);
+        }
+        match self.value.downcast::<M>() {
+            Ok(value) => Ok(*value),
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Debug for TypedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TypedMessage({}, {})", self.protocol_id, self.name)
+    }
+}
+
+type DecodeFn = fn(&Encapsulated) -> Result<Box<Any + Send>, CodecError>;
+
+struct Registration {
+    name: &'static str,
+    type_id: TypeId,
+    decode: DecodeFn,
+}
+
+fn decode<M: ProtocolMessage>(encapsulated: &Encapsulated) -> Result<Box<Any + Send>, CodecError> {
+    Ok(Box::new(M::decapsulate(encapsulated)?))
+}
+
+/// Protocol id to message type mapping
+#[derive(Default)]
+pub struct ProtocolRegistry {
+    protocols: HashMap<u16, Registration>,
+}
+
+impl ProtocolRegistry {
+    pub fn new() -> Self {
+        ProtocolRegistry::default()
+    }
+
+    /// Registers a message type. Fails if a different type already claims
+    /// its protocol id.
+    pub fn register<M: ProtocolMessage>(&mut self) -> Result<&mut Self, Error> {
+        let existing = self
+            .protocols
+            .get(&M::PROTOCOL_ID)
+            .map(|r| (r.type_id, r.name));
+
+        match existing {
+            Some((type_id, _)) if type_id == TypeId::of::<M>() => return Ok(self),
+            Some((_, name)) => {
+                let message = format!(
+                    "protocol id {} of '{}' collides with '{}'",
+                    M::PROTOCOL_ID,
+                    M::NAME,
+                    name
+                );
+                return Err(Error::new(ErrorKind::Other, ErrorSeverity::High, &message));
+            }
+            None => {}
+        }
+
+        let registration = Registration {
+            name: M::NAME,
+            type_id: TypeId::of::<M>(),
+            decode: decode::<M>,
+        };
+
+        self.protocols.insert(M::PROTOCOL_ID, registration);
+        Ok(self)
+    }
+
+    pub fn contains(&self, protocol_id: u16) -> bool {
+        self.protocols.contains_key(&protocol_id)
+    }
+
+    /// Decodes a message of a registered protocol. Returns `None` for
+    /// unregistered protocol ids.
+    pub fn decode(&self, encapsulated: &Encapsulated) -> Option<Result<TypedMessage, CodecError>> {
+        let registration = self.protocols.get(&encapsulated.protocol_id)?;
+        let result = (registration.decode)(encapsulated).map(|value| TypedMessage {
+            protocol_id: encapsulated.protocol_id,
+            name: registration.name,
+            value,
+            raw: encapsulated.clone(),
+        });
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use codec::message::Encapsulated;
+    use protocol::{ProtocolMessage, ProtocolRegistry};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Ping(u64);
+
+    impl ProtocolMessage for Ping {
+        const PROTOCOL_ID: u16 = 1;
+        const NAME: &'static str = "Ping";
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Pong(u64);
+
+    impl ProtocolMessage for Pong {
+        const PROTOCOL_ID: u16 = 2;
+        const NAME: &'static str = "Pong";
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut registry = ProtocolRegistry::new();
+        registry.register::<Ping>().unwrap().register::<Pong>().unwrap();
+
+        let encapsulated = Pong(7).encapsulate().unwrap();
+        let typed = registry.decode(&encapsulated).unwrap().unwrap();
+        assert_eq!("Pong", typed.name);
+        assert!(!typed.is::<Ping>());
+        assert_eq!(Pong(7), typed.downcast::<Pong>().unwrap());
+
+        let unknown = Encapsulated {
+            protocol_id: 3,
+            message: Bytes::new(),
+        };
+        assert!(registry.decode(&unknown).is_none());
+
+        let invalid = Encapsulated {
+            protocol_id: 1,
+            message: Bytes::new(),
+        };
+        assert!(registry.decode(&invalid).unwrap().is_err());
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use transport::firewall::canonical;
+use transport::puzzle::{PuzzleConfig, MAX_DIFFICULTY};
+use transport::RefusalReason;
+
+/// Connection admission limits, over the sessions of both transports
+#[derive(Clone, Debug)]
+pub struct AdmissionConfig {
+    /// Maximum number of sessions, including those still in the handshake
+    pub max_sessions: usize,
+    /// Maximum number of sessions with a single IP address
+    pub max_sessions_per_ip: usize,
+    /// Maximum number of connections still in the TLS, Noise or Hello
+    /// handshake
+    pub max_handshakes: usize,
+    /// Maximum number of connections solving the admission puzzle; these
+    /// hold no session slot until solved
+    pub max_challenged: usize,
+    /// Time allowed for a partially received frame to complete; checked
+    /// at most every 100 ms
+    pub read_timeout: Duration,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        AdmissionConfig {
+            max_sessions: 1024,
+            max_sessions_per_ip: 32,
+            max_handshakes: 256,
+            max_challenged: 1024,
+            read_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Connection counters of a node
+#[derive(Debug)]
+pub struct Admission {
+    config: AdmissionConfig,
+    puzzle: Option<PuzzleConfig>,
+    sessions: usize,
+    handshakes: usize,
+    challenged: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    challenged_per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig, puzzle: Option<PuzzleConfig>) -> Self {
+        Admission {
+            config,
+            puzzle,
+            sessions: 0,
+            handshakes: 0,
+            challenged: 0,
+            per_ip: HashMap::new(),
+            challenged_per_ip: HashMap::new(),
+        }
+    }
+
+    /// Number of sessions and challenged connections with `address`
+    fn connections_from(&self, address: &IpAddr) -> usize {
+        let count = |map: &HashMap<IpAddr, usize>| map.get(address).cloned().unwrap_or(0);
+        count(&self.per_ip) + count(&self.challenged_per_ip)
+    }
+
+    /// Why a connection with `address` would exceed the limits
+    fn refusal(&self, address: &IpAddr) -> Option<RefusalReason> {
+        let from_address = self.per_ip.get(address).cloned().unwrap_or(0);
+
+        if self.sessions >= self.config.max_sessions {
+            return Some(RefusalReason::TooManySessions);
+        }
+        if from_address >= self.config.max_sessions_per_ip {
+            return Some(RefusalReason::TooManyFromAddress);
+        }
+        if self.handshakes >= self.config.max_handshakes {
+            return Some(RefusalReason::TooManyHandshakes);
+        }
+        None
+    }
+
+    /// Utilization of the session or handshake limit, whichever is higher
+    pub fn load(&self) -> f64 {
+        let ratio = |count: usize, max: usize| match max {
+            0 => 1.0,
+            max => count as f64 / max as f64,
+        };
+
+        ratio(self.sessions, self.config.max_sessions)
+            .max(ratio(self.handshakes, self.config.max_handshakes))
+    }
+
+    /// Puzzle difficulty required from peers connecting now
+    pub fn difficulty(&self) -> u8 {
+        match self.puzzle {
+            Some(ref puzzle) => puzzle.difficulty(self.load()),
+            None => 0,
+        }
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.sessions
+    }
+
+    pub fn handshakes(&self) -> usize {
+        self.handshakes
+    }
+
+    pub fn challenged(&self) -> usize {
+        self.challenged
+    }
+}
+
+/// Admission counters shared by the transports and their sessions, so that
+/// the limits apply to the node as a whole
+#[derive(Clone, Debug)]
+pub struct SharedAdmission(Arc<Mutex<Admission>>);
+
+impl Default for SharedAdmission {
+    fn default() -> Self {
+        SharedAdmission::new(AdmissionConfig::default(), None)
+    }
+}
+
+impl SharedAdmission {
+    /// Under load, connecting peers are challenged with `puzzle`
+    pub fn new(config: AdmissionConfig, puzzle: Option<PuzzleConfig>) -> Self {
+        SharedAdmission(Arc::new(Mutex::new(Admission::new(config, puzzle))))
+    }
+
+    pub fn lock(&self) -> MutexGuard<Admission> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn config(&self) -> AdmissionConfig {
+        self.lock().config.clone()
+    }
+
+    /// Reserves a slot for a new connection with `address`. The slot is
+    /// released when dropped.
+    pub fn admit(&self, address: IpAddr) -> Result<AdmissionSlot, RefusalReason> {
+        let address = canonical(address);
+        {
+            let mut state = self.lock();
+            if let Some(reason) = state.refusal(&address) {
+                return Err(reason);
+            }
+
+            state.sessions += 1;
+            state.handshakes += 1;
+            *state.per_ip.entry(address).or_insert(0) += 1;
+        }
+
+        Ok(AdmissionSlot {
+            admission: self.clone(),
+            address,
+            handshake: true,
+        })
+    }
+
+    /// Admits an incoming connection from `address`, or, while the node is
+    /// under load or over its limits, challenges it with the puzzle first.
+    /// A challenged connection reserves its slot once it solves the
+    /// puzzle, so that connections which never do cannot exhaust the
+    /// limits. Challenged connections count towards the per-address limit,
+    /// over which connections are refused outright.
+    pub fn admit_incoming(&self, address: IpAddr) -> Result<Admittance, RefusalReason> {
+        let address = canonical(address);
+        let difficulty = {
+            let mut state = self.lock();
+            if state.connections_from(&address) >= state.config.max_sessions_per_ip {
+                return Err(RefusalReason::TooManyFromAddress);
+            }
+
+            let refusal = state.refusal(&address);
+            let difficulty = match (&state.puzzle, refusal) {
+                (&None, _) => 0,
+                (&Some(_), None) => state.difficulty(),
+                (&Some(ref puzzle), Some(_)) => puzzle.difficulty(1.0).max(1),
+            };
+
+            if difficulty > 0 {
+                if state.challenged >= state.config.max_challenged {
+                    return Err(refusal.unwrap_or(RefusalReason::TooManyHandshakes));
+                }
+                state.challenged += 1;
+                *state.challenged_per_ip.entry(address).or_insert(0) += 1;
+            }
+            difficulty
+        };
+
+        match difficulty {
+            0 => self.admit(address).map(Admittance::Admitted),
+            difficulty => Ok(Admittance::Challenged(Challenge {
+                admission: self.clone(),
+                address,
+                difficulty: difficulty.min(MAX_DIFFICULTY),
+            })),
+        }
+    }
+}
+
+/// Outcome of admitting an incoming connection
+#[derive(Debug)]
+pub enum Admittance {
+    Admitted(AdmissionSlot),
+    /// Admitted once the connecting peer solves the puzzle
+    Challenged(Challenge),
+}
+
+/// Connection solving the admission puzzle, counted separately from the
+/// sessions until then
+#[derive(Debug)]
+pub struct Challenge {
+    admission: SharedAdmission,
+    address: IpAddr,
+    difficulty: u8,
+}
+
+impl Challenge {
+    /// Puzzle difficulty required from the peer, decided on admission
+    pub fn difficulty(&self) -> u8 {
+        self.difficulty
+    }
+
+    /// Reserves a slot for the connection, after the peer solved the
+    /// puzzle
+    pub fn solved(self) -> Result<AdmissionSlot, RefusalReason> {
+        self.admission.admit(self.address)
+    }
+}
+
+impl Drop for Challenge {
+    fn drop(&mut self) {
+        let mut state = self.admission.lock();
+        state.challenged -= 1;
+        release(&mut state.challenged_per_ip, &self.address);
+    }
+}
+
+/// Connection reserved in the admission counters
+#[derive(Debug)]
+pub struct AdmissionSlot {
+    admission: SharedAdmission,
+    address: IpAddr,
+    handshake: bool,
+}
+
+impl AdmissionSlot {
+    /// Moves the connection out of the handshake phase
+    pub fn established(&mut self) {
+        if self.handshake {
+            self.handshake = false;
+            self.admission.lock().handshakes -= 1;
+        }
+    }
+}
+
+impl Drop for AdmissionSlot {
+    fn drop(&mut self) {
+        self.established();
+
+        let mut state = self.admission.lock();
+        state.sessions -= 1;
+        release(&mut state.per_ip, &self.address);
+    }
+}
+
+/// Decrements the count of `address`, forgetting it at zero
+fn release(per_ip: &mut HashMap<IpAddr, usize>, address: &IpAddr) {
+    let remaining = match per_ip.get_mut(address) {
+        Some(count) => {
+            *count -= 1;
+            *count
+        }
+        None => return,
+    };
+    if remaining == 0 {
+        per_ip.remove(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use transport::admission::{AdmissionConfig, Admittance, SharedAdmission};
+    use transport::puzzle::PuzzleConfig;
+    use transport::RefusalReason;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_limits() {
+        let admission = SharedAdmission::new(
+            AdmissionConfig {
+                max_sessions: 3,
+                max_sessions_per_ip: 2,
+                max_handshakes: 2,
+                ..AdmissionConfig::default()
+            },
+            None,
+        );
+
+        let mut first = admission.admit(ip("10.0.0.1")).unwrap();
+        let second = admission.admit(ip("::ffff:10.0.0.1")).unwrap();
+        assert_eq!(
+            RefusalReason::TooManyHandshakes,
+            admission.admit(ip("10.0.0.2")).unwrap_err()
+        );
+
+        first.established();
+        assert_eq!(
+            RefusalReason::TooManyFromAddress,
+            admission.admit(ip("10.0.0.1")).unwrap_err()
+        );
+
+        // the counters are shared by every clone
+        let third = admission.clone().admit(ip("10.0.0.2")).unwrap();
+        assert_eq!(
+            RefusalReason::TooManySessions,
+            admission.admit(ip("10.0.0.3")).unwrap_err()
+        );
+        assert_eq!(3, admission.lock().sessions());
+        assert_eq!(2, admission.lock().handshakes());
+
+        drop(first);
+        drop(second);
+        drop(third);
+        assert_eq!(0, admission.lock().sessions());
+        assert_eq!(0, admission.lock().handshakes());
+        assert!(admission.admit(ip("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn test_difficulty() {
+        let config = AdmissionConfig {
+            max_sessions: 4,
+            ..AdmissionConfig::default()
+        };
+        let admission = SharedAdmission::new(config, Some(PuzzleConfig::default()));
+
+        let first = admission.admit(ip("10.0.0.1")).unwrap();
+        let _second = admission.admit(ip("10.0.0.2")).unwrap();
+        assert_eq!(8, admission.lock().difficulty());
+        let _third = admission.admit(ip("10.0.0.3")).unwrap();
+        assert_eq!(14, admission.lock().difficulty());
+        drop(first);
+        assert_eq!(8, admission.lock().difficulty());
+    }
+
+    #[test]
+    fn test_challenge() {
+        let config = AdmissionConfig {
+            max_sessions: 2,
+            max_challenged: 2,
+            ..AdmissionConfig::default()
+        };
+        let admission = SharedAdmission::new(config, Some(PuzzleConfig::default()));
+
+        let admitted = |admittance: Admittance| match admittance {
+            Admittance::Admitted(slot) => slot,
+            Admittance::Challenged(_) => panic!("challenged"),
+        };
+        let challenged = |admittance: Admittance| match admittance {
+            Admittance::Challenged(challenge) => challenge,
+            Admittance::Admitted(_) => panic!("admitted"),
+        };
+
+        // below the load threshold
+        let _first = admitted(admission.admit_incoming(ip("10.0.0.1")).unwrap());
+
+        // at the threshold, connections hold no slot until solved
+        let second = challenged(admission.admit_incoming(ip("10.0.0.2")).unwrap());
+        assert_eq!(8, second.difficulty());
+        assert_eq!(1, admission.lock().sessions());
+        assert_eq!(1, admission.lock().challenged());
+
+        let _second = second.solved().unwrap();
+        assert_eq!(2, admission.lock().sessions());
+        assert_eq!(0, admission.lock().challenged());
+
+        // over the session limit, the hardest puzzle is issued instead of refusing
+        let third = challenged(admission.admit_incoming(ip("10.0.0.3")).unwrap());
+        let fourth = challenged(admission.admit_incoming(ip("10.0.0.4")).unwrap());
+        assert_eq!(20, third.difficulty());
+        assert_eq!(
+            RefusalReason::TooManySessions,
+            admission.admit_incoming(ip("10.0.0.5")).unwrap_err()
+        );
+
+        // a solution does not lift the limits
+        assert_eq!(RefusalReason::TooManySessions, third.solved().unwrap_err());
+        drop(fourth);
+        assert_eq!(0, admission.lock().challenged());
+    }
+
+    #[test]
+    fn test_challenge_per_address() {
+        let config = AdmissionConfig {
+            max_sessions: 2,
+            max_sessions_per_ip: 2,
+            ..AdmissionConfig::default()
+        };
+        let admission = SharedAdmission::new(config, Some(PuzzleConfig::default()));
+
+        // challenged connections count towards the address allowance
+        let _first = admission.admit_incoming(ip("10.0.0.1")).unwrap();
+        let second = admission.admit_incoming(ip("10.0.0.1")).unwrap();
+        match second {
+            Admittance::Challenged(_) => {}
+            Admittance::Admitted(_) => panic!("admitted"),
+        }
+        assert_eq!(
+            RefusalReason::TooManyFromAddress,
+            admission.admit_incoming(ip("::ffff:10.0.0.1")).unwrap_err()
+        );
+        assert_eq!(1, admission.lock().challenged());
+
+        // other addresses are still challenged
+        assert!(admission.admit_incoming(ip("10.0.0.2")).is_ok());
+
+        drop(second);
+        assert!(admission.admit_incoming(ip("10.0.0.1")).is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use codec::message::ListenPorts;
+use codec::noise::NoiseConfig;
+use codec::serializer::Serialization;
+use codec::CodecConfig;
+use error::Error;
+use identity::Identity;
+use transport::admission::SharedAdmission;
+use transport::congestion::CongestionConfig;
+use transport::discovery::DiscoveryConfig;
+use transport::firewall::SharedFirewall;
+use transport::fragment::FragmentConfig;
+use transport::pmtu::PmtuConfig;
+use transport::policy::ErrorPolicy;
+use transport::puzzle::PuzzleSolver;
+use transport::reliable::ReliableConfig;
+use transport::replay::ReplayConfig;
+use transport::tls::TlsConfig;
+
+/// Transport configuration, set at construction time
+#[derive(Clone, Debug)]
+pub struct TransportConfig {
+    /// Frame codec configuration
+    pub codec: CodecConfig,
+    /// Message serialization backend
+    pub serialization: Serialization,
+    /// Reaction to malformed incoming frames; malformed datagrams are
+    /// always dropped individually
+    pub error_policy: ErrorPolicy,
+    /// Encrypt TCP sessions after a Noise XX handshake
+    pub noise: Option<NoiseConfig>,
+    /// Wrap accepted and connected TCP streams in TLS
+    pub tls: Option<TlsConfig>,
+    /// Sequence UDP datagrams and drop replayed or stale ones; enabled with
+    /// the default configuration in private network mode, and only
+    /// available in it, as nothing else authenticates the sequence
+    pub replay: Option<ReplayConfig>,
+    /// Fragmentation of UDP messages larger than a datagram
+    pub fragment: FragmentConfig,
+    /// Path MTU discovery of UDP sessions, up from `fragment.mtu`
+    pub pmtu: PmtuConfig,
+    /// Retransmission of UDP messages sent for reliable delivery
+    pub reliable: ReliableConfig,
+    /// Congestion window and pacing of UDP sessions
+    pub congestion: CongestionConfig,
+    /// Address filter and ban list, shared by the transports cloning
+    /// this configuration
+    pub firewall: SharedFirewall,
+    /// Connection limits and the proof-of-work puzzle required under load,
+    /// with counters shared by the transports cloning this configuration
+    pub admission: SharedAdmission,
+    /// Threads solving the puzzles of nodes we connect to, shared by the
+    /// transports cloning this configuration
+    pub solver: PuzzleSolver,
+    /// Node keypair, proven to peers in the Hello exchange
+    pub identity: Arc<Identity>,
+    /// Node version announced to peers
+    pub version: String,
+    /// Ports announced to peers; the TCP transport fills in its own port
+    pub listen_ports: ListenPorts,
+    /// Time allowed for encryption and Hello handshakes to complete
+    pub handshake_timeout: Duration,
+    /// Time after which a UDP session without incoming datagrams is closed
+    pub udp_idle_timeout: Duration,
+    /// Announce the node to, and discover peers on, the LAN over UDP
+    /// multicast
+    pub discovery: Option<DiscoveryConfig>,
+}
+
+impl TransportConfig {
+    /// Default configuration of the node with `identity`
+    pub fn new(identity: Arc<Identity>) -> Self {
+        TransportConfig {
+            codec: CodecConfig::default(),
+            serialization: Serialization::default(),
+            error_policy: ErrorPolicy::default(),
+            noise: None,
+            tls: None,
+            replay: None,
+            fragment: FragmentConfig::default(),
+            pmtu: PmtuConfig::default(),
+            reliable: ReliableConfig::default(),
+            congestion: CongestionConfig::default(),
+            firewall: SharedFirewall::default(),
+            admission: SharedAdmission::default(),
+            solver: PuzzleSolver::default(),
+            identity,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            listen_ports: ListenPorts::default(),
+            handshake_timeout: Duration::from_secs(10),
+            udp_idle_timeout: Duration::from_secs(60),
+            discovery: None,
+        }
+    }
+
+    /// Default configuration of a node with a newly generated identity
+    pub fn generate() -> Result<Self, Error> {
+        let identity = Identity::generate()?;
+        Ok(TransportConfig::new(Arc::new(identity)))
+    }
+}
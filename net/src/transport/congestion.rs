@@ -0,0 +1,341 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+/// UDP session congestion control configuration
+#[derive(Clone, Debug)]
+pub struct CongestionConfig {
+    /// Window before any acknowledgement, in reliable messages
+    pub initial_window: u32,
+    pub min_window: u32,
+    pub max_window: u32,
+    /// Pacing rate over the rate of a full window per round trip
+    pub pacing_gain: f64,
+    /// Pacing rate floor, in bytes per second, once the round-trip time
+    /// is measured; datagrams are not paced before
+    pub min_pacing_rate: u64,
+    /// Bytes sent back to back after an idle period
+    pub burst: usize,
+    /// Bytes of datagrams waiting to be paced out, per session. Messages
+    /// are queued or dropped whole; one larger than this is queued if
+    /// nothing else is.
+    pub max_queue_bytes: usize,
+    /// Reliable messages waiting for room in the congestion window, per
+    /// session
+    pub max_pending: usize,
+}
+
+impl Default for CongestionConfig {
+    fn default() -> Self {
+        CongestionConfig {
+            initial_window: 10,
+            min_window: 2,
+            max_window: 1024,
+            pacing_gain: 2.0,
+            min_pacing_rate: 64 * 1024,
+            burst: 64 * 1024,
+            max_queue_bytes: 8 * 1024 * 1024,
+            max_pending: 1024,
+        }
+    }
+}
+
+/// Congestion control snapshot of a UDP session
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CongestionStats {
+    /// Reliable messages allowed in flight
+    pub window: u32,
+    /// Pacing rate, in bytes per second, once the round-trip time is
+    /// measured
+    pub pacing_rate: Option<u64>,
+    /// Datagrams and reliable messages waiting to be sent
+    pub queued: usize,
+    /// Messages dropped from full queues
+    pub dropped: u64,
+    /// Smoothed round-trip time, once measured
+    pub srtt: Option<Duration>,
+    /// Largest datagram sent to the peer, as discovered
+    pub path_mtu: usize,
+}
+
+pub type SharedCongestionStats = Rc<RefCell<CongestionStats>>;
+
+/// AIMD congestion window over reliable messages, with token bucket
+/// pacing of every datagram sent to the peer
+#[derive(Debug)]
+pub struct Congestion {
+    config: CongestionConfig,
+    window: f64,
+    ssthresh: f64,
+    /// Losses before this time belong to an already handled event
+    recovery_until: Option<Instant>,
+    rate: Option<u64>,
+    tokens: f64,
+    refilled: Instant,
+    queue: VecDeque<Bytes>,
+    queued_bytes: usize,
+    dropped: u64,
+}
+
+impl Congestion {
+    pub fn new(config: CongestionConfig, now: Instant) -> Self {
+        Congestion {
+            window: f64::from(config.initial_window),
+            ssthresh: f64::from(config.max_window),
+            recovery_until: None,
+            rate: None,
+            tokens: config.burst as f64,
+            refilled: now,
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            dropped: 0,
+            config,
+        }
+    }
+
+    pub fn window(&self) -> u32 {
+        self.window as u32
+    }
+
+    /// Pacing rate, in bytes per second; `None` while not paced
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Counts a message dropped before reaching the pacing queue
+    pub fn drop_message(&mut self) {
+        self.dropped += 1;
+    }
+
+    /// Opens the window for acknowledged messages: by one message per
+    /// acknowledgement in slow start, by one message per window after
+    pub fn on_ack(&mut self, acked: usize) {
+        for _ in 0..acked {
+            self.window += match self.window < self.ssthresh {
+                true => 1.0,
+                false => 1.0 / self.window,
+            };
+        }
+        self.window = self.window.min(f64::from(self.config.max_window));
+    }
+
+    /// Halves the window after a loss, once per round trip
+    pub fn on_loss(&mut self, now: Instant, rtt: Duration) {
+        match self.recovery_until {
+            Some(until) if now < until => return,
+            _ => {}
+        }
+
+        self.ssthresh = (self.window / 2.0).max(f64::from(self.config.min_window));
+        self.window = self.ssthresh;
+        self.recovery_until = Some(now + rtt);
+    }
+
+    /// Paces a full window of `mtu` sized datagrams per round trip,
+    /// scaled by the pacing gain. Without a round-trip time there is no
+    /// rate to derive, and datagrams are sent as they come.
+    pub fn update_rate(&mut self, srtt: Option<Duration>, mtu: usize) {
+        let nanos = match srtt {
+            Some(srtt) => srtt.as_secs() as f64 * 1e9 + f64::from(srtt.subsec_nanos()),
+            None => 0.0,
+        };
+
+        self.rate = match nanos > 0.0 {
+            true => {
+                let rate = (self.config.pacing_gain * self.window * mtu as f64 * 1e9 / nanos) as u64;
+                Some(rate.max(self.config.min_pacing_rate))
+            }
+            false => None,
+        };
+    }
+
+    /// Queues the datagrams of a message for pacing; returns false if the
+    /// queue is full and the message was dropped
+    pub fn push(&mut self, datagrams: Vec<Bytes>) -> bool {
+        let len = datagrams.iter().map(Bytes::len).sum::<usize>();
+        if !self.queue.is_empty() && self.queued_bytes + len > self.config.max_queue_bytes {
+            self.dropped += 1;
+            return false;
+        }
+
+        self.queued_bytes += len;
+        self.queue.extend(datagrams);
+        true
+    }
+
+    /// Datagrams the token bucket allows to send now
+    pub fn poll(&mut self, now: Instant) -> Vec<Bytes> {
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        let burst = self.config.burst as f64;
+        self.refilled = now;
+
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => {
+                self.tokens = burst;
+                self.queued_bytes = 0;
+                return self.queue.drain(..).collect();
+            }
+        };
+        self.tokens = (self.tokens + elapsed * rate as f64).min(burst);
+
+        let mut ready = Vec::new();
+        loop {
+            // datagrams larger than the bucket go out once it is full
+            let len = match self.queue.front() {
+                Some(datagram) => (datagram.len() as f64).min(burst),
+                None => break,
+            };
+            if self.tokens < len {
+                break;
+            }
+
+            self.tokens -= len;
+            if let Some(datagram) = self.queue.pop_front() {
+                self.queued_bytes -= datagram.len();
+                ready.push(datagram);
+            }
+        }
+
+        ready
+    }
+
+    pub fn stats(&self, pending: usize, srtt: Option<Duration>, path_mtu: usize) -> CongestionStats {
+        CongestionStats {
+            window: self.window(),
+            pacing_rate: self.rate,
+            queued: self.queue.len() + pending,
+            dropped: self.dropped,
+            srtt,
+            path_mtu,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use transport::congestion::{Congestion, CongestionConfig};
+
+    #[test]
+    fn test_window() {
+        let config = CongestionConfig::default();
+        let now = Instant::now();
+        let rtt = Duration::from_millis(100);
+        let mut congestion = Congestion::new(config.clone(), now);
+        assert_eq!(config.initial_window, congestion.window());
+
+        // slow start doubles the window per round trip
+        congestion.on_ack(10);
+        assert_eq!(20, congestion.window());
+
+        congestion.on_loss(now, rtt);
+        assert_eq!(10, congestion.window());
+        // the same loss event
+        congestion.on_loss(now + rtt / 2, rtt);
+        assert_eq!(10, congestion.window());
+
+        // additive increase past the threshold
+        congestion.on_ack(11);
+        assert_eq!(11, congestion.window());
+
+        for i in 0..8 {
+            congestion.on_loss(now + rtt * (i + 1), rtt);
+        }
+        assert_eq!(config.min_window, congestion.window());
+    }
+
+    #[test]
+    fn test_pacing() {
+        let config = CongestionConfig {
+            burst: 3000,
+            max_queue_bytes: 4000,
+            ..CongestionConfig::default()
+        };
+        let now = Instant::now();
+        let mut congestion = Congestion::new(config.clone(), now);
+        assert_eq!(None, congestion.rate());
+
+        congestion.update_rate(Some(Duration::from_millis(100)), 1000);
+        // 2 * 10 datagrams of 1000 bytes per 100 ms
+        assert_eq!(Some(200_000), congestion.rate());
+        congestion.update_rate(Some(Duration::from_secs(100)), 1000);
+        assert_eq!(Some(config.min_pacing_rate), congestion.rate());
+        congestion.update_rate(Some(Duration::from_millis(100)), 1000);
+
+        for _ in 0..5 {
+            congestion.push(datagrams(1, 1000));
+        }
+        assert_eq!(4, congestion.queued());
+        assert_eq!(1, congestion.dropped());
+
+        // the full bucket lets a burst through, then refills at the rate
+        assert_eq!(3, congestion.poll(now).len());
+        assert!(congestion.poll(now + Duration::from_millis(1)).is_empty());
+        assert_eq!(1, congestion.poll(now + Duration::from_millis(20)).len());
+    }
+
+    #[test]
+    fn test_unpaced() {
+        let now = Instant::now();
+        let mut congestion = Congestion::new(CongestionConfig::default(), now);
+
+        // nothing is held back before the round-trip time is measured
+        for _ in 0..100 {
+            congestion.push(datagrams(10, 1400));
+        }
+        assert_eq!(1000, congestion.poll(now).len());
+        assert_eq!(0, congestion.queued());
+    }
+
+    #[test]
+    fn test_queue_messages() {
+        let config = CongestionConfig {
+            burst: 1000,
+            max_queue_bytes: 10_000,
+            ..CongestionConfig::default()
+        };
+        let now = Instant::now();
+        let mut congestion = Congestion::new(config, now);
+        congestion.update_rate(Some(Duration::from_millis(100)), 1000);
+
+        // a message larger than the queue fits into an empty one
+        assert!(congestion.push(datagrams(20, 1000)));
+        assert_eq!(20, congestion.queued());
+
+        // and nothing is queued next to it, so that no message loses
+        // only some of its fragments
+        assert!(!congestion.push(datagrams(2, 1000)));
+        assert_eq!(20, congestion.queued());
+        assert_eq!(1, congestion.dropped());
+
+        let mut sent = 0;
+        let mut time = now;
+        while congestion.queued() > 0 {
+            sent += congestion.poll(time).len();
+            time += Duration::from_millis(10);
+        }
+        assert_eq!(20, sent);
+        assert!(congestion.push(datagrams(10, 1000)));
+        assert!(!congestion.push(datagrams(1, 1)));
+    }
+
+    fn datagrams(count: usize, len: usize) -> Vec<Bytes> {
+        (0..count).map(|_| Bytes::from(vec![0u8; len])).collect()
+    }
+}
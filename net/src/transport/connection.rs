@@ -0,0 +1,69 @@
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Connection ID magic bytes ("GC"), distinct from the fragment and frame
+/// magic
+pub const MAGIC: [u8; 2] = [0x47, 0x43];
+/// magic (2) + connection ID (8)
+pub const HEADER_SZ: usize = 10;
+
+/// Identifies a UDP session regardless of the remote address. Chosen by
+/// the receiving end, which derives it from the nonce of its Hello.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ConnectionId(pub u64);
+
+impl ConnectionId {
+    /// Connection ID derived from a Hello nonce of at least 8 bytes
+    pub fn from_nonce(nonce: &[u8]) -> Self {
+        ConnectionId(BigEndian::read_u64(&nonce[..8]))
+    }
+}
+
+/// Prefixes a datagram with the connection ID of its destination session
+pub fn wrap(id: ConnectionId, datagram: &[u8]) -> Bytes {
+    let mut wrapped = BytesMut::with_capacity(HEADER_SZ + datagram.len());
+    wrapped.put_slice(&MAGIC);
+    wrapped.put_u64_be(id.0);
+    wrapped.put_slice(datagram);
+    wrapped.freeze()
+}
+
+/// Splits the connection ID off a datagram, if it carries one
+pub fn unwrap(mut datagram: BytesMut) -> (Option<ConnectionId>, BytesMut) {
+    if datagram.len() < HEADER_SZ || datagram[..MAGIC.len()] != MAGIC[..] {
+        return (None, datagram);
+    }
+
+    let header = datagram.split_to(HEADER_SZ);
+    let id = ConnectionId(BigEndian::read_u64(&header[MAGIC.len()..]));
+    (Some(id), datagram)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use transport::connection::{unwrap, wrap, ConnectionId, HEADER_SZ};
+    use transport::fragment;
+
+    #[test]
+    fn test_wrap() {
+        let id = ConnectionId::from_nonce(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(ConnectionId(0x0102_0304_0506_0708), id);
+
+        let wrapped = wrap(id, b"frame");
+        assert_eq!(HEADER_SZ + 5, wrapped.len());
+        assert!(!fragment::is_fragment(&wrapped));
+
+        let (unwrapped, datagram) = unwrap(BytesMut::from(&wrapped[..]));
+        assert_eq!(Some(id), unwrapped);
+        assert_eq!(&b"frame"[..], &datagram[..]);
+
+        // plain and truncated datagrams pass through
+        let (unwrapped, datagram) = unwrap(BytesMut::from(&b"frame"[..]));
+        assert_eq!(None, unwrapped);
+        assert_eq!(&b"frame"[..], &datagram[..]);
+        let (unwrapped, _) = unwrap(BytesMut::from(&wrapped[..HEADER_SZ - 1]));
+        assert_eq!(None, unwrapped);
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+use std::net;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{constant_time, digest, hmac};
+
+use error::{Error, ErrorKind, ErrorSeverity};
+
+/// timestamp (8) + HMAC-SHA256 tag (32)
+pub const COOKIE_SZ: usize = 40;
+
+fn timestamp(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Stateless address validation. A handshake from an address without a
+/// session is answered with a cookie only that address receives; no state
+/// is kept until the cookie is echoed back.
+pub struct CookieKey {
+    key: hmac::SigningKey,
+    lifetime: Duration,
+}
+
+impl CookieKey {
+    /// Creates a key valid for this process only; cookies expire after
+    /// `lifetime`
+    pub fn generate(lifetime: Duration) -> Result<Self, Error> {
+        let mut secret = [0u8; 32];
+        SystemRandom::new().fill(&mut secret).map_err(|_| {
+            Error::new(ErrorKind::Other, ErrorSeverity::High, "cannot generate a cookie key")
+        })?;
+
+        Ok(CookieKey {
+            key: hmac::SigningKey::new(&digest::SHA256, &secret),
+            lifetime,
+        })
+    }
+
+    fn tag(&self, address: &net::SocketAddr, timestamp: &[u8]) -> hmac::Signature {
+        let mut context = hmac::SigningContext::with_key(&self.key);
+        context.update(timestamp);
+        context.update(address.to_string().as_bytes());
+        context.sign()
+    }
+
+    pub fn issue(&self, address: &net::SocketAddr, now: SystemTime) -> Bytes {
+        let mut timestamp = [0u8; 8];
+        BigEndian::write_u64(&mut timestamp, self::timestamp(now));
+
+        let tag = self.tag(address, &timestamp);
+        let parts: [&[u8]; 2] = [&timestamp, tag.as_ref()];
+        Bytes::from(parts.concat())
+    }
+
+    /// Checks that the cookie was issued to `address` and has not expired
+    pub fn verify(&self, address: &net::SocketAddr, cookie: &[u8], now: SystemTime) -> bool {
+        if cookie.len() != COOKIE_SZ {
+            return false;
+        }
+
+        let issued = BigEndian::read_u64(&cookie[..8]);
+        let now = timestamp(now);
+        if issued > now || now - issued > self.lifetime.as_secs() {
+            return false;
+        }
+
+        let tag = self.tag(address, &cookie[..8]);
+        constant_time::verify_slices_are_equal(tag.as_ref(), &cookie[8..]).is_ok()
+    }
+}
+
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CookieKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+
+    use transport::cookie::{CookieKey, COOKIE_SZ};
+
+    #[test]
+    fn test_verify() {
+        let key = CookieKey::generate(Duration::from_secs(10)).unwrap();
+        let address: SocketAddr = "10.0.0.1:40102".parse().unwrap();
+        let now = SystemTime::now();
+
+        let cookie = key.issue(&address, now);
+        assert_eq!(COOKIE_SZ, cookie.len());
+        assert!(key.verify(&address, &cookie, now));
+        assert!(key.verify(&address, &cookie, now + Duration::from_secs(10)));
+
+        // issued to another address, expired, tampered or issued by
+        // another process
+        assert!(!key.verify(&"10.0.0.1:40103".parse().unwrap(), &cookie, now));
+        assert!(!key.verify(&address, &cookie, now + Duration::from_secs(11)));
+        let mut tampered = cookie.to_vec();
+        tampered[COOKIE_SZ - 1] ^= 1;
+        assert!(!key.verify(&address, &tampered, now));
+        let other = CookieKey::generate(Duration::from_secs(10)).unwrap();
+        assert!(!other.verify(&address, &cookie, now));
+        assert!(!key.verify(&address, &[], now));
+    }
+}
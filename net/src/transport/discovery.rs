@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bincode::internal::{deserialize, serialize};
+use bincode::Infinite;
+use byteorder::BigEndian;
+use net2::{UdpBuilder, UdpSocketExt};
+use tokio::reactor::Handle;
+use tokio_udp::UdpSocket;
+
+use codec::message::ListenPorts;
+use identity::{self, Identity, PeerId, PeerInfo, PUBLIC_KEY_SZ};
+use transport::firewall::canonical;
+
+/// Announcement magic ("GNDA")
+const MAGIC: &[u8] = b"GNDA";
+/// Announcement format version
+pub const VERSION: u8 = 2;
+/// Largest announcement accepted
+const MAX_ANNOUNCEMENT_SZ: usize = 1024;
+
+/// Domain separation prefix of announcement signatures
+const ANNOUNCEMENT_CONTEXT: &[u8] = b"golem-net-discovery";
+
+/// LAN peer discovery configuration
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// IPv4 or IPv6 multicast group, and the port announcements are sent to
+    pub group: net::SocketAddr,
+    /// Address of the interface joining an IPv4 group; any if unspecified
+    pub interface_v4: net::Ipv4Addr,
+    /// Index of the interface joining an IPv6 group; 0 for the default one
+    pub interface_v6: u32,
+    /// Time between announcements
+    pub interval: Duration,
+    /// Announcements accepted from a single IP address per `rate_period`
+    pub max_announcements: u32,
+    pub rate_period: Duration,
+    /// IP addresses tracked by the rate limiter, and announced nodes by
+    /// the replay filter
+    pub max_tracked: usize,
+    /// Maximum difference between an announcement timestamp and the local
+    /// clock
+    pub max_skew: Duration,
+    /// Time to live (IPv4) or hop limit (IPv6) of announcements
+    pub ttl: u32,
+    /// Deliver announcements to the nodes on this host too
+    pub loopback: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            group: net::SocketAddr::new(net::Ipv4Addr::new(239, 255, 77, 77).into(), 40110),
+            interface_v4: net::Ipv4Addr::new(0, 0, 0, 0),
+            interface_v6: 0,
+            interval: Duration::from_secs(30),
+            max_announcements: 16,
+            rate_period: Duration::from_secs(10),
+            max_tracked: 1024,
+            max_skew: Duration::from_secs(300),
+            ttl: 1,
+            loopback: true,
+        }
+    }
+}
+
+/// Lets other nodes on this host bind the group port too; needed besides
+/// address reuse on BSD systems
+#[cfg(unix)]
+fn reuse_port(builder: &UdpBuilder) -> io::Result<()> {
+    use net2::unix::UnixUdpBuilderExt;
+    builder.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn reuse_port(_: &UdpBuilder) -> io::Result<()> {
+    Ok(())
+}
+
+fn timestamp(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+fn micros(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_nanos() / 1000),
+        Err(_) => 0,
+    }
+}
+
+/// Node identity and listen ports, multicast to the LAN
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    pub public_key: Vec<u8>,
+    pub version: String,
+    pub listen_ports: ListenPorts,
+    /// Announcement time, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Increases with every announcement of the node, so that receivers
+    /// drop replayed ones
+    pub sequence: u64,
+    pub signature: Vec<u8>,
+}
+
+impl Announcement {
+    fn content(
+        public_key: &[u8],
+        version: &str,
+        listen_ports: &ListenPorts,
+        timestamp: u64,
+        sequence: u64,
+    ) -> Vec<u8> {
+        let announced = (public_key, version, listen_ports, timestamp, sequence);
+        let encoded = serialize::<_, Infinite, BigEndian>(&announced, Infinite).unwrap_or_default();
+        [ANNOUNCEMENT_CONTEXT, &encoded[..]].concat()
+    }
+
+    pub fn new(
+        identity: &Identity,
+        version: String,
+        listen_ports: ListenPorts,
+        sequence: u64,
+        now: SystemTime,
+    ) -> Self {
+        let timestamp = timestamp(now);
+        let content = Announcement::content(
+            identity.public_key(),
+            &version,
+            &listen_ports,
+            timestamp,
+            sequence,
+        );
+
+        Announcement {
+            public_key: identity.public_key().to_vec(),
+            version,
+            listen_ports,
+            timestamp,
+            sequence,
+            signature: identity.sign(&content),
+        }
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.public_key)
+    }
+
+    /// Checks that the announced key signed the announcement
+    pub fn verify(&self) -> bool {
+        let content = Announcement::content(
+            &self.public_key,
+            &self.version,
+            &self.listen_ports,
+            self.timestamp,
+            self.sequence,
+        );
+        self.public_key.len() == PUBLIC_KEY_SZ && identity::verify(&self.public_key, &content, &self.signature)
+    }
+
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let encoded = serialize::<_, Infinite, BigEndian>(self, Infinite).ok()?;
+        let parts: [&[u8]; 3] = [MAGIC, &[VERSION], &encoded];
+        Some(parts.concat())
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let header = MAGIC.len() + 1;
+        if bytes.len() <= header || bytes.len() > MAX_ANNOUNCEMENT_SZ {
+            return None;
+        }
+        if &bytes[..MAGIC.len()] != MAGIC || bytes[MAGIC.len()] != VERSION {
+            return None;
+        }
+        deserialize::<_, BigEndian>(&bytes[header..]).ok()
+    }
+
+    pub fn into_peer_info(self) -> PeerInfo {
+        PeerInfo {
+            peer_id: self.peer_id(),
+            public_key: self.public_key,
+            version: self.version,
+            listen_ports: self.listen_ports,
+        }
+    }
+}
+
+/// Announcements counted in the current period of an IP address
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Latest announcement accepted from a node
+struct Seen {
+    sequence: u64,
+    timestamp: u64,
+}
+
+/// Multicast announcer and listener
+pub struct Discovery {
+    config: DiscoveryConfig,
+    /// Shares the group socket with the receiving stream
+    socket: net::UdpSocket,
+    windows: HashMap<net::IpAddr, Window>,
+    /// Sequence number of our last announcement
+    sequence: u64,
+    seen: HashMap<PeerId, Seen>,
+}
+
+impl Discovery {
+    /// Binds the group port, shared with other nodes on this host, and
+    /// joins the group. Returns the socket announcements arrive on.
+    pub fn bind(config: DiscoveryConfig) -> io::Result<(Self, UdpSocket)> {
+        let group = config.group;
+        let builder = match group {
+            net::SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+            net::SocketAddr::V6(_) => UdpBuilder::new_v6()?,
+        };
+
+        builder.reuse_address(true)?;
+        reuse_port(&builder)?;
+
+        let socket = match group {
+            net::SocketAddr::V4(v4) => {
+                let socket = builder.bind((net::Ipv4Addr::new(0, 0, 0, 0), group.port()))?;
+                socket.join_multicast_v4(v4.ip(), &config.interface_v4)?;
+                UdpSocketExt::set_multicast_if_v4(&socket, &config.interface_v4)?;
+                socket.set_multicast_loop_v4(config.loopback)?;
+                socket.set_multicast_ttl_v4(config.ttl)?;
+                socket
+            }
+            net::SocketAddr::V6(v6) => {
+                builder.only_v6(true)?;
+                let socket = builder.bind((net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), group.port()))?;
+                socket.join_multicast_v6(v6.ip(), config.interface_v6)?;
+                UdpSocketExt::set_multicast_if_v6(&socket, config.interface_v6)?;
+                socket.set_multicast_loop_v6(config.loopback)?;
+                UdpSocketExt::set_multicast_hops_v6(&socket, config.ttl)?;
+                socket
+            }
+        };
+
+        let receiver = UdpSocket::from_std(socket.try_clone()?, &Handle::default())?;
+        let discovery = Discovery {
+            config,
+            socket,
+            windows: HashMap::new(),
+            sequence: 0,
+            seen: HashMap::new(),
+        };
+        Ok((discovery, receiver))
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Sequence number of the next announcement. It follows the clock (in
+    /// microseconds) so that it keeps increasing across restarts.
+    pub fn next_sequence(&mut self, now: SystemTime) -> u64 {
+        self.sequence = micros(now).max(self.sequence + 1);
+        self.sequence
+    }
+
+    /// Multicasts an announcement to the group
+    pub fn announce(&self, announcement: &Announcement) -> io::Result<()> {
+        let bytes = match announcement.encode() {
+            Some(bytes) => bytes,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "cannot encode the announcement")),
+        };
+
+        self.socket.send_to(&bytes, &self.config.group)?;
+        Ok(())
+    }
+
+    /// Returns the node announced in a datagram; our own announcements,
+    /// invalid, replayed ones and those over the sender's rate limit yield
+    /// none
+    pub fn receive(
+        &mut self,
+        address: net::SocketAddr,
+        datagram: &[u8],
+        own_key: &[u8],
+        now: SystemTime,
+    ) -> Option<PeerInfo> {
+        let announcement = Announcement::decode(datagram)?;
+        // looped back to us
+        if &announcement.public_key[..] == own_key {
+            return None;
+        }
+        if !self.admit(address.ip(), Instant::now()) {
+            return None;
+        }
+
+        let local = timestamp(now);
+        let skew = announcement.timestamp.max(local) - announcement.timestamp.min(local);
+        if skew > self.config.max_skew.as_secs() || !announcement.verify() {
+            return None;
+        }
+        if !self.fresh(&announcement, local) {
+            return None;
+        }
+        Some(announcement.into_peer_info())
+    }
+
+    /// Records a verified announcement; returns false if its node already
+    /// announced this or a later sequence number
+    fn fresh(&mut self, announcement: &Announcement, local: u64) -> bool {
+        let peer_id = announcement.peer_id();
+        let skew = self.config.max_skew.as_secs();
+
+        if !self.seen.contains_key(&peer_id) && self.seen.len() >= self.config.max_tracked {
+            // announcements outside the clock skew tolerance are refused
+            // anyway; nodes not heard from since are forgotten
+            self.seen.retain(|_, s| s.timestamp.max(local) - s.timestamp.min(local) <= skew);
+            if self.seen.len() >= self.config.max_tracked {
+                return false;
+            }
+        }
+
+        let seen = self.seen.entry(peer_id).or_insert(Seen {
+            sequence: 0,
+            timestamp: 0,
+        });
+        if announcement.sequence <= seen.sequence {
+            return false;
+        }
+
+        seen.sequence = announcement.sequence;
+        seen.timestamp = announcement.timestamp;
+        true
+    }
+
+    /// Counts an announcement from `ip` against its rate limit
+    fn admit(&mut self, ip: net::IpAddr, now: Instant) -> bool {
+        let ip = canonical(ip);
+        let period = self.config.rate_period;
+
+        if !self.windows.contains_key(&ip) && self.windows.len() >= self.config.max_tracked {
+            self.windows.retain(|_, w| now.duration_since(w.started) < period);
+            if self.windows.len() >= self.config.max_tracked {
+                return false;
+            }
+        }
+
+        let window = self.windows.entry(ip).or_insert_with(|| Window {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= period {
+            window.started = now;
+            window.count = 0;
+        }
+        if window.count >= self.config.max_announcements {
+            return false;
+        }
+
+        window.count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::time::{Duration, SystemTime};
+
+    use codec::message::ListenPorts;
+    use identity::Identity;
+    use transport::discovery::{Announcement, Discovery, DiscoveryConfig};
+
+    fn ports() -> ListenPorts {
+        ListenPorts {
+            tcp: Some(40102),
+            udp: Some(40103),
+        }
+    }
+
+    #[test]
+    fn test_announcement() {
+        let identity = Identity::generate().unwrap();
+        let now = SystemTime::now();
+        let announcement = Announcement::new(&identity, "0.1.0".to_owned(), ports(), 1, now);
+        assert!(announcement.verify());
+
+        let bytes = announcement.encode().unwrap();
+        let decoded = Announcement::decode(&bytes).unwrap();
+        assert!(decoded.verify());
+        assert_eq!(identity.peer_id(), decoded.peer_id());
+        assert_eq!(ports(), decoded.listen_ports);
+
+        let mut forged = decoded.clone();
+        forged.listen_ports.tcp = Some(1);
+        assert!(!forged.verify());
+        let mut forged = decoded.clone();
+        forged.sequence += 1;
+        assert!(!forged.verify());
+        assert!(Announcement::decode(&bytes[1..]).is_none());
+    }
+
+    #[test]
+    fn test_loopback() {
+        // a port of its own per test run, on the loopback interface
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = DiscoveryConfig {
+            group: SocketAddr::new(Ipv4Addr::new(239, 255, 77, 77).into(), port),
+            interface_v4: Ipv4Addr::new(127, 0, 0, 1),
+            max_announcements: 1,
+            ..DiscoveryConfig::default()
+        };
+
+        let (mut first, _) = Discovery::bind(config.clone()).unwrap();
+        let (second, _) = Discovery::bind(config.clone()).unwrap();
+        let own = Identity::generate().unwrap();
+        let other = Identity::generate().unwrap();
+        let now = SystemTime::now();
+
+        let sequence = first.next_sequence(now);
+        assert!(first.next_sequence(now) > sequence);
+        let mine = Announcement::new(&own, "0.1.0".to_owned(), ports(), sequence, now);
+        let theirs = Announcement::new(&other, "0.1.0".to_owned(), ports(), sequence, now);
+        first.announce(&mine).unwrap();
+        second.announce(&theirs).unwrap();
+
+        // both announcements loop back to every member on this host; the
+        // stream half made the socket non-blocking
+        first.socket.set_nonblocking(false).unwrap();
+        first.socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0u8; 2048];
+        let mut discovered = Vec::new();
+        for _ in 0..2 {
+            let (len, address) = first.socket.recv_from(&mut buf).unwrap();
+            discovered.extend(first.receive(address, &buf[..len], own.public_key(), now));
+        }
+
+        assert_eq!(1, discovered.len());
+        assert_eq!(other.peer_id(), discovered[0].peer_id);
+
+        // over the rate limit of the sending address
+        let bytes = theirs.encode().unwrap();
+        let address = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port);
+        assert!(first.receive(address, &bytes, own.public_key(), now).is_none());
+    }
+
+    #[test]
+    fn test_replay() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = DiscoveryConfig {
+            group: SocketAddr::new(Ipv4Addr::new(239, 255, 77, 77).into(), port),
+            interface_v4: Ipv4Addr::new(127, 0, 0, 1),
+            ..DiscoveryConfig::default()
+        };
+
+        let (mut discovery, _) = Discovery::bind(config).unwrap();
+        let own = Identity::generate().unwrap();
+        let other = Identity::generate().unwrap();
+        let now = SystemTime::now();
+        let address = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port);
+        let receive = |discovery: &mut Discovery, sequence| {
+            let announcement = Announcement::new(&other, "0.1.0".to_owned(), ports(), sequence, now);
+            let bytes = announcement.encode().unwrap();
+            discovery.receive(address, &bytes, own.public_key(), now).is_some()
+        };
+
+        assert!(receive(&mut discovery, 10));
+        // replayed, or older than the latest one
+        assert!(!receive(&mut discovery, 10));
+        assert!(!receive(&mut discovery, 9));
+        assert!(receive(&mut discovery, 11));
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fmt;
+
+use error::{Error, ErrorKind, ErrorSeverity};
+use write_atomically;
+
+fn firewall_error(message: &str) -> Error {
+    Error::new(ErrorKind::Other, ErrorSeverity::Low, message)
+}
+
+/// Maps IPv4-mapped IPv6 addresses to IPv4, so that they match IPv4 rules
+/// and bans
+pub fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xFFFF, hi, lo] => IpAddr::V4(Ipv4Addr::new(
+                (hi >> 8) as u8,
+                hi as u8,
+                (lo >> 8) as u8,
+                lo as u8,
+            )),
+            _ => address,
+        },
+        _ => address,
+    }
+}
+
+/// IP address range in CIDR notation; a plain address is a single host range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn new(address: IpAddr, prefix: u8) -> Result<Self, Error> {
+        let (address, prefix) = match (address, canonical(address)) {
+            // a mapped range keeps its IPv4 prefix length
+            (IpAddr::V6(_), IpAddr::V4(v4)) if prefix >= 96 => (IpAddr::V4(v4), prefix - 96),
+            _ => (address, prefix),
+        };
+        let max = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix > max {
+            return Err(firewall_error(&format!("invalid prefix length: {}", prefix)));
+        }
+
+        Ok(IpNet { address, prefix })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, canonical(*address)) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                let mask = match self.prefix {
+                    0 => 0,
+                    p => !0u32 << (32 - p),
+                };
+                u32::from(net) & mask == u32::from(a) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                let mask = match self.prefix {
+                    0 => 0,
+                    p => !0u128 << (128 - p),
+                };
+                u128::from(net) & mask == u128::from(a) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let address: IpAddr = parts.next().unwrap_or("").trim().parse()?;
+        let prefix = match parts.next() {
+            Some(p) => p
+                .trim()
+                .parse()
+                .map_err(|_| firewall_error(&format!("invalid prefix length: {}", p)))?,
+            None => match address {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            },
+        };
+
+        IpNet::new(address, prefix)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// Firewall rules; the address ranges can be replaced at runtime
+#[derive(Clone, Debug, Default)]
+pub struct FirewallConfig {
+    /// When not empty, only addresses in these ranges are accepted
+    pub allow: Vec<IpNet>,
+    /// Addresses in these ranges are refused, even if allowed
+    pub deny: Vec<IpNet>,
+    /// File the ban list is loaded from and saved to
+    pub ban_file: Option<PathBuf>,
+}
+
+/// Address filter consulted on accepted and outgoing connections and on
+/// received datagrams. Banned addresses are refused until their ban expires.
+#[derive(Debug, Default)]
+pub struct Firewall {
+    config: FirewallConfig,
+    /// Banned addresses and their expiry time; `None` never expires
+    bans: HashMap<IpAddr, Option<SystemTime>>,
+}
+
+impl Firewall {
+    /// Creates a firewall, restoring the ban list from `ban_file` if it
+    /// exists
+    pub fn new(config: FirewallConfig) -> Result<Self, Error> {
+        let mut firewall = Firewall {
+            config,
+            bans: HashMap::new(),
+        };
+
+        let path = match firewall.config.ban_file {
+            Some(ref path) if path.exists() => path.clone(),
+            _ => return Ok(firewall),
+        };
+
+        let reader = BufReader::new(File::open(&path)?);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (address, expiry) = parse_ban(line)?;
+            firewall.bans.insert(address, expiry);
+        }
+
+        firewall.prune(SystemTime::now());
+        Ok(firewall)
+    }
+
+    pub fn allows(&self, address: &IpAddr, now: SystemTime) -> bool {
+        let address = canonical(*address);
+
+        match self.bans.get(&address) {
+            Some(&None) => return false,
+            Some(&Some(expiry)) if expiry > now => return false,
+            _ => {}
+        }
+
+        if self.config.deny.iter().any(|net| net.contains(&address)) {
+            return false;
+        }
+
+        self.config.allow.is_empty() || self.config.allow.iter().any(|net| net.contains(&address))
+    }
+
+    /// Bans an address for `duration`, or until unbanned; durations past
+    /// the representable time ban it until unbanned too. Extends an
+    /// existing ban but never shortens it.
+    pub fn ban(
+        &mut self,
+        address: &IpAddr,
+        duration: Option<Duration>,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let expiry = duration.and_then(|d| now.checked_add(d));
+        let entry = self.bans.entry(canonical(*address)).or_insert(expiry);
+        *entry = match (*entry, expiry) {
+            (Some(current), Some(expiry)) => Some(current.max(expiry)),
+            _ => None,
+        };
+
+        self.prune(now);
+        self.save()
+    }
+
+    /// Replaces the allowed and denied address ranges
+    pub fn set_rules(&mut self, allow: Vec<IpNet>, deny: Vec<IpNet>) {
+        self.config.allow = allow;
+        self.config.deny = deny;
+    }
+
+    /// Lifts a ban. Returns whether the address was banned.
+    pub fn unban(&mut self, address: &IpAddr) -> Result<bool, Error> {
+        let banned = self.bans.remove(&canonical(*address)).is_some();
+        if banned {
+            self.save()?;
+        }
+        Ok(banned)
+    }
+
+    /// Active bans and their expiry times
+    pub fn bans(&self, now: SystemTime) -> Vec<(IpAddr, Option<SystemTime>)> {
+        self.bans
+            .iter()
+            .filter(|&(_, expiry)| expiry.map_or(true, |e| e > now))
+            .map(|(address, expiry)| (*address, *expiry))
+            .collect()
+    }
+
+    fn prune(&mut self, now: SystemTime) {
+        self.bans.retain(|_, expiry| expiry.map_or(true, |e| e > now));
+    }
+
+    /// Writes the ban list to `ban_file`, replacing it atomically
+    fn save(&self) -> Result<(), Error> {
+        let path = match self.config.ban_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut bytes = Vec::new();
+        for (address, expiry) in &self.bans {
+            let expiry = match expiry.map(|e| e.duration_since(UNIX_EPOCH)) {
+                Some(Ok(d)) => d.as_secs().to_string(),
+                Some(Err(_)) => continue,
+                None => "-".to_owned(),
+            };
+            writeln!(bytes, "{} {}", address, expiry)?;
+        }
+
+        write_atomically(path, &bytes)?;
+        Ok(())
+    }
+}
+
+/// Parses an `<address> <expiry>` ban list line, where the expiry is in
+/// seconds since the epoch or `-` for permanent bans. Expiry times past
+/// the representable time are permanent too.
+fn parse_ban(line: &str) -> Result<(IpAddr, Option<SystemTime>), Error> {
+    let invalid = || firewall_error(&format!("invalid ban list entry: {}", line));
+    let mut parts = line.split_whitespace();
+
+    let address: IpAddr = parts.next().ok_or_else(invalid)?.parse()?;
+    let expiry = match parts.next().ok_or_else(invalid)? {
+        "-" => None,
+        secs => {
+            let secs: u64 = secs.parse().map_err(|_| invalid())?;
+            UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+        }
+    };
+
+    Ok((canonical(address), expiry))
+}
+
+/// Firewall shared by the transports and the network
+#[derive(Clone, Debug, Default)]
+pub struct SharedFirewall(Arc<RwLock<Firewall>>);
+
+impl SharedFirewall {
+    pub fn new(firewall: Firewall) -> Self {
+        SharedFirewall(Arc::new(RwLock::new(firewall)))
+    }
+
+    pub fn allows(&self, address: &IpAddr) -> bool {
+        let firewall = self.0.read().unwrap_or_else(|e| e.into_inner());
+        firewall.allows(address, SystemTime::now())
+    }
+
+    pub fn ban(&self, address: &IpAddr, duration: Option<Duration>) -> Result<(), Error> {
+        let mut firewall = self.0.write().unwrap_or_else(|e| e.into_inner());
+        firewall.ban(address, duration, SystemTime::now())
+    }
+
+    pub fn unban(&self, address: &IpAddr) -> Result<bool, Error> {
+        let mut firewall = self.0.write().unwrap_or_else(|e| e.into_inner());
+        firewall.unban(address)
+    }
+
+    pub fn set_rules(&self, allow: Vec<IpNet>, deny: Vec<IpNet>) {
+        let mut firewall = self.0.write().unwrap_or_else(|e| e.into_inner());
+        firewall.set_rules(allow, deny);
+    }
+
+    pub fn bans(&self) -> Vec<(IpAddr, Option<SystemTime>)> {
+        let firewall = self.0.read().unwrap_or_else(|e| e.into_inner());
+        firewall.bans(SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use std::net::IpAddr;
+    use std::time::{Duration, SystemTime};
+
+    use transport::firewall::{Firewall, FirewallConfig, IpNet};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&ip("10.1.200.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(net.contains(&ip("::ffff:10.1.0.1")));
+        assert!(!net.contains(&ip("::1")));
+        let mapped: IpNet = "::ffff:10.1.0.0/112".parse().unwrap();
+        assert_eq!(net, mapped);
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&ip("2001:db8:1::1")));
+        assert!(!net.contains(&ip("2001:db9::1")));
+
+        let host: IpNet = "192.168.0.1".parse().unwrap();
+        assert!(host.contains(&ip("192.168.0.1")));
+        assert!(!host.contains(&ip("192.168.0.2")));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&ip("8.8.8.8")));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        let config = FirewallConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            deny: vec!["10.0.0.13".parse().unwrap()],
+            ban_file: None,
+        };
+        let mut firewall = Firewall::new(config).unwrap();
+        let now = SystemTime::now();
+
+        assert!(firewall.allows(&ip("10.0.0.12"), now));
+        assert!(firewall.allows(&ip("fd00::1"), now));
+        assert!(!firewall.allows(&ip("10.0.0.13"), now));
+        assert!(!firewall.allows(&ip("192.168.0.1"), now));
+        assert!(Firewall::default().allows(&ip("192.168.0.1"), now));
+
+        // replaced rules apply at once
+        firewall.set_rules(Vec::new(), vec!["10.0.0.0/24".parse().unwrap()]);
+        assert!(firewall.allows(&ip("192.168.0.1"), now));
+        assert!(!firewall.allows(&ip("10.0.0.12"), now));
+        assert!(firewall.allows(&ip("10.0.1.12"), now));
+    }
+
+    #[test]
+    fn test_ban() {
+        let mut firewall = Firewall::default();
+        let now = SystemTime::now();
+        let address = ip("192.168.0.1");
+
+        firewall.ban(&address, Some(Duration::from_secs(60)), now).unwrap();
+        assert!(!firewall.allows(&address, now));
+        assert!(!firewall.allows(&ip("::ffff:192.168.0.1"), now));
+        assert!(firewall.allows(&address, now + Duration::from_secs(61)));
+
+        // a shorter ban does not lift a longer one
+        firewall.ban(&address, Some(Duration::from_secs(1)), now).unwrap();
+        assert!(!firewall.allows(&address, now + Duration::from_secs(30)));
+
+        firewall.ban(&address, None, now).unwrap();
+        assert!(!firewall.allows(&address, now + Duration::from_secs(3600)));
+
+        assert!(firewall.unban(&address).unwrap());
+        assert!(!firewall.unban(&address).unwrap());
+        assert!(firewall.allows(&address, now));
+
+        // an expiry past the representable time never comes
+        firewall.ban(&address, Some(Duration::from_secs(u64::max_value())), now).unwrap();
+        assert_eq!(vec![(address, None)], firewall.bans(now));
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = env::temp_dir().join(format!("golem-net-bans-{}", process::id()));
+        let config = FirewallConfig {
+            ban_file: Some(path.clone()),
+            ..FirewallConfig::default()
+        };
+        let now = SystemTime::now();
+
+        {
+            let mut firewall = Firewall::new(config.clone()).unwrap();
+            firewall.ban(&ip("10.0.0.1"), None, now).unwrap();
+            firewall.ban(&ip("::1"), Some(Duration::from_secs(600)), now).unwrap();
+        }
+
+        let restored = Firewall::new(config).unwrap();
+        assert!(!restored.allows(&ip("10.0.0.1"), now));
+        assert!(!restored.allows(&ip("::1"), now));
+        assert!(restored.allows(&ip("::1"), now + Duration::from_secs(601)));
+        assert_eq!(2, restored.bans(now).len());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_overflowing_expiry() {
+        let path = env::temp_dir().join(format!("golem-net-bans-overflow-{}", process::id()));
+        fs::write(&path, format!("10.0.0.1 {}\n", u64::max_value())).unwrap();
+        let config = FirewallConfig {
+            ban_file: Some(path.clone()),
+            ..FirewallConfig::default()
+        };
+
+        let firewall = Firewall::new(config).unwrap();
+        assert_eq!(vec![(ip("10.0.0.1"), None)], firewall.bans(SystemTime::now()));
+
+        fs::remove_file(path).unwrap();
+    }
+}
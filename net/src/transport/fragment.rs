@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use transport::firewall::canonical;
+
+/// Fragment magic bytes ("GF"). Whole frames start with the frame magic,
+/// or with a legacy length far above the size of a datagram.
+pub const MAGIC: [u8; 2] = [0x47, 0x46];
+/// magic (2) + message id (4) + fragment index (2) + fragment count (2)
+pub const HEADER_SZ: usize = 10;
+
+/// UDP fragmentation configuration
+#[derive(Clone, Debug)]
+pub struct FragmentConfig {
+    /// Largest datagram sent until the path MTU is discovered; larger
+    /// frames are fragmented
+    pub mtu: usize,
+    /// Largest message reassembled
+    pub max_message_size: usize,
+    /// Memory held by the incomplete messages of a single host; its oldest
+    /// ones are dropped to make room for new fragments
+    pub max_peer_buffer: usize,
+    /// Memory held by all incomplete messages; the oldest ones are dropped
+    /// to make room for new fragments
+    pub max_buffer: usize,
+    /// Time allowed for all fragments of a message to arrive
+    pub timeout: Duration,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        FragmentConfig {
+            mtu: 1200,
+            max_message_size: 4 * 1024 * 1024,
+            max_peer_buffer: 8 * 1024 * 1024,
+            max_buffer: 64 * 1024 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Why a fragment was dropped
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FragmentError {
+    /// Invalid header, or one inconsistent with earlier fragments
+    Malformed,
+    /// Message above the size limit, or too large to be fragmented
+    TooLarge,
+    /// Message too large for the reassembly memory of the peer or of the
+    /// transport
+    BufferFull,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.len() >= MAGIC.len() && datagram[..MAGIC.len()] == MAGIC[..]
+}
+
+/// Splits a frame into datagrams of at most `mtu` bytes. Frames that fit
+/// are sent whole.
+pub fn split(id: u32, frame: Bytes, mtu: usize) -> Result<Vec<Bytes>, FragmentError> {
+    if frame.len() <= mtu {
+        return Ok(vec![frame]);
+    }
+    if mtu <= HEADER_SZ {
+        return Err(FragmentError::TooLarge);
+    }
+
+    let payload = mtu - HEADER_SZ;
+    let count = (frame.len() + payload - 1) / payload;
+    if count > u16::max_value() as usize {
+        return Err(FragmentError::TooLarge);
+    }
+
+    let datagrams = frame
+        .chunks(payload)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut datagram = BytesMut::with_capacity(HEADER_SZ + chunk.len());
+            datagram.put_slice(&MAGIC);
+            datagram.put_u32_be(id);
+            datagram.put_u16_be(index as u16);
+            datagram.put_u16_be(count as u16);
+            datagram.put_slice(chunk);
+            datagram.freeze()
+        })
+        .collect();
+
+    Ok(datagrams)
+}
+
+/// Message with fragments still missing
+struct Partial {
+    fragments: HashMap<u16, Bytes>,
+    count: u16,
+    /// Payload bytes received
+    size: usize,
+    /// Memory charged to the buffers, fragment headers included
+    cost: usize,
+    started: Instant,
+    /// Position in the order messages were started in
+    position: u64,
+}
+
+type PartialKey = (net::SocketAddr, u32);
+
+/// Incomplete messages of a host
+#[derive(Default)]
+struct PeerBuffer {
+    buffered: usize,
+    /// Oldest first
+    partial: BTreeMap<u64, PartialKey>,
+}
+
+/// Reassembles fragmented frames, within per-host and total memory limits
+pub struct Reassembler {
+    config: FragmentConfig,
+    partial: HashMap<PartialKey, Partial>,
+    /// Incomplete messages, oldest first
+    order: BTreeMap<u64, PartialKey>,
+    /// Keyed by canonical IP address, so that a host cannot claim more
+    /// memory from other ports
+    per_peer: HashMap<net::IpAddr, PeerBuffer>,
+    buffered: usize,
+    next_position: u64,
+}
+
+impl Reassembler {
+    pub fn new(config: FragmentConfig) -> Self {
+        Reassembler {
+            config,
+            partial: HashMap::new(),
+            order: BTreeMap::new(),
+            per_peer: HashMap::new(),
+            buffered: 0,
+            next_position: 0,
+        }
+    }
+
+    /// Memory held by incomplete messages
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Adds a fragment received from `address`. Returns the frame once all
+    /// of its fragments have arrived.
+    pub fn insert(
+        &mut self,
+        address: net::SocketAddr,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<BytesMut>, FragmentError> {
+        if datagram.len() <= HEADER_SZ || !is_fragment(datagram) {
+            return Err(FragmentError::Malformed);
+        }
+
+        let id = BigEndian::read_u32(&datagram[2..6]);
+        let index = BigEndian::read_u16(&datagram[6..8]);
+        let count = BigEndian::read_u16(&datagram[8..10]);
+        if index >= count {
+            return Err(FragmentError::Malformed);
+        }
+
+        let key = (address, id);
+        let payload = &datagram[HEADER_SZ..];
+        let (size, complete) = match self.partial.get(&key) {
+            Some(p) if p.count != count => return Err(FragmentError::Malformed),
+            // duplicated in transit
+            Some(p) if p.fragments.contains_key(&index) => return Ok(None),
+            Some(p) => (p.size, p.fragments.len() + 1 == count as usize),
+            None => (0, count == 1),
+        };
+
+        if size + payload.len() > self.config.max_message_size {
+            self.remove(&key);
+            return Err(FragmentError::TooLarge);
+        }
+
+        let host = canonical(address.ip());
+        let cost = datagram.len();
+        if !self.make_room(&key, host, cost) {
+            // the message cannot fit whole
+            self.remove(&key);
+            return Err(FragmentError::BufferFull);
+        }
+
+        if !self.partial.contains_key(&key) {
+            let position = self.next_position;
+            self.next_position += 1;
+
+            self.partial.insert(key, Partial {
+                fragments: HashMap::new(),
+                count,
+                size: 0,
+                cost: 0,
+                started: now,
+                position,
+            });
+            self.order.insert(position, key);
+            let peer = self.per_peer.entry(host).or_insert_with(PeerBuffer::default);
+            peer.partial.insert(position, key);
+        }
+
+        if let Some(partial) = self.partial.get_mut(&key) {
+            partial.fragments.insert(index, Bytes::from(payload));
+            partial.size += payload.len();
+            partial.cost += cost;
+        }
+        if let Some(peer) = self.per_peer.get_mut(&host) {
+            peer.buffered += cost;
+        }
+        self.buffered += cost;
+
+        if !complete {
+            return Ok(None);
+        }
+
+        let partial = match self.remove(&key) {
+            Some(partial) => partial,
+            None => return Ok(None),
+        };
+
+        let mut frame = BytesMut::with_capacity(partial.size);
+        for index in 0..count {
+            match partial.fragments.get(&index) {
+                Some(fragment) => frame.put_slice(fragment),
+                None => return Err(FragmentError::Malformed),
+            }
+        }
+        Ok(Some(frame))
+    }
+
+    /// Drops messages not completed in time; returns how many
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.config.timeout;
+        let expired: Vec<_> = self.partial
+            .iter()
+            .filter(|&(_, p)| now.duration_since(p.started) >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    /// Drops the oldest incomplete messages of the host, then of any host,
+    /// until `cost` more bytes fit. Returns false if they do not fit without
+    /// dropping the message with the given key.
+    fn make_room(&mut self, key: &PartialKey, host: net::IpAddr, cost: usize) -> bool {
+        loop {
+            let oldest = match self.per_peer.get(&host) {
+                Some(peer) if peer.buffered + cost > self.config.max_peer_buffer => {
+                    peer.partial.values().find(|k| *k != key).cloned()
+                }
+                _ => break,
+            };
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => return false,
+            };
+        }
+
+        while self.buffered + cost > self.config.max_buffer {
+            let oldest = self.order.values().find(|k| *k != key).cloned();
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => return false,
+            };
+        }
+        true
+    }
+
+    fn remove(&mut self, key: &PartialKey) -> Option<Partial> {
+        let partial = self.partial.remove(key)?;
+        self.order.remove(&partial.position);
+        self.buffered -= partial.cost;
+
+        let host = canonical(key.0.ip());
+        let empty = match self.per_peer.get_mut(&host) {
+            Some(peer) => {
+                peer.buffered -= partial.cost;
+                peer.partial.remove(&partial.position);
+                peer.partial.is_empty()
+            }
+            None => return Some(partial),
+        };
+        if empty {
+            self.per_peer.remove(&host);
+        }
+        Some(partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    use bytes::Bytes;
+
+    use transport::fragment::{split, FragmentConfig, FragmentError, Reassembler, HEADER_SZ};
+
+    fn frame(len: usize) -> Bytes {
+        (0..len).map(|i| i as u8).collect::<Vec<u8>>().into()
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let address: SocketAddr = "10.0.0.1:40102".parse().unwrap();
+        let mut reassembler = Reassembler::new(FragmentConfig::default());
+        let now = Instant::now();
+
+        let small = frame(100);
+        assert_eq!(vec![small.clone()], split(1, small, 1200).unwrap());
+
+        let large = frame(3000);
+        let datagrams = split(2, large.clone(), 1200).unwrap();
+        assert_eq!(3, datagrams.len());
+        assert!(datagrams.iter().all(|d| d.len() <= 1200));
+
+        // out of order and duplicated
+        let order = [2, 0, 0, 1];
+        let mut results: Vec<_> = order
+            .iter()
+            .map(|i| reassembler.insert(address, &datagrams[*i], now).unwrap())
+            .collect();
+
+        assert_eq!(Some(&large[..]), results.pop().unwrap().as_ref().map(|f| &f[..]));
+        assert!(results.iter().all(Option::is_none));
+        assert_eq!(0, reassembler.buffered());
+    }
+
+    #[test]
+    fn test_limits() {
+        let address: SocketAddr = "10.0.0.1:40102".parse().unwrap();
+        // the same host, from another port and as an IPv4-mapped address
+        let port: SocketAddr = "10.0.0.1:40103".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:40104".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:40102".parse().unwrap();
+        let config = FragmentConfig {
+            max_message_size: 2000,
+            max_peer_buffer: 2100,
+            max_buffer: 3000,
+            ..FragmentConfig::default()
+        };
+        let mut reassembler = Reassembler::new(config.clone());
+        let now = Instant::now();
+
+        let datagrams = split(1, frame(2400), 1000).unwrap();
+        reassembler.insert(address, &datagrams[0], now).unwrap();
+        reassembler.insert(address, &datagrams[1], now).unwrap();
+        assert_eq!(
+            FragmentError::TooLarge,
+            reassembler.insert(address, &datagrams[2], now).unwrap_err()
+        );
+        assert_eq!(0, reassembler.buffered());
+
+        // the oldest messages of the host make room for its new ones
+        let first = split(2, frame(3000), 1000).unwrap();
+        let second = split(3, frame(3000), 1000).unwrap();
+        reassembler.insert(address, &first[0], now).unwrap();
+        reassembler.insert(port, &second[0], now).unwrap();
+        reassembler.insert(mapped, &second[1], now).unwrap();
+        assert_eq!(2000, reassembler.buffered());
+
+        // and the oldest messages of any host for those of another one
+        reassembler.insert(other, &first[0], now).unwrap();
+        reassembler.insert(other, &first[1], now).unwrap();
+        assert_eq!(3000, reassembler.buffered());
+
+        assert_eq!(0, reassembler.expire(now));
+        assert_eq!(2, reassembler.expire(now + config.timeout));
+        assert_eq!(0, reassembler.buffered());
+
+        // a message larger than the memory of a host is dropped
+        let tiny = split(5, frame(2000), 20).unwrap();
+        let results: Vec<_> = tiny.iter().map(|d| reassembler.insert(address, d, now)).collect();
+        assert!(results.contains(&Err(FragmentError::BufferFull)));
+        assert!(reassembler.buffered() <= config.max_peer_buffer);
+        reassembler.expire(now + config.timeout);
+
+        let mut malformed = first[1].to_vec();
+        malformed[HEADER_SZ - 1] = 0;
+        assert_eq!(
+            FragmentError::Malformed,
+            reassembler.insert(address, &malformed, now).unwrap_err()
+        );
+        assert!(split(4, frame(100), HEADER_SZ).is_err());
+    }
+}
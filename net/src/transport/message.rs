@@ -1,14 +1,29 @@
+use std::time::Duration;
 use std::{io, net};
 
 use actix;
 use futures::Future;
 
 use codec::message::Message;
+use codec::SharedCodecState;
+use error::Error;
+use identity::PeerInfo;
+use network::acl::AclEntry;
 use network::*;
+use protocol::ProtocolMessage;
+use network::session::{NodeStats, SessionStats};
+use transport::admission::SharedAdmission;
+use transport::congestion::SharedCongestionStats;
+use transport::firewall::{IpNet, SharedFirewall};
+use transport::replay::ReplayStats;
+use transport::tls::CertFingerprint;
 use transport::*;
 
 pub type NoResult = ();
 pub type EmptyResult = Result<(), actix::MailboxError>;
+pub type StatsResult = Result<SessionStats, actix::MailboxError>;
+pub type ReplayStatsResult = Result<ReplayStats, actix::MailboxError>;
+pub type NodeStatsResult = Result<NodeStats, actix::MailboxError>;
 
 pub type BoxedFuture = Box<Future<Item = (), Error = io::Error>>;
 pub type FutureResult = Result<BoxedFuture, actix::MailboxError>;
@@ -23,15 +38,35 @@ pub struct SendMessage {
     pub transport: TransportProtocol,
     pub address: net::SocketAddr,
     pub message: Message,
+    pub delivery: Delivery,
 }
 
 unsafe impl Send for SendMessage {}
 
+/// Send a message of a registered protocol
+pub struct SendTyped<M>
+where
+    M: ProtocolMessage,
+{
+    pub transport: TransportProtocol,
+    pub address: net::SocketAddr,
+    pub message: M,
+    pub delivery: Delivery,
+}
+
+impl<M> actix::Message for SendTyped<M>
+where
+    M: ProtocolMessage,
+{
+    type Result = EmptyResult;
+}
+
 #[derive(Message, Debug)]
 #[rtype(result = "NoResult")]
 pub struct SessionSendMessage {
     pub address: net::SocketAddr,
     pub message: Message,
+    pub delivery: Delivery,
 }
 
 unsafe impl Send for SessionSendMessage {}
@@ -54,12 +89,84 @@ pub struct Disconnect {
 
 unsafe impl Send for Disconnect {}
 
+/// Refuse an address for `duration`, or until unbanned, and close its
+/// sessions
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "EmptyResult")]
+pub struct Ban {
+    pub address: net::IpAddr,
+    pub duration: Option<Duration>,
+}
+
+unsafe impl Send for Ban {}
+
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "EmptyResult")]
+pub struct Unban {
+    pub address: net::IpAddr,
+}
+
+unsafe impl Send for Unban {}
+
+/// Replace the firewall's allowed and denied address ranges, and close the
+/// sessions of addresses no longer accepted
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "EmptyResult")]
+pub struct SetFirewall {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+unsafe impl Send for SetFirewall {}
+
+/// Restrict, or with `None` open, a protocol id to the senders in `entry`
+#[derive(Message, Debug)]
+#[rtype(result = "EmptyResult")]
+pub struct SetAcl {
+    pub protocol_id: u16,
+    pub entry: Option<AclEntry>,
+}
+
+unsafe impl Send for SetAcl {}
+
+/// Close the session with `address`, reporting `reason`
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "EmptyResult")]
+pub struct Close {
+    pub address: net::SocketAddr,
+    pub reason: DisconnectReason,
+}
+
+unsafe impl Send for Close {}
+
 #[derive(Message, Clone, Debug)]
 #[rtype(result = "EmptyResult")]
 pub struct Stop(pub TransportProtocol);
 
 unsafe impl Send for Stop {}
 
+#[derive(Message, Debug)]
+#[rtype(result = "StatsResult")]
+pub struct GetSessionStats {
+    pub transport: TransportProtocol,
+    pub address: net::SocketAddr,
+}
+
+unsafe impl Send for GetSessionStats {}
+
+#[derive(Message, Debug)]
+#[rtype(result = "NodeStatsResult")]
+pub struct GetNodeStats;
+
+unsafe impl Send for GetNodeStats {}
+
+/// UDP anti-replay counters (UDP exclusive)
+#[derive(Message, Debug)]
+#[rtype(result = "ReplayStatsResult")]
+pub struct GetReplayStats;
+
+unsafe impl Send for GetReplayStats {}
+
 //
 // Events
 //
@@ -74,6 +181,16 @@ pub struct ReceivedMessage {
 
 unsafe impl Send for ReceivedMessage {}
 
+#[derive(Message, Debug)]
+#[rtype(result = "NoResult")]
+pub struct TransportError {
+    pub transport: TransportProtocol,
+    pub address: net::SocketAddr,
+    pub error: Error,
+}
+
+unsafe impl Send for TransportError {}
+
 #[derive(Message)]
 #[rtype(result = "NoResult")]
 pub struct Listening<N>
@@ -83,6 +200,10 @@ where
 {
     pub actor: Transport<N>,
     pub address: net::SocketAddr,
+    /// Firewall the transport filters addresses with
+    pub firewall: SharedFirewall,
+    /// Admission counters the transport's sessions are counted in
+    pub admission: SharedAdmission,
 }
 
 unsafe impl<N> Send for Listening<N>
@@ -118,7 +239,14 @@ where
     pub transport: TransportProtocol,
     pub address: net::SocketAddr,
     pub session: TransportSession<N>,
+    pub codec: SharedCodecState,
     pub initiator: bool,
+    /// TLS peer certificate fingerprint
+    pub fingerprint: Option<CertFingerprint>,
+    /// Authenticated remote node
+    pub peer: PeerInfo,
+    /// Congestion control state of a UDP session
+    pub congestion: Option<SharedCongestionStats>,
 }
 
 unsafe impl<N> Send for Connected<N>
@@ -132,6 +260,39 @@ where
 pub struct Disconnected {
     pub transport: TransportProtocol,
     pub address: net::SocketAddr,
+    pub reason: DisconnectReason,
 }
 
 unsafe impl Send for Disconnected {}
+
+#[derive(Message, Debug)]
+#[rtype(result = "NoResult")]
+pub struct Refused {
+    pub transport: TransportProtocol,
+    pub address: net::SocketAddr,
+    pub reason: RefusalReason,
+}
+
+unsafe impl Send for Refused {}
+
+/// UDP session moved to a new remote address, once validated
+#[derive(Message, Debug)]
+#[rtype(result = "NoResult")]
+pub struct Migrated {
+    pub transport: TransportProtocol,
+    pub from: net::SocketAddr,
+    pub to: net::SocketAddr,
+}
+
+unsafe impl Send for Migrated {}
+
+/// Node announced on the LAN
+#[derive(Message, Debug)]
+#[rtype(result = "NoResult")]
+pub struct PeerDiscovered {
+    /// Address the announcement came from
+    pub address: net::SocketAddr,
+    pub peer: PeerInfo,
+}
+
+unsafe impl Send for PeerDiscovered {}
@@ -1,12 +1,33 @@
+pub mod admission;
+pub mod config;
+pub mod congestion;
+pub mod connection;
+pub mod cookie;
+pub mod discovery;
+pub mod firewall;
+pub mod fragment;
 pub mod message;
+pub mod pmtu;
+pub mod policy;
+pub mod puzzle;
+pub mod reliable;
+pub mod replay;
 pub mod tcp;
+pub mod tls;
 pub mod udp;
 
+#[cfg(test)]
+mod testing;
+
 use std::clone::Clone;
 use std::fmt;
+use std::time::Duration;
 
-use actix::AsyncContext;
+use actix::{Actor, AsyncContext};
 
+use codec::serializer::Serializer;
+use codec::{MessageCodec, SharedCodecState};
+use self::config::TransportConfig;
 use self::tcp::{TcpActorAddr, TcpSessionAddr};
 use self::udp::UdpActorAddr;
 use super::network::Network;
@@ -42,6 +63,92 @@ impl fmt::Display for TransportProtocol {
     }
 }
 
+/// Delivery guarantee of a UDP message. TCP messages are always delivered
+/// reliably and in order.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Delivery {
+    /// Sent once; may be lost, duplicated or reordered
+    Unreliable = 0,
+    /// Retransmitted until acknowledged and delivered once, on arrival;
+    /// requires a session
+    Reliable = 1,
+    /// Reliable, and delivered after every reliable message sent before it
+    Ordered = 2,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery::Unreliable
+    }
+}
+
+/// Why a session was closed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DisconnectReason {
+    /// Connection closed by the remote peer or lost
+    Closed = 0,
+    /// Disconnect requested locally
+    Requested = 1,
+    /// Malformed frames, per the codec error policy
+    ProtocolError = 2,
+    /// Noise handshake failed
+    HandshakeFailed = 3,
+    /// Encryption or Hello handshake did not complete in time
+    HandshakeTimeout = 4,
+    /// Connected to ourselves
+    SelfConnection = 5,
+    /// Hello exchange failed or messages arrived before it completed
+    AuthenticationFailed = 6,
+    /// Frames not authenticated with the private network key
+    Unauthenticated = 7,
+    /// Remote address banned or denied by the firewall
+    Banned = 8,
+    /// A partially received frame did not complete in time
+    ReadTimeout = 9,
+    /// Admission puzzle unsolvable or solved incorrectly
+    PuzzleFailed = 10,
+    /// Message of a protocol the peer is not allowed to use
+    AccessDenied = 11,
+    /// No datagrams received from the remote node in time
+    IdleTimeout = 12,
+}
+
+/// Why an incoming or outgoing connection was refused
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RefusalReason {
+    /// Address denied or banned by the firewall
+    Firewall = 0,
+    /// Session limit reached
+    TooManySessions = 1,
+    /// Per-address session limit reached
+    TooManyFromAddress = 2,
+    /// Too many connections in the handshake phase
+    TooManyHandshakes = 3,
+}
+
+/// Message codec with a serialization backend chosen at runtime
+pub type TransportCodec = MessageCodec<Box<Serializer>>;
+
+impl TransportCodec {
+    pub fn from_config(config: &TransportConfig, state: SharedCodecState) -> Self {
+        let serializer = config.serialization.serializer();
+        MessageCodec::with_state(config.codec.clone(), serializer, state)
+    }
+}
+
+/// Runs `f` every `interval` until the actor stops
+pub fn run_interval<A, F>(ctx: &mut A::Context, interval: Duration, mut f: F)
+where
+    A: Actor,
+    A::Context: AsyncContext<A>,
+    F: FnMut(&mut A, &mut A::Context) + 'static,
+{
+    ctx.run_later(interval, move |act, ctx| {
+        f(act, ctx);
+        run_interval(ctx, interval, f);
+    });
+}
+
 /// Transport router addresses
 pub enum Transport<N>
 where
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::net;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use transport::firewall::canonical;
+
+/// UDP path MTU discovery configuration. The search starts from the
+/// fragmentation MTU, which is never probed below.
+#[derive(Clone, Debug)]
+pub struct PmtuConfig {
+    /// Largest datagram probed
+    pub max_mtu: usize,
+    /// Search stops once the largest acknowledged size and the smallest
+    /// lost one are this close
+    pub resolution: usize,
+    /// Time a probe waits for its acknowledgement
+    pub probe_timeout: Duration,
+    /// Probes of a size sent before the size is deemed too large
+    pub max_probes: u32,
+    /// Time a discovered path MTU is used before the path is searched again
+    pub expiry: Duration,
+    /// Remote addresses with a cached path MTU
+    pub max_cached: usize,
+    /// Retransmission rounds of datagrams larger than the fragmentation
+    /// MTU, with no pause in acknowledged traffic, before the path MTU
+    /// falls back to the fragmentation MTU and the path is searched again
+    pub max_losses: u32,
+}
+
+impl Default for PmtuConfig {
+    fn default() -> Self {
+        PmtuConfig {
+            // Ethernet MTU less IPv4 and UDP headers
+            max_mtu: 1472,
+            resolution: 16,
+            probe_timeout: Duration::from_secs(1),
+            max_probes: 3,
+            expiry: Duration::from_secs(600),
+            max_cached: 4096,
+            max_losses: 3,
+        }
+    }
+}
+
+/// Probe awaiting acknowledgement
+#[derive(Debug)]
+struct PendingProbe {
+    id: u32,
+    size: usize,
+    sent: Instant,
+    attempts: u32,
+}
+
+/// Packetization layer path MTU search (RFC 8899) of a single path: a
+/// binary search between the largest acknowledged probe size and the
+/// smallest lost one
+#[derive(Debug)]
+pub struct PmtuSearch {
+    /// Largest size known to pass
+    low: usize,
+    /// Smallest size known not to pass
+    high: usize,
+    probe: Option<PendingProbe>,
+}
+
+impl PmtuSearch {
+    pub fn new(base: usize, config: &PmtuConfig) -> Self {
+        PmtuSearch {
+            low: base,
+            high: config.max_mtu.max(base) + 1,
+            probe: None,
+        }
+    }
+
+    /// Largest size acknowledged so far
+    pub fn mtu(&self) -> usize {
+        self.low
+    }
+
+    pub fn complete(&self, config: &PmtuConfig) -> bool {
+        self.probe.is_none() && self.high - self.low <= config.resolution
+    }
+
+    /// Probe to send now, as its id and size: a repeat of an unanswered
+    /// one, or a new one once the previous size is acknowledged or lost.
+    /// Acknowledgements are not authenticated, so ids are random rather
+    /// than guessable.
+    pub fn poll(&mut self, now: Instant, config: &PmtuConfig) -> Option<(u32, usize)> {
+        let lost = match self.probe {
+            Some(ref mut probe) => {
+                if now.duration_since(probe.sent) < config.probe_timeout {
+                    return None;
+                }
+                if probe.attempts < config.max_probes {
+                    probe.attempts += 1;
+                    probe.sent = now;
+                    return Some((probe.id, probe.size));
+                }
+                Some(probe.size)
+            }
+            None => None,
+        };
+
+        if let Some(size) = lost {
+            self.high = size;
+            self.probe = None;
+        }
+        if self.high - self.low <= config.resolution {
+            return None;
+        }
+
+        let mut id = [0u8; 4];
+        if SystemRandom::new().fill(&mut id).is_err() {
+            return None;
+        }
+        let id = BigEndian::read_u32(&id);
+        let size = self.low + (self.high - self.low) / 2;
+        self.probe = Some(PendingProbe {
+            id,
+            size,
+            sent: now,
+            attempts: 1,
+        });
+        Some((id, size))
+    }
+
+    /// Raises the path MTU to an acknowledged probe's size, as received;
+    /// returns false for unknown probes
+    pub fn ack(&mut self, id: u32, size: usize) -> bool {
+        let probed = match self.probe {
+            Some(ref probe) if probe.id == id => probe.size,
+            _ => return false,
+        };
+
+        self.low = self.low.max(size.min(probed));
+        self.probe = None;
+        true
+    }
+}
+
+/// Discovered path MTUs, by remote IP address
+pub struct PmtuCache {
+    expiry: Duration,
+    max_cached: usize,
+    entries: HashMap<net::IpAddr, (usize, Instant)>,
+}
+
+impl PmtuCache {
+    pub fn new(config: &PmtuConfig) -> Self {
+        PmtuCache {
+            expiry: config.expiry,
+            max_cached: config.max_cached,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Path MTU to `ip`, unless unknown or expired
+    pub fn get(&self, ip: &net::IpAddr, now: Instant) -> Option<usize> {
+        match self.entries.get(&canonical(*ip)) {
+            Some(&(mtu, expires)) if now < expires => Some(mtu),
+            _ => None,
+        }
+    }
+
+    /// Caches a path MTU, evicting the entry closest to expiry if the
+    /// cache is full
+    pub fn insert(&mut self, ip: net::IpAddr, mtu: usize, now: Instant) {
+        let ip = canonical(ip);
+        if !self.entries.contains_key(&ip) && self.entries.len() >= self.max_cached {
+            self.expire(now);
+        }
+        if !self.entries.contains_key(&ip) && self.entries.len() >= self.max_cached {
+            let oldest = self.entries
+                .iter()
+                .min_by_key(|&(_, &(_, expires))| expires)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        if self.max_cached > 0 {
+            self.entries.insert(ip, (mtu, now + self.expiry));
+        }
+    }
+
+    pub fn remove(&mut self, ip: &net::IpAddr) {
+        self.entries.remove(&canonical(*ip));
+    }
+
+    /// Drops expired entries; returns how many
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(|_, &mut (_, expires)| now < expires);
+        len - self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use transport::pmtu::{PmtuCache, PmtuConfig, PmtuSearch};
+
+    #[test]
+    fn test_search() {
+        let config = PmtuConfig::default();
+        let path = 1400;
+        let mut search = PmtuSearch::new(1200, &config);
+        let mut now = Instant::now();
+        let mut probes = 0;
+
+        // probes up to the path MTU are acknowledged, larger ones lost
+        while !search.complete(&config) {
+            if let Some((id, size)) = search.poll(now, &config) {
+                probes += 1;
+                if size <= path {
+                    assert!(search.ack(id, size));
+                    continue;
+                }
+            }
+            now += config.probe_timeout;
+        }
+
+        assert!(search.mtu() <= path);
+        assert!(path - search.mtu() < config.resolution);
+        assert!(probes < 20);
+        assert_eq!(None, search.poll(now + config.probe_timeout, &config));
+        assert!(!search.ack(0, path));
+
+        // probe ids are not predictable from earlier ones
+        let mut search = PmtuSearch::new(1200, &config);
+        let (first, _) = search.poll(now, &config).unwrap();
+        assert!(!search.ack(first.wrapping_add(1), path));
+        assert!(search.ack(first, path));
+        let (second, _) = search.poll(now, &config).unwrap();
+        assert_ne!(first.wrapping_add(1), second);
+
+        // every probe is lost on a path no larger than the base
+        let mut search = PmtuSearch::new(1200, &config);
+        while !search.complete(&config) {
+            search.poll(now, &config);
+            now += config.probe_timeout;
+        }
+        assert_eq!(1200, search.mtu());
+    }
+
+    #[test]
+    fn test_cache() {
+        let config = PmtuConfig {
+            max_cached: 2,
+            ..PmtuConfig::default()
+        };
+        let mut cache = PmtuCache::new(&config);
+        let now = Instant::now();
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+        let mapped: IpAddr = "::ffff:10.0.0.2".parse().unwrap();
+
+        cache.insert(first, 1400, now);
+        cache.insert(mapped, 1300, now + Duration::from_secs(1));
+        assert_eq!(Some(1400), cache.get(&first, now));
+        assert_eq!(Some(1300), cache.get(&second, now));
+
+        // the entry closest to expiry makes room
+        cache.insert("10.0.0.3".parse().unwrap(), 1472, now);
+        assert_eq!(2, cache.len());
+        assert_eq!(None, cache.get(&first, now));
+        cache.remove(&mapped);
+        assert_eq!(None, cache.get(&second, now));
+        cache.insert(second, 1300, now + Duration::from_secs(1));
+
+        let later = now + config.expiry + Duration::from_secs(1);
+        assert_eq!(None, cache.get(&second, later));
+        assert_eq!(2, cache.expire(later));
+        assert_eq!(0, cache.len());
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Reaction to codec errors on an incoming stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorPolicy {
+    /// Disconnect on the first error
+    Disconnect,
+    /// Disconnect once more than `max_errors` errors occur within `window`
+    Tolerate { max_errors: usize, window: Duration },
+    /// Drop the offending frame or datagram only
+    Drop,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        ErrorPolicy::Disconnect
+    }
+}
+
+/// Applies an error policy to a single session
+#[derive(Debug)]
+pub struct ErrorTracker {
+    policy: ErrorPolicy,
+    errors: VecDeque<Instant>,
+}
+
+impl ErrorTracker {
+    pub fn new(policy: ErrorPolicy) -> Self {
+        ErrorTracker {
+            policy,
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// Records an error. Returns whether the session should stay open.
+    pub fn record(&mut self, now: Instant) -> bool {
+        match self.policy {
+            ErrorPolicy::Disconnect => false,
+            ErrorPolicy::Drop => true,
+            ErrorPolicy::Tolerate { max_errors, window } => {
+                while let Some(&first) = self.errors.front() {
+                    if now.duration_since(first) <= window {
+                        break;
+                    }
+                    self.errors.pop_front();
+                }
+
+                self.errors.push_back(now);
+                self.errors.len() <= max_errors
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use transport::policy::{ErrorPolicy, ErrorTracker};
+
+    #[test]
+    fn test_disconnect() {
+        let mut tracker = ErrorTracker::new(ErrorPolicy::Disconnect);
+        assert!(!tracker.record(Instant::now()));
+    }
+
+    #[test]
+    fn test_drop() {
+        let mut tracker = ErrorTracker::new(ErrorPolicy::Drop);
+        let now = Instant::now();
+        assert!((0..100).all(|_| tracker.record(now)));
+    }
+
+    #[test]
+    fn test_tolerate() {
+        let window = Duration::from_secs(10);
+        let mut tracker = ErrorTracker::new(ErrorPolicy::Tolerate {
+            max_errors: 2,
+            window,
+        });
+
+        let now = Instant::now();
+        assert!(tracker.record(now));
+        assert!(tracker.record(now + Duration::from_secs(1)));
+        assert!(!tracker.record(now + Duration::from_secs(2)));
+        // errors older than the window are forgotten
+        assert!(tracker.record(now + Duration::from_secs(13)));
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::sync::oneshot;
+use ring::digest;
+
+/// Highest difficulty a node attempts to solve, in leading zero bits
+pub const MAX_DIFFICULTY: u8 = 32;
+
+/// Hashcash-style admission puzzle configuration
+#[derive(Clone, Debug)]
+pub struct PuzzleConfig {
+    /// Load, as a fraction of the admission limits, above which connecting
+    /// peers are challenged
+    pub load_threshold: f64,
+    /// Difficulty at the threshold, in leading zero bits
+    pub min_difficulty: u8,
+    /// Difficulty at full load
+    pub max_difficulty: u8,
+}
+
+impl Default for PuzzleConfig {
+    fn default() -> Self {
+        PuzzleConfig {
+            load_threshold: 0.5,
+            min_difficulty: 8,
+            max_difficulty: 20,
+        }
+    }
+}
+
+impl PuzzleConfig {
+    /// Difficulty required at `load`; 0 when no puzzle is required
+    pub fn difficulty(&self, load: f64) -> u8 {
+        if load < self.load_threshold {
+            return 0;
+        }
+
+        let min = self.min_difficulty.min(MAX_DIFFICULTY);
+        let max = self.max_difficulty.min(MAX_DIFFICULTY).max(min);
+        let span = (1.0 - self.load_threshold).max(::std::f64::EPSILON);
+        let scale = ((load - self.load_threshold) / span).min(1.0);
+
+        min + (f64::from(max - min) * scale).round() as u8
+    }
+}
+
+fn leading_zeros(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// Checks whether SHA-256(challenge || nonce) starts with `difficulty`
+/// zero bits
+pub fn verify(challenge: &[u8], difficulty: u8, nonce: u64) -> bool {
+    let mut encoded = [0u8; 8];
+    BigEndian::write_u64(&mut encoded, nonce);
+
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(challenge);
+    context.update(&encoded);
+    leading_zeros(context.finish().as_ref()) >= u32::from(difficulty)
+}
+
+/// Finds a nonce solving the challenge; takes 2^difficulty attempts on
+/// average
+pub fn solve(challenge: &[u8], difficulty: u8) -> u64 {
+    search(challenge, difficulty, &AtomicBool::new(false)).unwrap_or(0)
+}
+
+/// Attempts between checks of the cancellation flag
+const CANCEL_CHECK: u64 = 4096;
+
+fn search(challenge: &[u8], difficulty: u8, cancelled: &AtomicBool) -> Option<u64> {
+    for nonce in 0.. {
+        if nonce % CANCEL_CHECK == 0 && cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        if verify(challenge, difficulty, nonce) {
+            return Some(nonce);
+        }
+    }
+    None
+}
+
+/// Solves puzzles off the actor thread, on a bounded number of threads
+/// shared by the transports cloning it
+#[derive(Clone, Debug)]
+pub struct PuzzleSolver {
+    running: Arc<AtomicUsize>,
+    max_threads: usize,
+}
+
+impl Default for PuzzleSolver {
+    fn default() -> Self {
+        PuzzleSolver::new(2)
+    }
+}
+
+impl PuzzleSolver {
+    pub fn new(max_threads: usize) -> Self {
+        PuzzleSolver {
+            running: Arc::new(AtomicUsize::new(0)),
+            max_threads,
+        }
+    }
+
+    /// Starts solving on a separate thread; `None` if every thread is busy.
+    /// The search stops once the returned handle is dropped.
+    pub fn solve(&self, challenge: Vec<u8>, difficulty: u8) -> Option<(oneshot::Receiver<u64>, SolverHandle)> {
+        let mut running = self.running.load(Ordering::SeqCst);
+        loop {
+            if running >= self.max_threads {
+                return None;
+            }
+            let reserved = self.running.compare_exchange(running, running + 1, Ordering::SeqCst, Ordering::SeqCst);
+            match reserved {
+                Ok(_) => break,
+                Err(current) => running = current,
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = SolverHandle(cancelled.clone());
+        let worker = Worker(self.running.clone());
+
+        thread::spawn(move || {
+            let nonce = search(&challenge, difficulty, &cancelled);
+            // the thread is free before the result is out
+            drop(worker);
+            if let Some(nonce) = nonce {
+                tx.send(nonce).ok();
+            }
+        });
+
+        Some((rx, handle))
+    }
+}
+
+/// Releases a solver thread when the search ends, even if it panics
+struct Worker(Arc<AtomicUsize>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Search in progress; cancelled when dropped
+#[derive(Debug)]
+pub struct SolverHandle(Arc<AtomicBool>);
+
+impl Drop for SolverHandle {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use transport::puzzle::{solve, verify, PuzzleConfig, PuzzleSolver};
+
+    #[test]
+    fn test_difficulty() {
+        let config = PuzzleConfig::default();
+
+        assert_eq!(0, config.difficulty(0.2));
+        assert_eq!(config.min_difficulty, config.difficulty(0.5));
+        assert_eq!(14, config.difficulty(0.75));
+        assert_eq!(config.max_difficulty, config.difficulty(1.0));
+        assert_eq!(config.max_difficulty, config.difficulty(3.0));
+    }
+
+    #[test]
+    fn test_solve() {
+        let challenge = [7u8; 32];
+        let nonce = solve(&challenge, 12);
+
+        assert!(verify(&challenge, 12, nonce));
+        assert!(verify(&challenge, 0, nonce + 1));
+        // the first solution found is the smallest one
+        assert!((0..nonce).all(|n| !verify(&challenge, 12, n)));
+    }
+
+    #[test]
+    fn test_solver() {
+        let solver = PuzzleSolver::new(1);
+        let challenge = vec![7u8; 32];
+
+        let (rx, _handle) = solver.solve(challenge.clone(), 12).unwrap();
+        assert_eq!(solve(&challenge, 12), rx.wait().unwrap());
+
+        // one thread, busy with an unsolvable search until cancelled
+        let (rx, handle) = solver.solve(challenge.clone(), 64).unwrap();
+        assert!(solver.solve(challenge.clone(), 1).is_none());
+
+        drop(handle);
+        assert!(rx.wait().is_err());
+        assert!(solver.solve(challenge, 1).is_some());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use codec::message::{Ack, Message, Payload, Reliable};
+
+/// Number of sequence numbers covered by the selective acknowledgement
+/// bitmap
+const SELECTIVE_SZ: u64 = 64;
+
+/// Reliable UDP delivery configuration
+#[derive(Clone, Debug)]
+pub struct ReliableConfig {
+    /// Retransmission timeout before the first round-trip time sample
+    pub initial_rto: Duration,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    /// Retransmissions of a message before the session is deemed lost
+    pub max_retransmissions: u32,
+    /// Messages sent and not acknowledged yet, per session
+    pub max_in_flight: usize,
+    /// Sequence numbers accepted ahead of the first missing one
+    pub window: u64,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        ReliableConfig {
+            initial_rto: Duration::from_secs(1),
+            min_rto: Duration::from_millis(200),
+            max_rto: Duration::from_secs(10),
+            max_retransmissions: 8,
+            max_in_flight: 1024,
+            window: 1024,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReliableError {
+    /// Too many messages awaiting acknowledgement
+    TooManyInFlight,
+    /// Message with this sequence number retransmitted too many times
+    Undelivered(u64),
+}
+
+impl fmt::Display for ReliableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Round-trip time estimator (RFC 6298)
+#[derive(Debug)]
+struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    fn new(config: &ReliableConfig) -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: config.initial_rto,
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration, config: &ReliableConfig) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let delta = match srtt > rtt {
+                    true => srtt - rtt,
+                    false => rtt - srtt,
+                };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rttvar * 4).max(config.min_rto).min(config.max_rto);
+    }
+}
+
+/// Message awaiting acknowledgement
+#[derive(Debug)]
+struct InFlight {
+    message: Message,
+    sent: Instant,
+    retransmissions: u32,
+}
+
+/// Sending half of a reliable UDP session
+#[derive(Debug)]
+pub struct ReliableSender {
+    config: ReliableConfig,
+    next: u64,
+    in_flight: BTreeMap<u64, InFlight>,
+    rtt: RttEstimator,
+}
+
+impl ReliableSender {
+    pub fn new(config: ReliableConfig) -> Self {
+        ReliableSender {
+            rtt: RttEstimator::new(&config),
+            config,
+            next: 0,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    /// Current retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rtt.rto
+    }
+
+    /// Smoothed round-trip time, once measured
+    pub fn srtt(&self) -> Option<Duration> {
+        self.rtt.srtt
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Wraps a message for reliable delivery and keeps it for
+    /// retransmission
+    pub fn send(&mut self, message: Payload, ordered: bool, now: Instant) -> Result<Message, ReliableError> {
+        if self.in_flight.len() >= self.config.max_in_flight {
+            return Err(ReliableError::TooManyInFlight);
+        }
+
+        let reliable = Message::Reliable(Reliable {
+            sequence: self.next,
+            ordered,
+            message,
+        });
+
+        self.in_flight.insert(
+            self.next,
+            InFlight {
+                message: reliable.clone(),
+                sent: now,
+                retransmissions: 0,
+            },
+        );
+        self.next += 1;
+        Ok(reliable)
+    }
+
+    /// Forgets acknowledged messages and measures the round-trip time of
+    /// those sent once. Returns the number of newly acknowledged messages.
+    pub fn ack(&mut self, ack: &Ack, now: Instant) -> usize {
+        let mut acked: Vec<u64> = self.in_flight
+            .range(..ack.cumulative)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+
+        acked.extend(
+            (0..SELECTIVE_SZ)
+                .filter(|&i| ack.selective & (1u64 << i) != 0)
+                .map(|i| ack.cumulative.saturating_add(1 + i)),
+        );
+
+        let mut count = 0;
+        for sequence in acked {
+            let in_flight = match self.in_flight.remove(&sequence) {
+                Some(in_flight) => in_flight,
+                None => continue,
+            };
+            // retransmitted messages give ambiguous samples (Karn)
+            if in_flight.retransmissions == 0 {
+                self.rtt.sample(now.duration_since(in_flight.sent), &self.config);
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// Returns the messages due for retransmission, backing off
+    /// exponentially
+    pub fn poll(&mut self, now: Instant) -> Result<Vec<Message>, ReliableError> {
+        let rto = self.rtt.rto;
+        let config = &self.config;
+        let mut due = Vec::new();
+
+        for (sequence, in_flight) in self.in_flight.iter_mut() {
+            let backoff = 1u32 << in_flight.retransmissions.min(16);
+            let timeout = (rto * backoff).min(config.max_rto);
+            if now.duration_since(in_flight.sent) < timeout {
+                continue;
+            }
+
+            if in_flight.retransmissions >= config.max_retransmissions {
+                return Err(ReliableError::Undelivered(*sequence));
+            }
+
+            in_flight.sent = now;
+            in_flight.retransmissions += 1;
+            due.push(in_flight.message.clone());
+        }
+
+        Ok(due)
+    }
+}
+
+/// Receiving half of a reliable UDP session
+#[derive(Debug)]
+pub struct ReliableReceiver {
+    window: u64,
+    /// First sequence number not received yet
+    cumulative: u64,
+    /// Sequence numbers received past `cumulative`
+    received: BTreeSet<u64>,
+    /// Ordered messages waiting for earlier ones
+    held: BTreeMap<u64, Payload>,
+}
+
+impl ReliableReceiver {
+    pub fn new(config: &ReliableConfig) -> Self {
+        ReliableReceiver {
+            window: config.window,
+            cumulative: 0,
+            received: BTreeSet::new(),
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the messages deliverable after receiving `reliable`;
+    /// duplicates and messages beyond the window yield none
+    pub fn receive(&mut self, reliable: Reliable) -> Vec<Payload> {
+        let sequence = reliable.sequence;
+        if sequence < self.cumulative
+            || sequence >= self.cumulative.saturating_add(self.window)
+            || !self.received.insert(sequence)
+        {
+            return Vec::new();
+        }
+
+        let mut delivered = Vec::new();
+        match reliable.ordered {
+            true => {
+                self.held.insert(sequence, reliable.message);
+            }
+            false => delivered.push(reliable.message),
+        }
+
+        while self.received.remove(&self.cumulative) {
+            self.cumulative += 1;
+        }
+
+        let cumulative = self.cumulative;
+        let released: Vec<u64> = self.held.range(..cumulative).map(|(s, _)| *s).collect();
+        for sequence in released {
+            if let Some(message) = self.held.remove(&sequence) {
+                delivered.push(message);
+            }
+        }
+
+        delivered
+    }
+
+    pub fn ack(&self) -> Ack {
+        let selective = self.received
+            .range(self.cumulative + 1..self.cumulative.saturating_add(1 + SELECTIVE_SZ))
+            .fold(0u64, |bits, s| bits | 1u64 << (s - self.cumulative - 1));
+
+        Ack {
+            cumulative: self.cumulative,
+            selective,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use codec::message::{Ack, Encapsulated, Message, Payload, Reliable};
+    use transport::reliable::{ReliableConfig, ReliableError, ReliableReceiver, ReliableSender};
+
+    fn reliable(message: Message) -> Reliable {
+        match message {
+            Message::Reliable(reliable) => reliable,
+            _ => panic!("not a reliable message"),
+        }
+    }
+
+    fn sequences(payloads: &[Payload]) -> Vec<u16> {
+        payloads
+            .iter()
+            .map(|p| match *p {
+                Payload::Encapsulated(ref e) => e.protocol_id,
+                _ => panic!("unexpected payload"),
+            })
+            .collect()
+    }
+
+    fn payload(n: u16) -> Payload {
+        Payload::Encapsulated(Encapsulated {
+            protocol_id: n,
+            message: Bytes::new(),
+        })
+    }
+
+    #[test]
+    fn test_receive() {
+        let config = ReliableConfig::default();
+        let mut sender = ReliableSender::new(config.clone());
+        let mut receiver = ReliableReceiver::new(&config);
+        let now = Instant::now();
+
+        let sent: Vec<Reliable> = (0..4)
+            .map(|n| reliable(sender.send(payload(n), n != 2, now).unwrap()))
+            .collect();
+
+        // 2 is unordered and delivered on arrival, 3 waits for 0 and 1
+        assert_eq!(vec![2], sequences(&receiver.receive(sent[2].clone())));
+        assert!(receiver.receive(sent[3].clone()).is_empty());
+        assert!(receiver.receive(sent[1].clone()).is_empty());
+        assert_eq!(Ack { cumulative: 0, selective: 0b111 }, receiver.ack());
+
+        assert_eq!(vec![0, 1, 3], sequences(&receiver.receive(sent[0].clone())));
+        assert!(receiver.receive(sent[1].clone()).is_empty());
+        assert_eq!(Ack { cumulative: 4, selective: 0 }, receiver.ack());
+
+        assert_eq!(4, sender.ack(&receiver.ack(), now));
+        assert_eq!(0, sender.in_flight());
+    }
+
+    #[test]
+    fn test_retransmit() {
+        let config = ReliableConfig {
+            max_retransmissions: 2,
+            ..ReliableConfig::default()
+        };
+        let mut sender = ReliableSender::new(config.clone());
+        let start = Instant::now();
+
+        for n in 0..3 {
+            sender.send(payload(n), false, start).unwrap();
+        }
+        assert!(sender.poll(start).unwrap().is_empty());
+
+        // 1 acknowledged selectively, after 100 ms
+        sender.ack(&Ack { cumulative: 0, selective: 0b1 }, start + Duration::from_millis(100));
+        assert_eq!(Some(Duration::from_millis(100)), sender.srtt());
+        assert_eq!(Duration::from_millis(300), sender.rto());
+
+        let first = start + Duration::from_millis(300);
+        assert_eq!(2, sender.poll(first).unwrap().len());
+        // backed off to twice the timeout
+        assert!(sender.poll(first + Duration::from_millis(500)).unwrap().is_empty());
+        assert_eq!(2, sender.poll(first + Duration::from_millis(600)).unwrap().len());
+
+        sender.ack(&Ack { cumulative: 1, selective: 0 }, first);
+        assert_eq!(1, sender.in_flight());
+        assert_eq!(
+            ReliableError::Undelivered(2),
+            sender.poll(first + Duration::from_secs(2)).unwrap_err()
+        );
+    }
+}
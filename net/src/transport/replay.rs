@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::net;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use codec::Sequence;
+
+/// Anti-replay configuration for UDP datagrams. Sequences are only
+/// authenticated by private network tags, so a pre-shared key is required:
+/// without one, anyone can forge a fresh sequence.
+#[derive(Clone, Debug)]
+pub struct ReplayConfig {
+    /// Number of most recent sequence numbers tracked per peer; rounded up
+    /// to a multiple of 64
+    pub window: u64,
+    /// Maximum difference between a datagram timestamp and the local clock
+    pub max_skew: Duration,
+    /// Maximum number of addresses without a session tracked; the least
+    /// recently seen one is forgotten first. Addresses of sessions are
+    /// always tracked.
+    pub max_peers: usize,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            window: 1024,
+            max_skew: Duration::from_secs(30),
+            max_peers: 4096,
+        }
+    }
+}
+
+/// Dropped datagram counters
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReplayStats {
+    /// Sequence number already seen
+    pub replayed: u64,
+    /// Timestamp outside the clock skew tolerance or sequence number older
+    /// than the window
+    pub stale: u64,
+    /// Datagram without a sequence number
+    pub unsequenced: u64,
+}
+
+fn millis(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + u64::from(d.subsec_nanos() / 1_000_000),
+        Err(_) => 0,
+    }
+}
+
+/// Sliding window of received sequence numbers (RFC 6479). Bit `n % size`
+/// records whether `n` was received, for the `size` numbers up to `highest`.
+struct ReplayWindow {
+    highest: u64,
+    bitmap: Vec<u64>,
+    last_seen: Instant,
+    /// Held by an established session, and never evicted
+    pinned: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Check {
+    Accepted,
+    Replayed,
+    Stale,
+}
+
+impl ReplayWindow {
+    fn new(number: u64, words: usize, now: Instant) -> Self {
+        let mut window = ReplayWindow {
+            highest: number,
+            bitmap: vec![0; words],
+            last_seen: now,
+            pinned: false,
+        };
+        window.set(number);
+        window
+    }
+
+    fn size(&self) -> u64 {
+        self.bitmap.len() as u64 * 64
+    }
+
+    fn position(&self, number: u64) -> (usize, u64) {
+        let bit = number % self.size();
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+
+    fn set(&mut self, number: u64) {
+        let (word, mask) = self.position(number);
+        self.bitmap[word] |= mask;
+    }
+
+    fn check(&mut self, number: u64, now: Instant) -> Check {
+        if number > self.highest {
+            // forget the numbers sliding out of the window
+            let advance = (number - self.highest).min(self.size());
+            for n in number - advance + 1..number + 1 {
+                let (word, mask) = self.position(n);
+                self.bitmap[word] &= !mask;
+            }
+            self.highest = number;
+        } else if self.highest - number >= self.size() {
+            return Check::Stale;
+        } else {
+            let (word, mask) = self.position(number);
+            if self.bitmap[word] & mask != 0 {
+                return Check::Replayed;
+            }
+        }
+
+        self.set(number);
+        self.last_seen = now;
+        Check::Accepted
+    }
+}
+
+/// Per-peer datagram sequencing and replay detection. Source addresses
+/// are not authenticated, so windows of sessions are kept apart from those
+/// of other addresses, which spoofed datagrams could churn.
+pub struct ReplayGuard {
+    config: ReplayConfig,
+    windows: HashMap<net::SocketAddr, ReplayWindow>,
+    /// Last sequence number sent, to any address
+    counter: u64,
+    stats: ReplayStats,
+}
+
+impl ReplayGuard {
+    pub fn new(config: ReplayConfig) -> Self {
+        ReplayGuard {
+            config,
+            windows: HashMap::new(),
+            counter: 0,
+            stats: ReplayStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+
+    /// Sequence of the next datagram sent. A single counter increases
+    /// for every address, so no state is kept per destination.
+    pub fn next(&mut self, now: SystemTime) -> Sequence {
+        let timestamp = millis(now);
+        // the counter follows the clock (in microseconds) so that it keeps
+        // increasing across restarts
+        self.counter = self.counter.max(timestamp.saturating_mul(1000)) + 1;
+
+        Sequence {
+            number: self.counter,
+            timestamp,
+        }
+    }
+
+    /// Records a datagram received from `address`. Returns whether it
+    /// should be accepted.
+    pub fn check(
+        &mut self,
+        address: net::SocketAddr,
+        sequence: Option<Sequence>,
+        now: SystemTime,
+    ) -> bool {
+        let sequence = match sequence {
+            Some(sequence) => sequence,
+            None => {
+                self.stats.unsequenced += 1;
+                return false;
+            }
+        };
+
+        let local = millis(now);
+        let skew = self.config.max_skew.as_secs() * 1000
+            + u64::from(self.config.max_skew.subsec_nanos() / 1_000_000);
+        if sequence.timestamp.max(local) - sequence.timestamp.min(local) > skew {
+            self.stats.stale += 1;
+            return false;
+        }
+
+        let instant = Instant::now();
+        if !self.windows.contains_key(&address) {
+            self.make_room();
+            let words = ((self.config.window.max(1) + 63) / 64) as usize;
+            let window = ReplayWindow::new(sequence.number, words, instant);
+            self.windows.insert(address, window);
+            return true;
+        }
+
+        let check = match self.windows.get_mut(&address) {
+            Some(window) => window.check(sequence.number, instant),
+            None => Check::Accepted,
+        };
+
+        match check {
+            Check::Accepted => true,
+            Check::Replayed => {
+                self.stats.replayed += 1;
+                false
+            }
+            Check::Stale => {
+                self.stats.stale += 1;
+                false
+            }
+        }
+    }
+
+    /// Keeps the window of an established session until `release`d
+    pub fn pin(&mut self, address: net::SocketAddr) {
+        if let Some(window) = self.windows.get_mut(&address) {
+            window.pinned = true;
+        }
+    }
+
+    /// Forgets the window of a closed session
+    pub fn release(&mut self, address: net::SocketAddr) {
+        self.windows.remove(&address);
+    }
+
+    /// Moves the state of a peer to its new address
+    pub fn migrate(&mut self, from: net::SocketAddr, to: net::SocketAddr) {
+        if let Some(window) = self.windows.remove(&from) {
+            self.windows.insert(to, window);
+        }
+    }
+
+    fn make_room(&mut self) {
+        let unpinned = self.windows.values().filter(|w| !w.pinned).count();
+        if unpinned < self.config.max_peers {
+            return;
+        }
+
+        let oldest = self
+            .windows
+            .iter()
+            .filter(|&(_, w)| !w.pinned)
+            .min_by_key(|&(_, w)| w.last_seen)
+            .map(|(address, _)| *address);
+
+        if let Some(address) = oldest {
+            self.windows.remove(&address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+
+    use codec::Sequence;
+    use transport::replay::{ReplayConfig, ReplayGuard};
+
+    fn sequence(number: u64, now: SystemTime) -> Option<Sequence> {
+        let mut sequence = ReplayGuard::new(ReplayConfig::default()).next(now);
+        sequence.number = number;
+        Some(sequence)
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:40102".parse().unwrap()
+    }
+
+    #[test]
+    fn test_window() {
+        let config = ReplayConfig {
+            window: 64,
+            ..ReplayConfig::default()
+        };
+        let mut guard = ReplayGuard::new(config);
+        let now = SystemTime::now();
+
+        assert!(guard.check(addr(), sequence(100, now), now));
+        assert!(!guard.check(addr(), sequence(100, now), now));
+        // out of order, within the window
+        assert!(guard.check(addr(), sequence(90, now), now));
+        assert!(guard.check(addr(), sequence(150, now), now));
+        assert!(!guard.check(addr(), sequence(90, now), now));
+        // slid out of the window
+        assert!(!guard.check(addr(), sequence(80, now), now));
+        assert!(guard.check(addr(), sequence(1000, now), now));
+        assert!(!guard.check(addr(), sequence(1000, now), now));
+
+        let stats = guard.stats();
+        assert_eq!(3, stats.replayed);
+        assert_eq!(1, stats.stale);
+    }
+
+    #[test]
+    fn test_skew() {
+        let mut guard = ReplayGuard::new(ReplayConfig::default());
+        let now = SystemTime::now();
+        let past = now - Duration::from_secs(60);
+
+        assert!(!guard.check(addr(), sequence(1, past), now));
+        assert!(!guard.check(addr(), None, now));
+        assert!(guard.check(addr(), sequence(1, now), now));
+
+        let stats = guard.stats();
+        assert_eq!(1, stats.stale);
+        assert_eq!(1, stats.unsequenced);
+    }
+
+    #[test]
+    fn test_next() {
+        let mut guard = ReplayGuard::new(ReplayConfig::default());
+        let now = SystemTime::now();
+
+        let first = guard.next(now);
+        let second = guard.next(now);
+        assert_eq!(first.number + 1, second.number);
+
+        // a restarted sender keeps producing fresh sequence numbers
+        let mut restarted = ReplayGuard::new(ReplayConfig::default());
+        let later = now + Duration::from_millis(1);
+        assert!(restarted.next(later).number > second.number);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut guard = ReplayGuard::new(ReplayConfig::default());
+        let now = SystemTime::now();
+        let moved: SocketAddr = "127.0.0.1:40103".parse().unwrap();
+
+        assert!(guard.check(addr(), sequence(100, now), now));
+
+        guard.migrate(addr(), moved);
+        assert!(!guard.check(moved, sequence(100, now), now));
+        assert!(guard.check(addr(), sequence(100, now), now));
+    }
+
+    #[test]
+    fn test_pinned() {
+        let config = ReplayConfig {
+            max_peers: 2,
+            ..ReplayConfig::default()
+        };
+        let mut guard = ReplayGuard::new(config);
+        let now = SystemTime::now();
+
+        assert!(guard.check(addr(), sequence(100, now), now));
+        guard.pin(addr());
+
+        // datagrams from other addresses, spoofed or not, evict each other
+        for port in 50000..50010 {
+            let spoofed = SocketAddr::new(addr().ip(), port);
+            assert!(guard.check(spoofed, sequence(100, now), now));
+        }
+        assert!(!guard.check(addr(), sequence(100, now), now));
+
+        // a closed session starts over
+        guard.release(addr());
+        assert!(guard.check(addr(), sequence(100, now), now));
+    }
+}
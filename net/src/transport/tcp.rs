@@ -1,27 +1,70 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::{error, net};
-use std::time::Duration;
 
+use actix::fut;
 use actix::io::{FramedWrite, WriteHandler};
 use actix::prelude::*;
 use actix::Unsync;
+use bytes::Bytes;
 use futures::stream::Stream;
 use futures::{future, Future};
 use tokio_codec::FramedRead;
 use tokio_io::io::WriteHalf;
-use tokio_io::AsyncRead;
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_tcp::{TcpListener, TcpStream};
 
-use error::Error;
+use error::{Error, ErrorKind, ErrorSeverity};
+use identity::{self, HelloTranscript, PeerId, PeerInfo, NONCE_SZ};
+use codec::compression::Compression;
 use codec::error::CodecError;
-use codec::message::Message;
-use codec::MessageCodec;
+use codec::message::{Capabilities, Hello, HelloAck, Message, Puzzle, Solution};
+use codec::noise::NoiseHandshake;
+use codec::psk::PskSession;
+use codec::{CodecState, SharedCodecState};
 use network::*;
+use transport::admission::{AdmissionSlot, Admittance, Challenge, SharedAdmission};
+use transport::config::TransportConfig;
 use transport::message::*;
+use transport::policy::ErrorTracker;
+use transport::puzzle::{self, SolverHandle, MAX_DIFFICULTY};
+use transport::tls::{CertFingerprint, TlsContext};
 use transport::*;
 
 pub type TcpActorAddr<N> = Addr<Unsync, TcpTransport<N>>;
 pub type TcpSessionAddr<N> = Addr<Unsync, TcpSession<N>>;
 
+/// Shortest interval of stalled frame checks, in milliseconds, however
+/// short the read timeout
+const MIN_READ_CHECK_MILLIS: u64 = 100;
+
+/// Byte stream underlying a session: a plain or a TLS wrapped TCP stream
+pub trait SessionStream: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> SessionStream for T {}
+
+/// Byte stream of a session, and what the TLS handshake established, if
+/// there was one
+pub struct EstablishedStream {
+    pub stream: Box<SessionStream>,
+    /// Peer certificate fingerprint, if the peer presented one
+    pub fingerprint: Option<CertFingerprint>,
+    /// Keying material exported from the TLS session, identical on both
+    /// ends; empty without TLS
+    pub binding: Vec<u8>,
+}
+
+impl EstablishedStream {
+    fn plain(stream: TcpStream) -> Self {
+        EstablishedStream {
+            stream: Box::new(stream),
+            fingerprint: None,
+            binding: Vec::new(),
+        }
+    }
+}
+
 /// Session creation message (TCP exclusive)
 #[derive(Debug, Message)]
 struct CreateSession {
@@ -29,6 +72,10 @@ struct CreateSession {
     initiator: bool,
 }
 
+/// Admission puzzle solution, computed off the actor thread
+#[derive(Debug, Message)]
+struct PuzzleSolved(u64);
+
 /// TCP transport actor
 pub struct TcpTransport<N>
 where
@@ -41,6 +88,12 @@ where
     pub network: NetAddr<N>,
     /// Own actor address
     pub actor: TcpActorAddr<N>,
+    /// Transport configuration
+    pub config: TransportConfig,
+    /// TLS client and server configurations
+    tls: Option<TlsContext>,
+    /// Connection counters
+    admission: SharedAdmission,
 }
 
 impl<N> TcpTransport<N>
@@ -51,6 +104,7 @@ where
     pub fn run(
         network: NetAddr<N>,
         address: net::SocketAddr,
+        mut config: TransportConfig,
     ) -> Result<TcpActorAddr<N>, Box<error::Error>> {
         let listener = match TcpListener::bind(&address) {
             Ok(l) => l,
@@ -59,6 +113,14 @@ where
 
         // store the actual IP address and port
         let address = listener.local_addr()?;
+        config.listen_ports.tcp = Some(address.port());
+
+        let tls = match config.tls {
+            Some(ref tls) => Some(TlsContext::new(tls)?),
+            None => None,
+        };
+
+        let admission = config.admission.clone();
 
         let router = TcpTransport::create(move |ctx| {
             let flow = listener
@@ -81,6 +143,9 @@ where
                 address,
                 network,
                 actor: ctx.address(),
+                config,
+                tls,
+                admission,
             }
         });
 
@@ -88,6 +153,121 @@ where
     }
 }
 
+impl<N> TcpTransport<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    fn refuse(&self, address: net::SocketAddr, reason: RefusalReason) {
+        eprintln!("TCP: connection with {} refused: {:?}", address, reason);
+
+        let msg = Refused {
+            transport: TransportProtocol::Tcp,
+            address,
+            reason,
+        };
+
+        let future = self.network.send(msg)
+            .map_err(|_| eprintln!("TCP: failed to send 'Refused' event"));
+
+        Arbiter::handle().spawn(future);
+    }
+
+    /// Runs the connection and TLS handshake of a session, abandoning them
+    /// after the handshake timeout. Dropping the future closes the stream
+    /// and releases the admission slot, so stalled peers cannot hold slots.
+    fn spawn_handshake<F>(&self, address: net::SocketAddr, future: F, ctx: &mut Context<Self>)
+    where
+        F: Future<Item = (), Error = ()> + 'static,
+    {
+        let done = Rc::new(Cell::new(false));
+        let finished = done.clone();
+        let future = future.then(move |result| {
+            finished.set(true);
+            result
+        });
+
+        let handle = ctx.spawn(fut::wrap_future(future));
+        let network = self.network.clone();
+        ctx.run_later(self.config.handshake_timeout, move |_, ctx| {
+            if done.get() {
+                return;
+            }
+
+            eprintln!("TCP: connection with {} timed out before the session started", address);
+            ctx.cancel_future(handle);
+
+            let message = "connection or TLS handshake timed out";
+            let msg = TransportError {
+                transport: TransportProtocol::Tcp,
+                address,
+                error: Error::new(ErrorKind::Network, ErrorSeverity::Medium, message),
+            };
+            let future = network
+                .send(msg)
+                .map_err(|_| eprintln!("TCP: failed to send 'Error' event"));
+
+            Arbiter::handle().spawn(future);
+        });
+    }
+}
+
+fn auth_error(message: &str) -> Error {
+    Error::new(ErrorKind::Network, ErrorSeverity::Medium, message)
+}
+
+/// Starts a session over an established TCP stream, after a TLS handshake
+/// if TLS is configured
+fn start_session<N>(
+    network: NetAddr<N>,
+    address: net::SocketAddr,
+    stream: TcpStream,
+    initiator: bool,
+    config: TransportConfig,
+    tls: Option<TlsContext>,
+    admittance: Admittance,
+) -> Box<Future<Item = (), Error = ()>>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    let tls = match tls {
+        Some(tls) => tls,
+        None => {
+            let stream = EstablishedStream::plain(stream);
+            TcpSession::run(network, address, stream, initiator, config, admittance);
+            return Box::new(future::ok(()));
+        }
+    };
+
+    let future = tls.handshake(stream, initiator).then(move |result| {
+        match result {
+            Ok(stream) => {
+                TcpSession::run(network, address, stream, initiator, config, admittance);
+            }
+            Err(e) => {
+                eprintln!("TCP: TLS handshake with {} failed: {}", address, e);
+
+                let message = format!("TLS handshake failed: {}", e);
+                let msg = TransportError {
+                    transport: TransportProtocol::Tcp,
+                    address,
+                    error: Error::new(ErrorKind::Network, ErrorSeverity::Medium, &message),
+                };
+
+                let future = network
+                    .send(msg)
+                    .map_err(|_| eprintln!("TCP: failed to send 'Error' event"));
+
+                Arbiter::handle().spawn(future);
+            }
+        };
+        future::ok(())
+    });
+
+    Box::new(future)
+}
+
 impl<N> Actor for TcpTransport<N>
 where
     N: Network + 'static,
@@ -98,7 +278,9 @@ where
     fn started(&mut self, _ctx: &mut Self::Context) {
         let actor = Transport::Tcp(self.actor.clone());
         let address = self.address.clone();
-        let msg = Listening{ actor, address };
+        let firewall = self.config.firewall.clone();
+        let admission = self.admission.clone();
+        let msg = Listening{ actor, address, firewall, admission };
 
         let future = self
             .network
@@ -134,11 +316,31 @@ where
 {
     type Result = NoResult;
 
-    fn handle(&mut self, msg: CreateSession, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: CreateSession, ctx: &mut Context<Self>) {
         let address = msg.stream.peer_addr().unwrap();
         let initiator = false;
 
-        TcpSession::<N>::run(self.network.clone(), address, msg.stream, initiator);
+        // dropping the stream closes the connection
+        if !self.config.firewall.allows(&address.ip()) {
+            return self.refuse(address, RefusalReason::Firewall);
+        }
+
+        let admittance = match self.admission.admit_incoming(address.ip()) {
+            Ok(admittance) => admittance,
+            Err(reason) => return self.refuse(address, reason),
+        };
+
+        let future = start_session(
+            self.network.clone(),
+            address,
+            msg.stream,
+            initiator,
+            self.config.clone(),
+            self.tls.clone(),
+            admittance,
+        );
+
+        self.spawn_handshake(address, future, ctx);
     }
 }
 
@@ -149,11 +351,28 @@ where
 {
     type Result = EmptyResult;
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
+        // refusals are reported as events, like those of accepted connections
+        if !self.config.firewall.allows(&msg.address.ip()) {
+            self.refuse(msg.address, RefusalReason::Firewall);
+            return Ok(());
+        }
+
+        let slot = match self.admission.admit(msg.address.ip()) {
+            Ok(slot) => slot,
+            Err(reason) => {
+                self.refuse(msg.address, reason);
+                return Ok(());
+            }
+        };
+
+        let address = msg.address;
         let network = self.network.clone();
-        let future = TcpStream::connect(&msg.address)
+        let config = self.config.clone();
+        let tls = self.tls.clone();
+        let future = TcpStream::connect(&address)
             .map_err(move |e| {
-                eprintln!("TCP: error while connecting to {}: {}", msg.address, e);
+                eprintln!("TCP: error while connecting to {}: {}", address, e);
             })
             .and_then(move |stream| {
                 let address = stream.peer_addr().unwrap();
@@ -164,12 +383,11 @@ where
                     eprintln!("TCP: cannot set keep-alive for stream ({})", address);
                 }
 
-                TcpSession::run(network, address, stream, initiator);
-
-                future::ok(())
+                let admittance = Admittance::Admitted(slot);
+                start_session(network, address, stream, initiator, config, tls, admittance)
             });
 
-        Arbiter::handle().spawn(future);
+        self.spawn_handshake(address, future, ctx);
         Ok(())
     }
 }
@@ -198,11 +416,47 @@ where
     /// Remote address
     address: net::SocketAddr,
     /// Framed writer
-    writer: FramedWrite<WriteHalf<TcpStream>, MessageCodec>,
+    writer: FramedWrite<WriteHalf<Box<SessionStream>>, TransportCodec>,
     /// Own actor address
     actor: TcpSessionAddr<N>,
     /// Whether session was initiated by us
     initiator: bool,
+    /// Codec state shared with the framed reader and writer
+    codec: SharedCodecState,
+    /// Codec error policy state
+    errors: ErrorTracker,
+    /// Transport configuration
+    config: TransportConfig,
+    /// Noise handshake in progress
+    handshake: Option<NoiseHandshake>,
+    /// Reason reported when the session stops
+    reason: DisconnectReason,
+    /// TLS peer certificate fingerprint
+    fingerprint: Option<CertFingerprint>,
+    /// Noise handshake hash and TLS keying material, signed along with
+    /// the Hello nonces
+    binding: Vec<u8>,
+    /// Nonce sent in our Hello
+    nonce: Option<[u8; NONCE_SZ]>,
+    /// Nonce of the remote node's Hello
+    remote_nonce: Option<Bytes>,
+    /// Remote node, as announced in its Hello
+    peer: Option<PeerInfo>,
+    /// Whether the remote node answered our Hello challenge
+    verified: bool,
+    /// Admission puzzle sent to the remote node and not solved yet
+    puzzle: Option<Puzzle>,
+    /// Search for a solution to the remote node's admission puzzle
+    solving: Option<SolverHandle>,
+    /// Whether the remote node is authenticated and the session announced
+    connected: bool,
+    /// Whether the session was refused by the admission limits, and
+    /// reported as such instead of disconnected
+    refused: bool,
+    /// Admission counters entry, released when the session ends; reserved
+    /// once the remote node solves its puzzle, if challenged
+    slot: Option<AdmissionSlot>,
+    challenge: Option<Challenge>,
 }
 
 impl<N> TcpSession<N>
@@ -213,13 +467,28 @@ where
     fn run(
         network: NetAddr<N>,
         address: net::SocketAddr,
-        stream: TcpStream,
+        stream: EstablishedStream,
         initiator: bool,
+        config: TransportConfig,
+        admittance: Admittance,
     ) -> TcpSessionAddr<N> {
+        let (slot, challenge) = match admittance {
+            Admittance::Admitted(slot) => (Some(slot), None),
+            Admittance::Challenged(challenge) => (None, Some(challenge)),
+        };
+
         TcpSession::create(move |ctx| {
-            let (read, write) = stream.split();
-            let reader = FramedRead::new(read, MessageCodec);
-            let writer = FramedWrite::new(write, MessageCodec, ctx);
+            let mut state = CodecState::default();
+            if config.codec.psk.is_some() {
+                state.psk_session = Some(PskSession::default());
+            }
+            let codec = Rc::new(RefCell::new(state));
+            let fingerprint = stream.fingerprint;
+            let binding = stream.binding;
+            let (read, write) = stream.stream.split();
+            let reader = FramedRead::new(read, TransportCodec::from_config(&config, codec.clone()));
+            let writer =
+                FramedWrite::new(write, TransportCodec::from_config(&config, codec.clone()), ctx);
 
             TcpSession::add_stream(reader, ctx);
             TcpSession {
@@ -228,16 +497,335 @@ where
                 writer,
                 actor: ctx.address(),
                 initiator,
+                codec,
+                errors: ErrorTracker::new(config.error_policy),
+                config,
+                handshake: None,
+                reason: DisconnectReason::Closed,
+                fingerprint,
+                binding,
+                nonce: None,
+                remote_nonce: None,
+                peer: None,
+                verified: false,
+                puzzle: None,
+                solving: None,
+                connected: false,
+                refused: false,
+                slot,
+                challenge,
             }
         })
     }
+
+    fn negotiate(&mut self, capabilities: Capabilities) {
+        let local = &self.config.codec.compression;
+        let compression = Compression::negotiate(local, &capabilities.compression);
+
+        self.codec.borrow_mut().compression = compression;
+    }
+
+    /// Starts the Noise handshake, or the Hello exchange without one
+    fn begin(&mut self, ctx: &mut Context<Self>) {
+        let noise = match self.config.noise {
+            Some(ref noise) => noise.clone(),
+            None => return self.introduce(ctx),
+        };
+
+        let mut handshake = match NoiseHandshake::new(&noise, self.initiator) {
+            Ok(handshake) => handshake,
+            Err(e) => return self.fail(DisconnectReason::HandshakeFailed, Error::from(e), ctx),
+        };
+
+        if self.initiator {
+            match handshake.write() {
+                Ok(message) => self.writer.write(Message::Noise(Bytes::from(message))),
+                Err(e) => {
+                    return self.fail(DisconnectReason::HandshakeFailed, Error::from(e), ctx)
+                }
+            }
+        }
+        self.handshake = Some(handshake);
+    }
+
+    /// Advertises session features and challenges the remote node to prove
+    /// its identity
+    fn introduce(&mut self, ctx: &mut Context<Self>) {
+        // frames stay uncompressed until the remote peer's capabilities arrive
+        let capabilities = Capabilities {
+            compression: self.config.codec.compression.clone(),
+        };
+        self.writer.write(Message::Capabilities(capabilities));
+
+        // peers connecting under load prove their work before taking a slot
+        let difficulty = self.challenge.as_ref().map_or(0, Challenge::difficulty);
+        if !self.initiator && difficulty > 0 {
+            let challenge = match identity::nonce() {
+                Ok(challenge) => challenge,
+                Err(e) => return self.fail(DisconnectReason::PuzzleFailed, e, ctx),
+            };
+
+            let puzzle = Puzzle {
+                challenge: Bytes::from(&challenge[..]),
+                difficulty,
+            };
+            self.writer.write(Message::Puzzle(puzzle.clone()));
+            self.puzzle = Some(puzzle);
+        }
+
+        let nonce = match identity::nonce() {
+            Ok(nonce) => nonce,
+            Err(e) => return self.fail(DisconnectReason::AuthenticationFailed, e, ctx),
+        };
+
+        let hello = Hello {
+            version: self.config.version.clone(),
+            public_key: Bytes::from(self.config.identity.public_key()),
+            nonce: Bytes::from(&nonce[..]),
+            listen_ports: self.config.listen_ports,
+            cookie: Bytes::new(),
+        };
+
+        self.nonce = Some(nonce);
+        self.writer.write(Message::Hello(hello));
+    }
+
+    /// Answers the remote node's challenge
+    fn hello(&mut self, hello: Hello, ctx: &mut Context<Self>) {
+        if self.peer.is_some() || hello.nonce.len() != NONCE_SZ {
+            let e = auth_error("invalid Hello");
+            return self.fail(DisconnectReason::AuthenticationFailed, e, ctx);
+        }
+
+        if &hello.public_key[..] == self.config.identity.public_key() {
+            let e = auth_error("connected to self");
+            return self.fail(DisconnectReason::SelfConnection, e, ctx);
+        }
+
+        // our Hello is sent as soon as the session is set up, before any
+        // other message is handled
+        let signature = match self.nonce {
+            Some(ref nonce) => {
+                let transcript = HelloTranscript {
+                    initiator: self.initiator,
+                    signer_key: self.config.identity.public_key(),
+                    verifier_key: &hello.public_key,
+                    signer_nonce: nonce,
+                    verifier_nonce: &hello.nonce,
+                    binding: &self.binding,
+                };
+                self.config.identity.sign_hello(&transcript)
+            }
+            None => {
+                let e = auth_error("Hello received before ours was sent");
+                return self.fail(DisconnectReason::AuthenticationFailed, e, ctx);
+            }
+        };
+        let ack = HelloAck {
+            signature: Bytes::from(signature),
+        };
+        self.writer.write(Message::HelloAck(ack));
+
+        self.remote_nonce = Some(hello.nonce.clone());
+        self.peer = Some(PeerInfo {
+            peer_id: PeerId::from_public_key(&hello.public_key),
+            public_key: hello.public_key.to_vec(),
+            version: hello.version,
+            listen_ports: hello.listen_ports,
+        });
+    }
+
+    /// Verifies the remote node's answer to our challenge
+    fn hello_ack(&mut self, ack: HelloAck, ctx: &mut Context<Self>) {
+        let verified = match (&self.peer, &self.nonce, &self.remote_nonce) {
+            (&Some(ref peer), &Some(ref nonce), &Some(ref remote_nonce)) if !self.verified => {
+                let transcript = HelloTranscript {
+                    initiator: !self.initiator,
+                    signer_key: &peer.public_key,
+                    verifier_key: self.config.identity.public_key(),
+                    signer_nonce: remote_nonce,
+                    verifier_nonce: nonce,
+                    binding: &self.binding,
+                };
+                identity::verify_hello(&transcript, &ack.signature)
+            }
+            _ => false,
+        };
+
+        if !verified {
+            let e = auth_error("invalid HelloAck");
+            return self.fail(DisconnectReason::AuthenticationFailed, e, ctx);
+        }
+
+        self.verified = true;
+        self.announce(ctx);
+    }
+
+    /// Solves the remote node's admission puzzle on a solver thread; the
+    /// search is cancelled if the session ends first
+    fn solve(&mut self, puzzle: Puzzle, ctx: &mut Context<Self>) {
+        if !self.initiator || self.solving.is_some() || puzzle.difficulty > MAX_DIFFICULTY {
+            let e = auth_error("invalid Puzzle");
+            return self.fail(DisconnectReason::PuzzleFailed, e, ctx);
+        }
+
+        let (rx, handle) = match self.config.solver.solve(puzzle.challenge.to_vec(), puzzle.difficulty) {
+            Some(search) => search,
+            None => {
+                let e = auth_error("every puzzle solver thread is busy");
+                return self.fail(DisconnectReason::PuzzleFailed, e, ctx);
+            }
+        };
+
+        self.solving = Some(handle);
+        ctx.add_message_stream(rx.map(PuzzleSolved).map_err(|_| ()).into_stream());
+    }
+
+    /// Checks the remote node's solution to our admission puzzle
+    fn solution(&mut self, solution: Solution, ctx: &mut Context<Self>) {
+        let solved = match self.puzzle {
+            Some(ref p) => puzzle::verify(&p.challenge, p.difficulty, solution.nonce),
+            None => false,
+        };
+
+        if !solved {
+            let e = auth_error("invalid Solution");
+            return self.fail(DisconnectReason::PuzzleFailed, e, ctx);
+        }
+
+        self.puzzle = None;
+        let slot = match self.challenge.take().map(Challenge::solved) {
+            Some(Ok(slot)) => slot,
+            Some(Err(reason)) => return self.refuse(reason, ctx),
+            None => return,
+        };
+        self.slot = Some(slot);
+        self.announce(ctx);
+    }
+
+    /// Ends a session the admission limits leave no room for
+    fn refuse(&mut self, reason: RefusalReason, ctx: &mut Context<Self>) {
+        eprintln!("TCP: connection from {} refused: {:?}", self.address, reason);
+
+        let msg = Refused {
+            transport: TransportProtocol::Tcp,
+            address: self.address,
+            reason,
+        };
+
+        let future = self.network.send(msg)
+            .map_err(|_| eprintln!("TCP: failed to send 'Refused' event"));
+
+        Arbiter::handle().spawn(future);
+        self.refused = true;
+        ctx.stop();
+    }
+
+    /// Announces the session to the network, once the remote node is
+    /// authenticated and has solved its admission puzzle
+    fn announce(&mut self, ctx: &mut Context<Self>) {
+        if !self.verified || self.slot.is_none() || self.connected {
+            return;
+        }
+
+        let peer = match self.peer {
+            Some(ref peer) => peer.clone(),
+            None => return,
+        };
+
+        // the address may have been banned during the handshake
+        if !self.config.firewall.allows(&self.address.ip()) {
+            let e = auth_error("address banned");
+            return self.fail(DisconnectReason::Banned, e, ctx);
+        }
+
+        self.connected = true;
+        if let Some(ref mut slot) = self.slot {
+            slot.established();
+        }
+
+        let session = self.actor.clone();
+        let msg = Connected {
+            transport: TransportProtocol::Tcp,
+            address: self.address.clone(),
+            session: TransportSession::Tcp(session),
+            codec: self.codec.clone(),
+            initiator: self.initiator,
+            fingerprint: self.fingerprint,
+            peer,
+            congestion: None,
+        };
+
+        let future = self.network.send(msg)
+            .map_err(|_| eprintln!("TCP: failed to send 'Connected' event"));
+
+        Arbiter::handle().spawn(future);
+    }
+
+    /// Advances the Noise handshake with a message from the remote peer.
+    /// Installs the session cipher once the handshake completes and returns
+    /// whether it did.
+    fn handshake(&mut self, message: &[u8]) -> Result<bool, CodecError> {
+        let finished = {
+            let handshake = match self.handshake {
+                Some(ref mut handshake) => handshake,
+                None => return Err(CodecError::Encryption("unexpected handshake message".to_owned())),
+            };
+
+            handshake.read(message)?;
+            if handshake.is_my_turn() && !handshake.is_finished() {
+                let reply = handshake.write()?;
+                self.writer.write(Message::Noise(Bytes::from(reply)));
+            }
+            handshake.is_finished()
+        };
+
+        if finished {
+            if let Some(handshake) = self.handshake.take() {
+                self.binding.extend_from_slice(&handshake.handshake_hash()?);
+                // the final handshake message has already been encoded in
+                // plaintext; everything after it is encrypted
+                self.codec.borrow_mut().cipher = Some(handshake.into_cipher()?);
+            }
+        }
+        Ok(finished)
+    }
+
+    fn fail(&mut self, reason: DisconnectReason, error: Error, ctx: &mut Context<Self>) {
+        eprintln!("TCP: session setup failed ({}): {}", self.address, error);
+        self.reason = reason;
+        self.report(error);
+        ctx.stop();
+    }
+
+    fn report(&self, error: Error) {
+        let msg = TransportError {
+            transport: TransportProtocol::Tcp,
+            address: self.address.clone(),
+            error,
+        };
+
+        let future = self.network.send(msg)
+            .map_err(|_| eprintln!("TCP: failed to send 'Error' event"));
+
+        Arbiter::handle().spawn(future);
+    }
 }
 
 impl<N> WriteHandler<CodecError> for TcpSession<N>
 where
     N: Network + 'static,
     N::Context: AsyncContext<N>,
-{}
+{
+    fn error(&mut self, err: CodecError, _ctx: &mut Self::Context) -> Running {
+        eprintln!("TCP: message sink error ({}): {}", self.address, err);
+        match err {
+            // the oversized message was never written; the stream is intact
+            CodecError::FrameTooLarge { .. } => Running::Continue,
+            _ => Running::Stop,
+        }
+    }
+}
 
 impl<N> Actor for TcpSession<N>
 where
@@ -246,25 +834,54 @@ where
 {
     type Context = Context<Self>;
 
-    fn started(&mut self, _: &mut <Self as Actor>::Context) {
-        let session = self.actor.clone();
-        let msg = Connected {
-            transport: TransportProtocol::Tcp,
-            address: self.address.clone(),
-            session: TransportSession::Tcp(session),
-            initiator: self.initiator,
-        };
+    fn started(&mut self, ctx: &mut <Self as Actor>::Context) {
+        // the session is announced only after the remote node authenticates
+        ctx.run_later(self.config.handshake_timeout, |act, ctx| {
+            if !act.connected {
+                eprintln!("TCP: handshake timed out ({})", act.address);
+                act.reason = DisconnectReason::HandshakeTimeout;
+                ctx.stop();
+            }
+        });
 
-        let future = self.network.send(msg)
-            .map_err(|_| eprintln!("TCP: failed to send 'Connected' event"));
+        let read_timeout = self.config.admission.config().read_timeout;
+        let interval = (read_timeout / 2).max(Duration::from_millis(MIN_READ_CHECK_MILLIS));
+        run_interval(ctx, interval, move |act: &mut Self, ctx| {
+            let stalled = match act.codec.borrow().partial_since {
+                Some(since) => since.elapsed() > read_timeout,
+                None => false,
+            };
 
-        Arbiter::handle().spawn(future);
+            if stalled {
+                eprintln!("TCP: incomplete frame timed out ({})", act.address);
+                act.reason = DisconnectReason::ReadTimeout;
+                ctx.stop();
+            }
+        });
+
+        // in a private network, the handshake starts once the session nonces
+        // are exchanged
+        if self.config.codec.psk.is_none() {
+            return self.begin(ctx);
+        }
+
+        match identity::nonce() {
+            Ok(nonce) => self.writer.write(Message::SessionNonce(Bytes::from(&nonce[..]))),
+            Err(e) => self.fail(DisconnectReason::Unauthenticated, e, ctx),
+        }
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        // sessions failing before the announcement are reported as
+        // disconnected too, with the reason of the failure
+        if self.refused {
+            return Running::Stop;
+        }
+
         let msg = Disconnected {
             transport: TransportProtocol::Tcp,
             address: self.address.clone(),
+            reason: self.reason,
         };
 
         let future = self.network.send(msg)
@@ -284,7 +901,33 @@ where
     N: Network + 'static,
     N::Context: AsyncContext<N>,
 {
-    fn handle(&mut self, msg: Message, _ctx: &mut Self::Context) {
+    fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
+        let msg = match msg {
+            // the codec accepts a single nonce, before any other message
+            Message::SessionNonce(_) => return self.begin(ctx),
+            Message::Noise(message) => {
+                return match self.handshake(&message) {
+                    Ok(true) => self.introduce(ctx),
+                    Ok(false) => {}
+                    Err(e) => self.fail(DisconnectReason::HandshakeFailed, Error::from(e), ctx),
+                };
+            }
+            _ if self.handshake.is_some() => {
+                let e = CodecError::Encryption("unencrypted message during handshake".to_owned());
+                return self.fail(DisconnectReason::HandshakeFailed, Error::from(e), ctx);
+            }
+            Message::Capabilities(c) => return self.negotiate(c),
+            Message::Hello(hello) => return self.hello(hello, ctx),
+            Message::HelloAck(ack) => return self.hello_ack(ack, ctx),
+            Message::Puzzle(puzzle) => return self.solve(puzzle, ctx),
+            Message::Solution(solution) => return self.solution(solution, ctx),
+            _ if !self.connected => {
+                let e = auth_error("message received before authentication");
+                return self.fail(DisconnectReason::AuthenticationFailed, e, ctx);
+            }
+            msg => msg,
+        };
+
         let msg = ReceivedMessage {
             transport: TransportProtocol::Tcp,
             address: self.address.clone(),
@@ -298,8 +941,23 @@ where
     }
 
     fn error(&mut self, err: CodecError, _ctx: &mut Self::Context) -> Running {
-        eprintln!("TCP: message stream error: {}", err);
-        Running::Continue
+        eprintln!("TCP: message stream error ({}): {}", self.address, err);
+
+        // the stream cannot be resynchronized past a malformed header
+        let keep_open = !err.is_fatal() && self.errors.record(Instant::now());
+        let reason = match err {
+            CodecError::Unauthenticated => DisconnectReason::Unauthenticated,
+            _ => DisconnectReason::ProtocolError,
+        };
+        self.report(Error::from(err));
+
+        match keep_open {
+            true => Running::Continue,
+            false => {
+                self.reason = reason;
+                Running::Stop
+            }
+        }
     }
 }
 
@@ -328,7 +986,91 @@ where
     type Result = EmptyResult;
 
     fn handle(&mut self, _: Stop, ctx: &mut Self::Context) -> Self::Result {
+        self.reason = DisconnectReason::Requested;
+        ctx.stop();
+        Ok(())
+    }
+}
+
+impl<N> Handler<PuzzleSolved> for TcpSession<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    type Result = NoResult;
+
+    fn handle(&mut self, msg: PuzzleSolved, _ctx: &mut Self::Context) {
+        self.writer.write(Message::Solution(Solution { nonce: msg.0 }));
+    }
+}
+
+impl<N> Handler<Close> for TcpSession<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    type Result = EmptyResult;
+
+    fn handle(&mut self, msg: Close, ctx: &mut Self::Context) -> Self::Result {
+        self.reason = msg.reason;
         ctx.stop();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use codec::noise::NoiseConfig;
+    use event::Event;
+    use transport::config::TransportConfig;
+    use transport::testing::Node;
+    use transport::{DisconnectReason, RefusalReason, TransportProtocol};
+
+    fn closed(event: &Event) -> bool {
+        match *event {
+            Event::Connected(TransportProtocol::Tcp, ..) => true,
+            Event::Disconnected(TransportProtocol::Tcp, ..) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_noise_mismatch() {
+        let config = TransportConfig {
+            noise: Some(NoiseConfig::generate().unwrap()),
+            ..TransportConfig::generate().unwrap()
+        };
+        let server = Node::run(TransportProtocol::Tcp, config);
+        let client = Node::run(TransportProtocol::Tcp, TransportConfig::generate().unwrap());
+
+        // the client's plaintext messages arrive during the server's Noise
+        // handshake; the session is never announced
+        client.connect(server.address);
+        match server.expect(closed) {
+            Event::Disconnected(_, _, reason) => {
+                assert_eq!(DisconnectReason::HandshakeFailed, reason)
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_refused_connect() {
+        let server = Node::run(TransportProtocol::Tcp, TransportConfig::generate().unwrap());
+        let config = TransportConfig::generate().unwrap();
+        config.firewall.set_rules(Vec::new(), vec!["127.0.0.1".parse().unwrap()]);
+        let client = Node::run(TransportProtocol::Tcp, config);
+
+        client.connect(server.address);
+        match client.expect(|e| match *e {
+            Event::Refused(TransportProtocol::Tcp, ..) => true,
+            _ => false,
+        }) {
+            Event::Refused(_, address, reason) => {
+                assert_eq!(server.address, address);
+                assert_eq!(RefusalReason::Firewall, reason);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
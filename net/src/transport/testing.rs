@@ -0,0 +1,130 @@
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix::Syn;
+use bytes::Bytes;
+
+use codec::message::{Encapsulated, Message};
+use event::Event;
+use network::NetworkCore;
+use transport::config::TransportConfig;
+use transport::message::{Connect, Disconnect, SendMessage};
+use transport::tcp::TcpTransport;
+use transport::udp::UdpTransport;
+use transport::{Delivery, TransportProtocol};
+
+pub const TIMEOUT_SECS: u64 = 10;
+
+/// Network with a single transport, for the transport tests
+pub struct Node {
+    pub network: Addr<Syn, NetworkCore>,
+    pub address: SocketAddr,
+    transport: TransportProtocol,
+    pub events: mpsc::Receiver<Event>,
+}
+
+impl Node {
+    /// Runs a network with a `transport` transport on loopback, on its own
+    /// thread
+    pub fn run(transport: TransportProtocol, config: TransportConfig) -> Self {
+        let (tx_events, events) = mpsc::sync_channel(1024);
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let sys = System::new("test");
+            let (unsync, syn) = NetworkCore::run(tx_events);
+            let address = "127.0.0.1:0".parse().unwrap();
+            match transport {
+                TransportProtocol::Tcp => {
+                    TcpTransport::run(unsync, address, config).unwrap();
+                }
+                TransportProtocol::Udp => {
+                    UdpTransport::run(unsync, address, config).unwrap();
+                }
+                TransportProtocol::Unsupported => unreachable!(),
+            }
+            tx.send(syn).unwrap();
+            sys.run();
+        });
+
+        let network = rx.recv().unwrap();
+        let mut node = Node {
+            network,
+            address: "0.0.0.0:0".parse().unwrap(),
+            transport,
+            events,
+        };
+        node.address = match node.expect(|e| match *e {
+            Event::Started(..) => true,
+            _ => false,
+        }) {
+            Event::Started(_, address) => address,
+            _ => unreachable!(),
+        };
+        node
+    }
+
+    /// Waits for an event, skipping the others
+    pub fn expect<F>(&self, matches: F) -> Event
+    where
+        F: Fn(&Event) -> bool,
+    {
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+        loop {
+            let now = Instant::now();
+            assert!(now < deadline, "event timed out");
+            match self.events.recv_timeout(deadline - now) {
+                Ok(event) => {
+                    if matches(&event) {
+                        return event;
+                    }
+                }
+                Err(_) => panic!("event timed out"),
+            }
+        }
+    }
+
+    /// Events emitted within `duration`
+    pub fn collect(&self, duration: Duration) -> Vec<Event> {
+        let deadline = Instant::now() + duration;
+        let mut events = Vec::new();
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return events;
+            }
+            if let Ok(event) = self.events.recv_timeout(deadline - now) {
+                events.push(event);
+            }
+        }
+    }
+
+    pub fn connect(&self, address: SocketAddr) {
+        self.network.do_send(Connect {
+            transport: self.transport,
+            address,
+        });
+    }
+
+    pub fn disconnect(&self, address: SocketAddr) {
+        self.network.do_send(Disconnect {
+            transport: self.transport,
+            address,
+        });
+    }
+
+    pub fn send(&self, address: SocketAddr, protocol_id: u16) {
+        self.network.do_send(SendMessage {
+            transport: self.transport,
+            address,
+            message: Message::Encapsulated(Encapsulated {
+                protocol_id,
+                message: Bytes::from(&b"payload"[..]),
+            }),
+            delivery: Delivery::Unreliable,
+        });
+    }
+}
@@ -0,0 +1,356 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use futures::Future;
+use ring::digest;
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier,
+    ClientConfig, DistinguishedNames, NoClientAuth, PrivateKey, RootCertStore,
+    ServerCertVerified, ServerCertVerifier, ServerConfig, Session, TLSError,
+};
+use tokio_io::io::flush;
+use tokio_rustls::{ClientConfigExt, ServerConfigExt};
+use tokio_tcp::TcpStream;
+use webpki::DNSNameRef;
+
+use error::{Error, ErrorKind, ErrorSeverity};
+use transport::tcp::EstablishedStream;
+
+/// SHA-256 digest of a DER encoded certificate
+pub type CertFingerprint = [u8; 32];
+
+/// Server name used when certificates are not verified against roots
+const DEFAULT_SERVER_NAME: &str = "localhost";
+
+pub fn fingerprint(certificate: &[u8]) -> CertFingerprint {
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(digest::digest(&digest::SHA256, certificate).as_ref());
+    fingerprint
+}
+
+/// Peer certificate verification
+#[derive(Clone, Debug)]
+pub enum CertVerification {
+    /// Verify the chain against DER encoded root certificates; servers are
+    /// also checked against `server_name`
+    Roots {
+        roots: Vec<Vec<u8>>,
+        server_name: String,
+    },
+    /// Accept only leaf certificates with one of these fingerprints, e.g.
+    /// self-signed ones
+    Pinned(Vec<CertFingerprint>),
+    /// Accept any certificate. The fingerprint is still reported on
+    /// connection, for the application to check.
+    Any,
+}
+
+/// TLS configuration
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// Certificate chain presented to peers, DER encoded, leaf first
+    pub certificates: Vec<Vec<u8>>,
+    /// Private key of the leaf certificate, DER encoded (PKCS#8 or RSA)
+    pub private_key: Vec<u8>,
+    /// Verification of server and, with `client_auth`, client certificates
+    pub verification: CertVerification,
+    /// Require accepted peers to present a certificate
+    pub client_auth: bool,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("certificates", &self.certificates.len())
+            .field("verification", &self.verification)
+            .field("client_auth", &self.client_auth)
+            .finish()
+    }
+}
+
+fn tls_error(message: &str) -> Error {
+    Error::new(ErrorKind::Network, ErrorSeverity::High, message)
+}
+
+fn tls_io_error(e: TLSError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Accepts certificates by fingerprint; `None` accepts any certificate
+struct FingerprintVerifier(Option<Vec<CertFingerprint>>);
+
+impl FingerprintVerifier {
+    fn verify(&self, presented: &[Certificate]) -> Result<(), TLSError> {
+        let leaf = match presented.first() {
+            Some(leaf) => leaf,
+            None => return Err(TLSError::NoCertificatesPresented),
+        };
+
+        match self.0 {
+            Some(ref pinned) if !pinned.contains(&fingerprint(&leaf.0)) => Err(
+                TLSError::General("certificate fingerprint is not pinned".to_owned()),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        self.verify(presented).map(|_| ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for FingerprintVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        true
+    }
+
+    fn client_auth_root_subjects(&self) -> DistinguishedNames {
+        DistinguishedNames::new()
+    }
+
+    fn verify_client_cert(&self, presented: &[Certificate]) -> Result<ClientCertVerified, TLSError> {
+        self.verify(presented).map(|_| ClientCertVerified::assertion())
+    }
+}
+
+/// Exporter label of the keying material binding Hello signatures to a
+/// TLS session (RFC 5705)
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-golem-net-hello";
+const EXPORTER_SZ: usize = 32;
+
+fn peer_fingerprint<S: Session>(session: &S) -> Option<CertFingerprint> {
+    session
+        .get_peer_certificates()
+        .and_then(|certificates| certificates.first().map(|c| fingerprint(&c.0)))
+}
+
+fn export_binding<S: Session>(session: &S) -> Result<Vec<u8>, io::Error> {
+    let mut binding = vec![0u8; EXPORTER_SZ];
+    session
+        .export_keying_material(&mut binding, EXPORTER_LABEL, None)
+        .map_err(tls_io_error)?;
+    Ok(binding)
+}
+
+/// TLS client and server configurations built from a `TlsConfig`
+#[derive(Clone)]
+pub struct TlsContext {
+    client: Arc<ClientConfig>,
+    server: Arc<ServerConfig>,
+    server_name: String,
+}
+
+impl TlsContext {
+    pub fn new(config: &TlsConfig) -> Result<Self, Error> {
+        let certificates: Vec<_> = config.certificates.iter().cloned().map(Certificate).collect();
+        let private_key = PrivateKey(config.private_key.clone());
+
+        let mut roots = RootCertStore::empty();
+        let (pinned, server_name) = match config.verification {
+            CertVerification::Roots {
+                roots: ref certs,
+                ref server_name,
+            } => {
+                for cert in certs {
+                    if let Err(e) = roots.add(&Certificate(cert.clone())) {
+                        return Err(tls_error(&format!("invalid root certificate: {:?}", e)));
+                    }
+                }
+                (None, server_name.clone())
+            }
+            CertVerification::Pinned(ref fingerprints) => {
+                (Some(Some(fingerprints.clone())), DEFAULT_SERVER_NAME.to_owned())
+            }
+            CertVerification::Any => (Some(None), DEFAULT_SERVER_NAME.to_owned()),
+        };
+
+        if let Err(_) = DNSNameRef::try_from_ascii_str(&server_name) {
+            return Err(tls_error(&format!("invalid server name: {}", server_name)));
+        }
+
+        let client_verifier: Arc<ClientCertVerifier> = match (config.client_auth, &pinned) {
+            (false, _) => NoClientAuth::new(),
+            (true, None) => AllowAnyAuthenticatedClient::new(roots.clone()),
+            (true, Some(pinned)) => Arc::new(FingerprintVerifier(pinned.clone())),
+        };
+
+        let mut server = ServerConfig::new(client_verifier);
+        server
+            .set_single_cert(certificates.clone(), private_key.clone())
+            .map_err(|e| tls_error(&format!("invalid certificate or key: {:?}", e)))?;
+
+        let mut client = ClientConfig::new();
+        client.root_store = roots;
+        client.set_single_client_cert(certificates, private_key);
+        if let Some(pinned) = pinned {
+            let verifier = Arc::new(FingerprintVerifier(pinned));
+            client.dangerous().set_certificate_verifier(verifier);
+        }
+
+        Ok(TlsContext {
+            client: Arc::new(client),
+            server: Arc::new(server),
+            server_name,
+        })
+    }
+
+    /// Runs the TLS handshake over an established TCP stream. The last
+    /// handshake records may still be buffered once the session reports the
+    /// handshake done, so they are flushed before the stream is handed over.
+    pub fn handshake(
+        &self,
+        stream: TcpStream,
+        initiator: bool,
+    ) -> Box<Future<Item = EstablishedStream, Error = io::Error>> {
+        if !initiator {
+            let future = self.server.accept_async(stream).and_then(flush).and_then(|stream| {
+                let fingerprint = peer_fingerprint(stream.get_ref().1);
+                let binding = export_binding(stream.get_ref().1)?;
+                Ok(EstablishedStream {
+                    stream: Box::new(stream),
+                    fingerprint,
+                    binding,
+                })
+            });
+            return Box::new(future);
+        }
+
+        // validated in `new`
+        let server_name = DNSNameRef::try_from_ascii_str(&self.server_name).unwrap();
+        let future = self
+            .client
+            .connect_async(server_name, stream)
+            .and_then(flush)
+            .and_then(|stream| {
+                let fingerprint = match peer_fingerprint(stream.get_ref().1) {
+                    Some(fingerprint) => fingerprint,
+                    None => return Err(tls_io_error(TLSError::NoCertificatesPresented)),
+                };
+                let binding = export_binding(stream.get_ref().1)?;
+                Ok(EstablishedStream {
+                    stream: Box::new(stream),
+                    fingerprint: Some(fingerprint),
+                    binding,
+                })
+            });
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use futures::{Future, Stream};
+    use rustls::{Certificate, TLSError};
+    use tokio_tcp::{TcpListener, TcpStream};
+
+    use transport::tcp::EstablishedStream;
+    use transport::tls::{
+        fingerprint, CertFingerprint, CertVerification, FingerprintVerifier, TlsConfig, TlsContext,
+    };
+
+    /// Self-signed P-256 certificate for "localhost" and its PKCS#8 key
+    const CERTIFICATE: &[u8] = include_bytes!("../../tests/data/tls_cert.der");
+    const PRIVATE_KEY: &[u8] = include_bytes!("../../tests/data/tls_key.der");
+
+    fn config(verification: CertVerification) -> TlsConfig {
+        TlsConfig {
+            certificates: vec![CERTIFICATE.to_vec()],
+            private_key: PRIVATE_KEY.to_vec(),
+            verification,
+            client_auth: true,
+        }
+    }
+
+    /// Peer fingerprint and session binding of an established stream, or
+    /// the handshake error
+    type Established = Result<(Option<CertFingerprint>, Vec<u8>), String>;
+
+    fn established(stream: EstablishedStream) -> (Option<CertFingerprint>, Vec<u8>) {
+        (stream.fingerprint, stream.binding)
+    }
+
+    /// Runs both ends of a TLS handshake over a loopback connection
+    fn handshake(server: TlsContext, client: TlsContext) -> (Established, Established) {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let accepted = thread::spawn(move || {
+            listener
+                .incoming()
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(|(stream, _)| server.handshake(stream.unwrap(), false))
+                .wait()
+                .map(established)
+                .map_err(|e| e.to_string())
+        });
+
+        let connected = TcpStream::connect(&address)
+            .and_then(|stream| client.handshake(stream, true))
+            .wait()
+            .map(established)
+            .map_err(|e| e.to_string());
+
+        (accepted.join().unwrap(), connected)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let pinned = CertVerification::Pinned(vec![fingerprint(CERTIFICATE)]);
+        let server = TlsContext::new(&config(pinned.clone())).unwrap();
+        let client = TlsContext::new(&config(pinned)).unwrap();
+
+        let (accepted, connected) = handshake(server, client);
+        let (accepted, connected) = (accepted.unwrap(), connected.unwrap());
+
+        // both ends see the other's certificate and derive the same binding
+        assert_eq!(Some(fingerprint(CERTIFICATE)), accepted.0);
+        assert_eq!(Some(fingerprint(CERTIFICATE)), connected.0);
+        assert!(!accepted.1.is_empty());
+        assert_eq!(accepted.1, connected.1);
+    }
+
+    #[test]
+    fn test_handshake_unpinned() {
+        let pinned = CertVerification::Pinned(vec![fingerprint(CERTIFICATE)]);
+        let other = CertVerification::Pinned(vec![fingerprint(b"other certificate")]);
+        let server = TlsContext::new(&config(pinned)).unwrap();
+        let client = TlsContext::new(&config(other)).unwrap();
+
+        // the client refuses the server certificate
+        let (_, connected) = handshake(server, client);
+        assert!(connected.is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_verifier() {
+        let trusted = Certificate(b"trusted certificate".to_vec());
+        let unknown = Certificate(b"unknown certificate".to_vec());
+
+        let pinned = FingerprintVerifier(Some(vec![fingerprint(&trusted.0)]));
+        assert!(pinned.verify(&[trusted.clone()]).is_ok());
+        assert!(pinned.verify(&[unknown.clone()]).is_err());
+
+        let any = FingerprintVerifier(None);
+        assert!(any.verify(&[unknown]).is_ok());
+        match any.verify(&[]) {
+            Err(TLSError::NoCertificatesPresented) => {}
+            _ => panic!("missing certificate accepted"),
+        }
+    }
+}
@@ -1,3 +1,8 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
 use std::{error, net};
 
 use actix::io::WriteHandler;
@@ -8,25 +13,155 @@ use futures::stream::{MapErr, SplitSink, Stream};
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::{Future, Sink};
 
+use bytes::{Bytes, BytesMut};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio_codec::{Decoder, Encoder};
 use tokio_udp::{UdpFramed, UdpSocket};
 
+use error::Error;
+use identity::{self, HelloTranscript, PathTranscript, PeerId, PeerInfo, NONCE_SZ};
 use codec::error::CodecError;
-use codec::message::Message;
-use codec::MessageCodec;
+use codec::message::{
+    Ack, Cookie, Goodbye, Hello, HelloAck, Message, PathChallenge, PathResponse, Payload, Probe,
+    ProbeAck, Reliable,
+};
+use codec::SharedCodecState;
 use network::*;
+use transport::admission::{AdmissionSlot, SharedAdmission};
+use transport::config::TransportConfig;
+use transport::congestion::{Congestion, CongestionStats, SharedCongestionStats};
+use transport::connection::{self, ConnectionId};
+use transport::cookie::CookieKey;
+use transport::discovery::{Announcement, Discovery};
+use transport::firewall::SharedFirewall;
+use transport::fragment::{self, Reassembler};
 use transport::message::*;
+use transport::pmtu::{PmtuCache, PmtuSearch};
+use transport::reliable::{ReliableReceiver, ReliableSender};
+use transport::replay::{ReplayConfig, ReplayGuard, ReplayStats};
 use transport::*;
 
 pub type UdpActorAddr<N> = Addr<Unsync, UdpTransport<N>>;
 
-type FromErrType = SinkFromErr<SplitSink<UdpFramed<MessageCodec>>, CodecError>;
-type ReceiverType = UnboundedReceiver<(Message, net::SocketAddr)>;
+type FromErrType = SinkFromErr<SplitSink<UdpFramed<RawCodec>>, CodecError>;
+type ReceiverType = UnboundedReceiver<(Bytes, net::SocketAddr)>;
+
+/// Interval of Hello retransmissions, session expiry checks and path MTU
+/// probe timeouts, in seconds
+const TICK_SECS: u64 = 1;
+/// Interval of reliable message retransmission checks, in milliseconds
+const RETRANSMIT_MILLIS: u64 = 50;
+/// Interval of paced datagram releases, in milliseconds
+const PACE_MILLIS: u64 = 10;
+/// Most new addresses of a session challenged at a time
+const MAX_VALIDATIONS: usize = 4;
+
+/// Passes datagrams through unchanged; frames are encoded, fragmented
+/// and reassembled by the transport, which knows their peers
+struct RawCodec;
+
+impl Decoder for RawCodec {
+    type Item = BytesMut;
+    type Error = CodecError;
+
+    fn decode(&mut self, bytes: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // an empty datagram is an item too; `None` would end the stream
+        let len = bytes.len();
+        Ok(Some(bytes.split_to(len)))
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Bytes;
+    type Error = CodecError;
+
+    fn encode(&mut self, datagram: Self::Item, bytes: &mut BytesMut) -> Result<(), Self::Error> {
+        bytes.extend_from_slice(&datagram);
+        Ok(())
+    }
+}
 
 // Actor messages
 #[derive(Message)]
 pub struct UdpPacket {
     pub address: net::SocketAddr,
-    pub message: Message,
+    pub datagram: BytesMut,
+}
+
+#[derive(Message)]
+pub struct DiscoveryPacket {
+    pub address: net::SocketAddr,
+    pub datagram: BytesMut,
+}
+
+/// Challenge of a new address the remote node of a session sent from
+struct Validation {
+    nonce: [u8; NONCE_SZ],
+    /// Time the address was first challenged
+    issued: Instant,
+    /// Time the challenge was last sent
+    sent: Instant,
+}
+
+/// Virtual session with a remote node, established by a Hello exchange
+/// over datagrams
+struct UdpSession {
+    /// Nonce sent in our Hello
+    nonce: [u8; NONCE_SZ],
+    /// Nonce of the remote node's Hello
+    remote_nonce: Option<Bytes>,
+    /// Address validation cookie the remote node answered our Hello with
+    cookie: Option<Bytes>,
+    /// Whether session was initiated by us
+    initiator: bool,
+    /// Remote node, as announced in its Hello
+    peer: Option<PeerInfo>,
+    /// Whether the remote node answered our Hello challenge
+    verified: bool,
+    /// Whether the remote node is authenticated and the session announced
+    connected: bool,
+    /// Time the handshake started
+    started: Instant,
+    /// Time the last datagram from the remote node arrived
+    last_seen: Instant,
+    /// Admission counters entry, released when the session ends
+    slot: AdmissionSlot,
+    /// Reliable messages sent and not acknowledged yet
+    outgoing: ReliableSender,
+    /// Reliable messages received
+    incoming: ReliableReceiver,
+    /// Reliable messages waiting for room in the congestion window
+    pending: VecDeque<(Payload, bool)>,
+    /// Congestion window and datagrams waiting to be paced out
+    congestion: Congestion,
+    /// Congestion control snapshot shared with the network
+    stats: SharedCongestionStats,
+    /// Largest datagram sent to the remote node
+    path_mtu: usize,
+    /// Path MTU search, while one is running
+    pmtu: Option<PmtuSearch>,
+    /// Retransmission rounds of datagrams above the fragmentation MTU
+    /// since every reliable message was last acknowledged
+    large_losses: u32,
+    /// Unanswered challenges of new remote addresses
+    validations: HashMap<net::SocketAddr, Validation>,
+}
+
+impl UdpSession {
+    /// Connection ID of datagrams from the remote node
+    fn local_id(&self) -> ConnectionId {
+        ConnectionId::from_nonce(&self.nonce)
+    }
+
+    /// Connection ID of datagrams to the remote node, once its Hello arrived
+    fn remote_id(&self) -> Option<ConnectionId> {
+        self.remote_nonce.as_ref().map(|nonce| ConnectionId::from_nonce(nonce))
+    }
+
+    fn update_stats(&self) {
+        let stats = self.congestion.stats(self.pending.len(), self.outgoing.srtt(), self.path_mtu);
+        *self.stats.borrow_mut() = stats;
+    }
 }
 
 pub struct UdpTransport<N>
@@ -36,8 +171,34 @@ where
 {
     address: net::SocketAddr,
     logic: NetAddr<N>,
-    sender: UnboundedSender<(Message, net::SocketAddr)>,
+    sender: UnboundedSender<(Bytes, net::SocketAddr)>,
     actor: UdpActorAddr<N>,
+    /// Transport configuration
+    config: TransportConfig,
+    /// Frame codec
+    codec: TransportCodec,
+    /// Codec state shared by all datagrams
+    state: SharedCodecState,
+    /// Incomplete fragmented frames
+    reassembler: Reassembler,
+    /// Id of the next fragmented frame
+    next_fragmented: u32,
+    /// Discovered path MTUs, kept past the sessions they were found in
+    pmtu_cache: PmtuCache,
+    /// Anti-replay state, if enabled
+    replay: Option<ReplayGuard>,
+    /// Filter of datagram senders
+    firewall: SharedFirewall,
+    /// Virtual session counters
+    admission: SharedAdmission,
+    /// Validates the address of a Hello before a session is created
+    cookies: CookieKey,
+    /// Virtual sessions, by remote address
+    sessions: HashMap<net::SocketAddr, UdpSession>,
+    /// Session addresses, by local connection ID
+    connections: HashMap<ConnectionId, net::SocketAddr>,
+    /// LAN discovery over multicast, if enabled
+    discovery: Option<Discovery>,
 }
 
 impl<N> UdpTransport<N>
@@ -48,24 +209,60 @@ where
     pub fn run(
         logic: NetAddr<N>,
         address: net::SocketAddr,
+        mut config: TransportConfig,
     ) -> Result<UdpActorAddr<N>, Box<error::Error>> {
         let socket = match UdpSocket::bind(&address) {
             Ok(s) => s,
             Err(e) => return Err(e.into()),
         };
 
-        let (sink, stream) = UdpFramed::new(socket, MessageCodec {}).split();
+        // store the actual IP address and port
+        let address = socket.local_addr()?;
+        config.listen_ports.udp = Some(address.port());
+
+        let state = SharedCodecState::default();
+        let codec = TransportCodec::from_config(&config, state.clone());
+        let reassembler = Reassembler::new(config.fragment.clone());
+        let pmtu_cache = PmtuCache::new(&config.pmtu);
+        let (sink, stream) = UdpFramed::new(socket, RawCodec).split();
         let (sender, receiver) = unbounded();
+        // private network tags are not bound to sessions over UDP; the
+        // sequence they cover keeps datagrams from being replayed, and
+        // nothing authenticates it without them
+        let replay = match (config.replay.clone(), config.codec.psk.is_some()) {
+            (Some(replay), true) => Some(replay),
+            (None, true) => Some(ReplayConfig::default()),
+            (Some(_), false) => return Err("UDP anti-replay requires a pre-shared key".into()),
+            (None, false) => None,
+        };
+        let replay = replay.map(ReplayGuard::new);
+        let firewall = config.firewall.clone();
+        // virtual sessions are not challenged with puzzles
+        let admission = config.admission.clone();
+        let cookies = CookieKey::generate(config.handshake_timeout)?;
+        let discovery = match config.discovery.clone() {
+            Some(discovery_config) => Some(Discovery::bind(discovery_config)?),
+            None => None,
+        };
 
         let router = UdpTransport::create(move |ctx| {
             let map_fn = |_: (FromErrType, MapErr<ReceiverType, _>)| ();
 
             // ingress stream
-            ctx.add_stream(stream.map(|(m, a)| UdpPacket {
+            ctx.add_stream(stream.map(|(d, a)| UdpPacket {
                 address: a,
-                message: m,
+                datagram: d,
             }));
 
+            // announcements multicast to the LAN
+            let discovery = discovery.map(|(discovery, socket)| {
+                ctx.add_stream(UdpFramed::new(socket, RawCodec).map(|(d, a)| DiscoveryPacket {
+                    address: a,
+                    datagram: d,
+                }));
+                discovery
+            });
+
             // egress stream
             Arbiter::handle().spawn(
                 sink.sink_from_err()
@@ -83,6 +280,19 @@ where
                 logic,
                 sender,
                 actor: ctx.address(),
+                config,
+                codec,
+                state,
+                reassembler,
+                next_fragmented: 0,
+                pmtu_cache,
+                replay,
+                firewall,
+                admission,
+                cookies,
+                sessions: HashMap::new(),
+                connections: HashMap::new(),
+                discovery,
             }
         });
 
@@ -90,6 +300,943 @@ where
     }
 }
 
+impl<N> UdpTransport<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    fn report(&self, address: net::SocketAddr, error: Error) {
+        let msg = TransportError {
+            transport: TransportProtocol::Udp,
+            address,
+            error,
+        };
+
+        let future = self.logic.send(msg).map_err(|_| {});
+
+        Arbiter::handle().spawn(future);
+    }
+
+    fn refuse(&self, address: net::SocketAddr, reason: RefusalReason) {
+        eprintln!("UDP: session with {} refused: {:?}", address, reason);
+
+        let msg = Refused {
+            transport: TransportProtocol::Udp,
+            address,
+            reason,
+        };
+
+        let future = self.logic.send(msg).map_err(|_| {});
+
+        Arbiter::handle().spawn(future);
+    }
+
+    /// Encodes a message into a single frame
+    fn encode(&mut self, address: net::SocketAddr, message: Message) -> Option<Bytes> {
+        let sequence = match self.replay {
+            Some(ref mut replay) => Some(replay.next(SystemTime::now())),
+            None => None,
+        };
+
+        let mut frame = BytesMut::new();
+        match self.codec.encode_message(message, sequence, &mut frame) {
+            Ok(_) => Some(frame.freeze()),
+            Err(e) => {
+                eprintln!("UDP: cannot encode message ({}): {}", address, e);
+                None
+            }
+        }
+    }
+
+    /// Largest datagram sent to `address`: the session's path MTU, a
+    /// cached one, or the fragmentation MTU
+    fn path_mtu(&self, address: &net::SocketAddr) -> usize {
+        match self.sessions.get(address) {
+            Some(session) => session.path_mtu,
+            None => self.pmtu_cache
+                .get(&address.ip(), Instant::now())
+                .unwrap_or(self.config.fragment.mtu),
+        }
+    }
+
+    /// Sends a message, fragmented at the path MTU; returns the length of
+    /// the frame sent, if any
+    fn send(&mut self, address: net::SocketAddr, message: Message) -> Option<usize> {
+        let frame = match self.encode(address, message) {
+            Some(frame) => frame,
+            None => return None,
+        };
+        let len = frame.len();
+
+        // session datagrams carry the remote node's connection ID
+        let remote_id = self.sessions.get(&address).and_then(UdpSession::remote_id);
+        let mtu = match remote_id {
+            Some(_) => self.path_mtu(&address).saturating_sub(connection::HEADER_SZ),
+            None => self.path_mtu(&address),
+        };
+
+        let id = self.next_fragmented;
+        let datagrams = match fragment::split(id, frame, mtu) {
+            Ok(datagrams) => datagrams,
+            Err(e) => {
+                eprintln!("UDP: cannot fragment message ({}): {}", address, e);
+                return None;
+            }
+        };
+        if datagrams.len() > 1 {
+            self.next_fragmented = id.wrapping_add(1);
+        }
+
+        // datagrams to session peers are paced
+        let datagrams = match self.sessions.get_mut(&address) {
+            Some(session) => {
+                let datagrams = datagrams
+                    .into_iter()
+                    .map(|datagram| match remote_id {
+                        Some(id) => connection::wrap(id, &datagram),
+                        None => datagram,
+                    })
+                    .collect();
+                if !session.congestion.push(datagrams) {
+                    eprintln!("UDP: send queue full, message dropped ({})", address);
+                }
+                Vec::new()
+            }
+            None => datagrams,
+        };
+
+        for datagram in datagrams {
+            self.transmit(address, datagram);
+        }
+        self.pace(address);
+        Some(len)
+    }
+
+    /// Sends a small control message in a single datagram, ahead of the
+    /// paced ones. Acknowledgements and handshake answers are not held
+    /// behind bulk data, which would inflate the peer's round-trip time.
+    fn send_control(&mut self, address: net::SocketAddr, message: Message) {
+        let frame = match self.encode(address, message) {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        let datagram = match self.sessions.get(&address).and_then(UdpSession::remote_id) {
+            Some(id) => connection::wrap(id, &frame),
+            None => frame,
+        };
+        self.transmit(address, datagram);
+    }
+
+    fn transmit(&self, address: net::SocketAddr, datagram: Bytes) {
+        if let Err(e) = self.sender.unbounded_send((datagram, address)) {
+            eprintln!("UDP unbounded send failed: {}", e);
+        }
+    }
+
+    /// Sends the queued datagrams the session's pacing rate allows
+    fn pace(&mut self, address: net::SocketAddr) {
+        let ready = match self.sessions.get_mut(&address) {
+            Some(session) => {
+                let ready = session.congestion.poll(Instant::now());
+                session.update_stats();
+                ready
+            }
+            None => return,
+        };
+
+        for datagram in ready {
+            self.transmit(address, datagram);
+        }
+    }
+
+    fn pace_all(&mut self) {
+        let queued: Vec<_> = self.sessions
+            .iter()
+            .filter(|&(_, session)| session.congestion.queued() > 0)
+            .map(|(address, _)| *address)
+            .collect();
+
+        for address in queued {
+            self.pace(address);
+        }
+    }
+
+    /// Sends pending reliable messages while the congestion window allows
+    fn release(&mut self, address: net::SocketAddr) {
+        let now = Instant::now();
+        let mut released = Vec::new();
+
+        if let Some(session) = self.sessions.get_mut(&address) {
+            while (session.outgoing.in_flight() as u32) < session.congestion.window() {
+                let (message, ordered) = match session.pending.pop_front() {
+                    Some(pending) => pending,
+                    None => break,
+                };
+
+                match session.outgoing.send(message, ordered, now) {
+                    Ok(message) => released.push(message),
+                    Err(e) => eprintln!("UDP: reliable message dropped ({}): {}", address, e),
+                }
+            }
+        }
+
+        for message in released {
+            self.send(address, message);
+        }
+    }
+
+    /// Starts a virtual session handshake with `address`
+    fn open(
+        &mut self,
+        address: net::SocketAddr,
+        initiator: bool,
+        slot: AdmissionSlot,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        let congestion = Congestion::new(self.config.congestion.clone(), now);
+        let stats = Rc::new(RefCell::new(CongestionStats::default()));
+        let path_mtu = self.path_mtu(&address);
+        let session = UdpSession {
+            nonce: identity::nonce()?,
+            remote_nonce: None,
+            cookie: None,
+            initiator,
+            peer: None,
+            verified: false,
+            connected: false,
+            started: now,
+            last_seen: now,
+            slot,
+            outgoing: ReliableSender::new(self.config.reliable.clone()),
+            incoming: ReliableReceiver::new(&self.config.reliable),
+            pending: VecDeque::new(),
+            congestion,
+            stats,
+            path_mtu,
+            pmtu: None,
+            large_losses: 0,
+            validations: HashMap::new(),
+        };
+
+        session.update_stats();
+        self.connections.insert(session.local_id(), address);
+        self.sessions.insert(address, session);
+        self.introduce(address);
+        Ok(())
+    }
+
+    /// Challenges the remote node to prove its identity. Repeated until the
+    /// remote node answers, since datagrams may be lost.
+    fn introduce(&mut self, address: net::SocketAddr) {
+        let hello = match self.sessions.get(&address) {
+            Some(session) => Hello {
+                version: self.config.version.clone(),
+                public_key: Bytes::from(self.config.identity.public_key()),
+                nonce: Bytes::from(&session.nonce[..]),
+                listen_ports: self.config.listen_ports,
+                cookie: session.cookie.clone().unwrap_or_default(),
+            },
+            None => return,
+        };
+
+        self.send(address, Message::Hello(hello));
+    }
+
+    /// Answers the remote node's challenge, accepting the session if it is
+    /// a new one. A Hello that would create a session, or replace one, is
+    /// first answered with a cookie, so that no state is kept for, and no
+    /// datagrams are repeated to, addresses the sender does not receive at.
+    fn hello(&mut self, address: net::SocketAddr, hello: Hello) {
+        if hello.nonce.len() != NONCE_SZ || &hello.public_key[..] == self.config.identity.public_key() {
+            eprintln!("UDP: invalid Hello ({})", address);
+            return;
+        }
+
+        // a fresh nonce means that the remote node started over
+        let (known, restarted) = match self.sessions.get(&address) {
+            Some(&UdpSession {
+                remote_nonce: Some(ref nonce),
+                ..
+            }) => (true, *nonce != hello.nonce),
+            Some(_) => (true, false),
+            None => (false, false),
+        };
+
+        if !known || restarted {
+            let now = SystemTime::now();
+            if !self.cookies.verify(&address, &hello.cookie, now) {
+                let cookie = Cookie {
+                    cookie: self.cookies.issue(&address, now),
+                };
+                return self.send_control(address, Message::Cookie(cookie));
+            }
+        }
+        if restarted {
+            self.close(address, DisconnectReason::Closed);
+        }
+
+        if !self.sessions.contains_key(&address) {
+            let slot = match self.admission.admit(address.ip()) {
+                Ok(slot) => slot,
+                Err(reason) => return self.refuse(address, reason),
+            };
+            if let Err(e) = self.open(address, false, slot) {
+                eprintln!("UDP: cannot start a session ({}): {}", address, e);
+                return;
+            }
+        }
+
+        if let Some(session) = self.sessions.get_mut(&address) {
+            if session.peer.is_none() {
+                session.remote_nonce = Some(hello.nonce.clone());
+                session.peer = Some(PeerInfo {
+                    peer_id: PeerId::from_public_key(&hello.public_key),
+                    public_key: hello.public_key.to_vec(),
+                    version: hello.version,
+                    listen_ports: hello.listen_ports,
+                });
+            }
+        }
+
+        // every copy is answered, as acknowledgements may be lost too
+        let signature = match self.sessions.get(&address) {
+            Some(session) => {
+                let transcript = HelloTranscript {
+                    initiator: session.initiator,
+                    signer_key: self.config.identity.public_key(),
+                    verifier_key: &hello.public_key,
+                    signer_nonce: &session.nonce,
+                    verifier_nonce: &hello.nonce,
+                    binding: &[],
+                };
+                self.config.identity.sign_hello(&transcript)
+            }
+            None => return,
+        };
+        let ack = HelloAck {
+            signature: Bytes::from(signature),
+        };
+        self.send_control(address, Message::HelloAck(ack));
+        self.announce(address);
+    }
+
+    /// Repeats our Hello with the cookie the remote node answered it with
+    fn cookie(&mut self, address: net::SocketAddr, cookie: Cookie) {
+        let first = match self.sessions.get_mut(&address) {
+            Some(session) if !session.verified && session.remote_nonce.is_none() => {
+                let first = session.cookie.is_none();
+                session.cookie = Some(cookie.cookie);
+                first
+            }
+            _ => return,
+        };
+
+        // later cookies are sent on the next tick
+        if first {
+            self.introduce(address);
+        }
+    }
+
+    /// Closes the session if the remote node signed its teardown
+    fn goodbye(&mut self, address: net::SocketAddr, goodbye: Goodbye) {
+        let valid = match self.sessions.get(&address) {
+            Some(session) => match (&session.peer, &session.remote_nonce) {
+                (&Some(ref peer), &Some(ref remote_nonce)) => identity::verify_goodbye(
+                    &peer.public_key,
+                    remote_nonce,
+                    &session.nonce,
+                    &goodbye.signature,
+                ),
+                _ => false,
+            },
+            None => return,
+        };
+
+        if !valid {
+            return eprintln!("UDP: invalid Goodbye ({})", address);
+        }
+        self.close(address, DisconnectReason::Closed);
+    }
+
+    /// Verifies the remote node's answer to our challenge
+    fn hello_ack(&mut self, address: net::SocketAddr, ack: HelloAck) {
+        let verified = match self.sessions.get(&address) {
+            Some(session) if !session.verified => match (&session.peer, &session.remote_nonce) {
+                (&Some(ref peer), &Some(ref remote_nonce)) => {
+                    let transcript = HelloTranscript {
+                        initiator: !session.initiator,
+                        signer_key: &peer.public_key,
+                        verifier_key: self.config.identity.public_key(),
+                        signer_nonce: remote_nonce,
+                        verifier_nonce: &session.nonce,
+                        binding: &[],
+                    };
+                    identity::verify_hello(&transcript, &ack.signature)
+                }
+                // answered before its Hello arrived; retried on the next tick
+                _ => return,
+            },
+            _ => return,
+        };
+
+        if !verified {
+            eprintln!("UDP: invalid HelloAck ({})", address);
+            return;
+        }
+
+        if let Some(session) = self.sessions.get_mut(&address) {
+            session.verified = true;
+        }
+        self.announce(address);
+    }
+
+    /// Announces the session to the network, once the remote node is
+    /// authenticated
+    fn announce(&mut self, address: net::SocketAddr) {
+        let (initiator, peer, stats) = match self.sessions.get_mut(&address) {
+            Some(session) => {
+                if !session.verified || session.connected {
+                    return;
+                }
+
+                let peer = match session.peer {
+                    Some(ref peer) => peer.clone(),
+                    None => return,
+                };
+
+                session.connected = true;
+                session.slot.established();
+                if let Some(ref mut replay) = self.replay {
+                    replay.pin(address);
+                }
+                (session.initiator, peer, session.stats.clone())
+            }
+            None => return,
+        };
+
+        let msg = Connected {
+            transport: TransportProtocol::Udp,
+            address,
+            session: TransportSession::Udp(self.actor.clone()),
+            codec: self.state.clone(),
+            initiator,
+            fingerprint: None,
+            peer,
+            congestion: Some(stats),
+        };
+
+        let future = self.logic.send(msg)
+            .map_err(|_| eprintln!("UDP: failed to send 'Connected' event"));
+
+        Arbiter::handle().spawn(future);
+    }
+
+    /// Acknowledges a reliable message and delivers what it completes
+    fn reliable(&mut self, address: net::SocketAddr, reliable: Reliable) {
+        let (delivered, ack) = match self.sessions.get_mut(&address) {
+            Some(session) => {
+                let delivered = session.incoming.receive(reliable);
+                (delivered, session.incoming.ack())
+            }
+            // retransmitted once the session is established
+            None => return,
+        };
+
+        self.send_control(address, Message::Ack(ack));
+        for payload in delivered {
+            self.deliver(address, payload.into_message());
+        }
+    }
+
+    /// Opens the congestion window for acknowledged messages
+    fn ack(&mut self, address: net::SocketAddr, ack: Ack) {
+        match self.sessions.get_mut(&address) {
+            Some(session) => {
+                let acked = session.outgoing.ack(&ack, Instant::now());
+                if session.outgoing.in_flight() == 0 {
+                    session.large_losses = 0;
+                }
+                session.congestion.on_ack(acked);
+                session.congestion.update_rate(session.outgoing.srtt(), session.path_mtu);
+                session.update_stats();
+            }
+            None => return,
+        }
+
+        self.release(address);
+    }
+
+    /// Retransmits unacknowledged reliable messages, shrinking the
+    /// congestion window. Sessions with a message undelivered after all
+    /// retransmissions are closed, and those repeatedly losing datagrams
+    /// above the fragmentation MTU fall back to it.
+    fn retransmit(&mut self) {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut lost = Vec::new();
+
+        for (address, session) in self.sessions.iter_mut() {
+            let messages = match session.outgoing.poll(now) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    lost.push((*address, e));
+                    continue;
+                }
+            };
+            if messages.is_empty() {
+                continue;
+            }
+
+            let rtt = session.outgoing.srtt().unwrap_or_else(|| session.outgoing.rto());
+            session.congestion.on_loss(now, rtt);
+            session.congestion.update_rate(session.outgoing.srtt(), session.path_mtu);
+            session.update_stats();
+            due.extend(messages.into_iter().map(|m| (*address, m)));
+        }
+
+        let base = self.config.fragment.mtu;
+        let mut large = Vec::new();
+        for (address, message) in due {
+            match self.send(address, message) {
+                Some(len) if len > base && !large.contains(&address) => large.push(address),
+                _ => {}
+            }
+        }
+        for address in large {
+            self.black_hole(address);
+        }
+        for (address, e) in lost {
+            eprintln!("UDP: reliable delivery failed ({}): {}", address, e);
+            self.close(address, DisconnectReason::Closed);
+        }
+    }
+
+    /// Counts a retransmission round of datagrams above the fragmentation
+    /// MTU. Once they keep getting lost, the path MTU shrank under the
+    /// discovered one: it falls back to the fragmentation MTU, and the path
+    /// is searched again.
+    fn black_hole(&mut self, address: net::SocketAddr) {
+        let base = self.config.fragment.mtu;
+        match self.sessions.get_mut(&address) {
+            Some(session) => {
+                if session.path_mtu <= base {
+                    return;
+                }
+                session.large_losses += 1;
+                if session.large_losses < self.config.pmtu.max_losses {
+                    return;
+                }
+
+                session.large_losses = 0;
+                session.path_mtu = base;
+                session.pmtu = None;
+                session.congestion.update_rate(session.outgoing.srtt(), base);
+                session.update_stats();
+            }
+            None => return,
+        }
+
+        eprintln!("UDP: datagrams to {} lost, path MTU lowered to {}", address, base);
+        self.pmtu_cache.remove(&address.ip());
+    }
+
+    /// Sends the next path MTU probe to the remote node, and caches the
+    /// path MTU once the search is complete
+    fn probe(&mut self, address: net::SocketAddr) {
+        let now = Instant::now();
+        let probe = {
+            let config = &self.config.pmtu;
+            match self.sessions.get_mut(&address) {
+                Some(session) => {
+                    let probe = match session.pmtu {
+                        Some(ref mut search) => search.poll(now, config),
+                        None => return,
+                    };
+
+                    if probe.is_none() && session.pmtu.as_ref().map_or(false, |s| s.complete(config)) {
+                        let mtu = session.pmtu.take().map_or(session.path_mtu, |s| s.mtu());
+                        if mtu != session.path_mtu {
+                            eprintln!("UDP: path MTU to {} is {}", address, mtu);
+                        }
+
+                        session.path_mtu = mtu;
+                        session.congestion.update_rate(session.outgoing.srtt(), mtu);
+                        session.update_stats();
+                        self.pmtu_cache.insert(address.ip(), mtu, now);
+                    }
+                    probe
+                }
+                None => return,
+            }
+        };
+
+        if let Some((id, size)) = probe {
+            self.send_probe(address, id, size);
+        }
+    }
+
+    /// Sends a probe padded to `size` bytes. Probes are paced, but never
+    /// fragmented.
+    fn send_probe(&mut self, address: net::SocketAddr, id: u32, size: usize) {
+        let remote_id = self.sessions.get(&address).and_then(UdpSession::remote_id);
+        let size = match remote_id {
+            Some(_) => size.saturating_sub(connection::HEADER_SZ),
+            None => size,
+        };
+        let random = SystemRandom::new();
+        let mut padding = 0;
+        let mut datagram = None;
+
+        // the encoded padding length varies with the padding; a few
+        // rounds settle it
+        for _ in 0..4 {
+            let mut bytes = vec![0u8; padding];
+            if random.fill(&mut bytes).is_err() {
+                return eprintln!("UDP: cannot generate probe padding ({})", address);
+            }
+
+            let probe = Probe {
+                id,
+                padding: Bytes::from(bytes),
+            };
+            let encoded = match self.encode(address, Message::Probe(probe)) {
+                Some(encoded) => encoded,
+                None => return,
+            };
+
+            let len = encoded.len();
+            if len <= size {
+                datagram = Some(encoded);
+            }
+            if len == size || padding + size < len {
+                break;
+            }
+            padding = padding + size - len;
+        }
+
+        // a smaller datagram is acknowledged with its own size
+        let datagram = match datagram {
+            Some(datagram) => datagram,
+            None => return,
+        };
+
+        let datagram = match remote_id {
+            Some(id) => connection::wrap(id, &datagram),
+            None => datagram,
+        };
+
+        match self.sessions.get_mut(&address) {
+            Some(session) => {
+                if !session.congestion.push(vec![datagram]) {
+                    eprintln!("UDP: send queue full, probe dropped ({})", address);
+                }
+            }
+            None => return,
+        }
+        self.pace(address);
+    }
+
+    /// Raises the path MTU to an acknowledged probe's size and continues
+    /// the search
+    fn probe_ack(&mut self, address: net::SocketAddr, ack: ProbeAck) {
+        match self.sessions.get_mut(&address) {
+            Some(session) => {
+                let mtu = match session.pmtu {
+                    Some(ref mut search) => match search.ack(ack.id, ack.size as usize) {
+                        true => search.mtu(),
+                        false => return,
+                    },
+                    None => return,
+                };
+
+                // a repeated search keeps the previous value until complete
+                session.path_mtu = session.path_mtu.max(mtu);
+                session.congestion.update_rate(session.outgoing.srtt(), session.path_mtu);
+                session.update_stats();
+            }
+            None => return,
+        }
+
+        self.probe(address);
+    }
+
+    /// Handles a datagram of a session sent from a new address. The
+    /// session migrates once the remote node proves its identity there;
+    /// until then, only path validation messages are accepted.
+    fn migrating(&mut self, address: net::SocketAddr, from: net::SocketAddr, message: Message) {
+        match message {
+            Message::PathResponse(response) => self.path_response(address, from, response),
+            _ => self.challenge(address, from),
+        }
+    }
+
+    /// Challenges the remote node of a session at a new address. Each
+    /// address is challenged at most once per tick, with the same nonce
+    /// until the challenge expires. Challenges of other addresses, which
+    /// spoofed datagrams may come from, never replace a pending one.
+    fn challenge(&mut self, address: net::SocketAddr, from: net::SocketAddr) {
+        let now = Instant::now();
+        let expiry = self.config.handshake_timeout;
+        let (remote_id, nonce) = match self.sessions.get_mut(&address) {
+            Some(ref mut session) if session.connected => {
+                session.validations.retain(|_, v| now.duration_since(v.issued) < expiry);
+                let full = session.validations.len() >= MAX_VALIDATIONS;
+
+                let nonce = match session.validations.entry(from) {
+                    Entry::Occupied(mut entry) => {
+                        let v = entry.get_mut();
+                        if now.duration_since(v.sent) < Duration::from_secs(TICK_SECS) {
+                            return;
+                        }
+                        v.sent = now;
+                        v.nonce
+                    }
+                    Entry::Vacant(_) if full => return,
+                    Entry::Vacant(entry) => {
+                        let nonce = match identity::nonce() {
+                            Ok(nonce) => nonce,
+                            Err(e) => {
+                                return eprintln!("UDP: cannot challenge {} ({}): {}", from, address, e)
+                            }
+                        };
+                        entry.insert(Validation {
+                            nonce,
+                            issued: now,
+                            sent: now,
+                        });
+                        nonce
+                    }
+                };
+                (session.remote_id(), nonce)
+            }
+            _ => return,
+        };
+
+        // sequenced as part of the session, but sent to the new address
+        let challenge = PathChallenge {
+            connection_id: remote_id.map_or(0, |id| id.0),
+            nonce: Bytes::from(&nonce[..]),
+            address: from,
+        };
+        let frame = match self.encode(address, Message::PathChallenge(challenge)) {
+            Some(frame) => frame,
+            None => return,
+        };
+        let datagram = match remote_id {
+            Some(id) => connection::wrap(id, &frame),
+            None => frame,
+        };
+        self.transmit(from, datagram);
+    }
+
+    /// Answers the challenge of our new address, if it was issued in this
+    /// session
+    fn path_challenge(&mut self, address: net::SocketAddr, challenge: PathChallenge) {
+        let signature = match self.sessions.get(&address) {
+            Some(session) if session.connected && challenge.connection_id == session.local_id().0 => {
+                let remote_nonce = match session.remote_nonce {
+                    Some(ref nonce) => nonce,
+                    None => return,
+                };
+                let transcript = PathTranscript {
+                    connection_id: challenge.connection_id,
+                    challenger_nonce: remote_nonce,
+                    responder_nonce: &session.nonce,
+                    nonce: &challenge.nonce,
+                    address: challenge.address,
+                };
+                self.config.identity.sign_path_challenge(&transcript)
+            }
+            _ => return eprintln!("UDP: invalid path challenge ({})", address),
+        };
+
+        let response = PathResponse {
+            signature: Bytes::from(signature),
+        };
+        self.send_control(address, Message::PathResponse(response));
+    }
+
+    /// Migrates the session if the remote node answered the challenge of
+    /// its new address
+    fn path_response(&mut self, address: net::SocketAddr, from: net::SocketAddr, response: PathResponse) {
+        let valid = match self.sessions.get(&address) {
+            Some(session) => match (session.validations.get(&from), &session.peer, &session.remote_nonce) {
+                (Some(v), &Some(ref peer), &Some(ref remote_nonce)) => {
+                    let transcript = PathTranscript {
+                        connection_id: ConnectionId::from_nonce(remote_nonce).0,
+                        challenger_nonce: &session.nonce,
+                        responder_nonce: remote_nonce,
+                        nonce: &v.nonce,
+                        address: from,
+                    };
+                    identity::verify_path_challenge(&peer.public_key, &transcript, &response.signature)
+                }
+                _ => false,
+            },
+            None => false,
+        };
+
+        if !valid {
+            return eprintln!("UDP: invalid path response ({} from {})", address, from);
+        }
+        self.migrate(address, from);
+    }
+
+    /// Moves a session to a new remote address
+    fn migrate(&mut self, from: net::SocketAddr, to: net::SocketAddr) {
+        // a stale session at the new address is superseded
+        self.close(to, DisconnectReason::Closed);
+
+        let mut session = match self.sessions.remove(&from) {
+            Some(session) => session,
+            None => return,
+        };
+
+        // the new path is searched for its own MTU
+        session.path_mtu = self.path_mtu(&to);
+        session.pmtu = None;
+        session.large_losses = 0;
+        session.validations.clear();
+        session.last_seen = Instant::now();
+        session.update_stats();
+
+        self.connections.insert(session.local_id(), to);
+        self.sessions.insert(to, session);
+        if let Some(ref mut replay) = self.replay {
+            replay.migrate(from, to);
+        }
+
+        eprintln!("UDP: session with {} migrated to {}", from, to);
+
+        let msg = Migrated {
+            transport: TransportProtocol::Udp,
+            from,
+            to,
+        };
+
+        let future = self.logic.send(msg)
+            .map_err(|_| eprintln!("UDP: failed to send 'Migrated' event"));
+
+        Arbiter::handle().spawn(future);
+    }
+
+    /// Multicasts our identity and listen ports to the LAN
+    fn discover(&mut self) {
+        let discovery = match self.discovery {
+            Some(ref mut discovery) => discovery,
+            None => return,
+        };
+
+        let now = SystemTime::now();
+        let announcement = Announcement::new(
+            &self.config.identity,
+            self.config.version.clone(),
+            self.config.listen_ports,
+            discovery.next_sequence(now),
+            now,
+        );
+        if let Err(e) = discovery.announce(&announcement) {
+            eprintln!("UDP: cannot announce the node: {}", e);
+        }
+    }
+
+    fn deliver(&self, address: net::SocketAddr, message: Message) {
+        let msg = ReceivedMessage {
+            transport: TransportProtocol::Udp,
+            address,
+            message,
+        };
+
+        let future = self.logic.send(msg).map_err(|_| {});
+
+        Arbiter::handle().spawn(future);
+    }
+
+    /// Removes a session. Announced sessions and our own failed attempts
+    /// are reported as disconnected.
+    fn close(&mut self, address: net::SocketAddr, reason: DisconnectReason) {
+        let session = match self.sessions.remove(&address) {
+            Some(session) => session,
+            None => return,
+        };
+        self.connections.remove(&session.local_id());
+        if let Some(ref mut replay) = self.replay {
+            replay.release(address);
+        }
+        if !session.connected && !session.initiator {
+            return;
+        }
+
+        let msg = Disconnected {
+            transport: TransportProtocol::Udp,
+            address,
+            reason,
+        };
+
+        let future = self.logic.send(msg)
+            .map_err(|_| eprintln!("UDP: failed to send 'Disconnected' event"));
+
+        Arbiter::handle().spawn(future);
+    }
+
+    /// Expires idle sessions and stalled handshakes, repeats unanswered
+    /// challenges and drives path MTU searches
+    fn tick(&mut self) {
+        let now = Instant::now();
+        self.reassembler.expire(now);
+
+        let handshake_timeout = self.config.handshake_timeout;
+        let idle_timeout = self.config.udp_idle_timeout;
+
+        let expired: Vec<_> = self.sessions
+            .iter()
+            .filter_map(|(address, session)| match session.connected {
+                true if now.duration_since(session.last_seen) >= idle_timeout => {
+                    Some((*address, DisconnectReason::IdleTimeout))
+                }
+                false if now.duration_since(session.started) >= handshake_timeout => {
+                    Some((*address, DisconnectReason::HandshakeTimeout))
+                }
+                _ => None,
+            })
+            .collect();
+
+        for (address, reason) in expired {
+            eprintln!("UDP: session with {} expired: {:?}", address, reason);
+            self.close(address, reason);
+        }
+
+        let unanswered: Vec<_> = self.sessions
+            .iter()
+            .filter(|&(_, session)| !session.verified)
+            .map(|(address, _)| *address)
+            .collect();
+
+        for address in unanswered {
+            self.introduce(address);
+        }
+
+        // paths without a valid cached MTU are searched again
+        self.pmtu_cache.expire(now);
+        for (address, session) in self.sessions.iter_mut() {
+            if session.connected && session.pmtu.is_none() && self.pmtu_cache.get(&address.ip(), now).is_none() {
+                session.pmtu = Some(PmtuSearch::new(self.config.fragment.mtu, &self.config.pmtu));
+            }
+        }
+
+        let searching: Vec<_> = self.sessions
+            .iter()
+            .filter(|&(_, session)| session.pmtu.is_some())
+            .map(|(address, _)| *address)
+            .collect();
+
+        for address in searching {
+            self.probe(address);
+        }
+    }
+}
+
 impl<N> Handler<Stop> for UdpTransport<N>
 where
     N: Network + 'static,
@@ -111,9 +1258,108 @@ where
     type Result = NoResult;
 
     fn handle(&mut self, msg: SessionSendMessage, _ctx: &mut Self::Context) {
-        let data = (msg.message, msg.address);
-        if let Err(e) = self.sender.unbounded_send(data) {
-            eprintln!("UDP unbounded send failed: {}", e);
+        let ordered = match msg.delivery {
+            Delivery::Unreliable => {
+                self.send(msg.address, msg.message);
+                return;
+            }
+            Delivery::Reliable => false,
+            Delivery::Ordered => true,
+        };
+        let payload = match Payload::from_message(msg.message) {
+            Some(payload) => payload,
+            None => return eprintln!("UDP: only application messages are delivered reliably"),
+        };
+
+        match self.sessions.get_mut(&msg.address) {
+            Some(session) => {
+                // waits for room in the congestion window
+                if session.pending.len() >= self.config.congestion.max_pending {
+                    session.congestion.drop_message();
+                    return eprintln!("UDP: reliable queue full, message dropped ({})", msg.address);
+                }
+                session.pending.push_back((payload, ordered));
+            }
+            None => {
+                return eprintln!("UDP: no session for reliable delivery ({})", msg.address);
+            }
+        }
+
+        self.release(msg.address);
+    }
+}
+
+impl<N> Handler<Connect> for UdpTransport<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    type Result = EmptyResult;
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) -> Self::Result {
+        if !self.firewall.allows(&msg.address.ip()) {
+            self.refuse(msg.address, RefusalReason::Firewall);
+            return Ok(());
+        }
+        if self.sessions.contains_key(&msg.address) {
+            return Ok(());
+        }
+
+        let slot = match self.admission.admit(msg.address.ip()) {
+            Ok(slot) => slot,
+            Err(reason) => {
+                self.refuse(msg.address, reason);
+                return Ok(());
+            }
+        };
+
+        self.open(msg.address, true, slot).map_err(|e| {
+            eprintln!("UDP: cannot connect to {}: {}", msg.address, e);
+            MailboxError::Closed
+        })
+    }
+}
+
+impl<N> Handler<Close> for UdpTransport<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    type Result = EmptyResult;
+
+    fn handle(&mut self, msg: Close, _ctx: &mut Self::Context) -> Self::Result {
+        let signature = match self.sessions.get(&msg.address) {
+            Some(session) => session
+                .remote_nonce
+                .as_ref()
+                .map(|remote_nonce| self.config.identity.sign_goodbye(&session.nonce, remote_nonce)),
+            None => return Err(MailboxError::Closed),
+        };
+
+        // a session the remote node never introduced itself in is left to
+        // expire there
+        if let Some(signature) = signature {
+            let goodbye = Goodbye {
+                signature: Bytes::from(signature),
+            };
+            self.send_control(msg.address, Message::Goodbye(goodbye));
+        }
+        self.close(msg.address, msg.reason);
+        Ok(())
+    }
+}
+
+impl<N> Handler<GetReplayStats> for UdpTransport<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    type Result = ReplayStatsResult;
+
+    fn handle(&mut self, _: GetReplayStats, _ctx: &mut Self::Context) -> Self::Result {
+        match self.replay {
+            Some(ref replay) => Ok(replay.stats()),
+            None => Ok(ReplayStats::default()),
         }
     }
 }
@@ -130,16 +1376,128 @@ where
     N::Context: AsyncContext<N>,
 {
     fn handle(&mut self, pkt: UdpPacket, _: &mut Context<Self>) {
-        let msg = ReceivedMessage {
-            transport: TransportProtocol::Udp,
-            address: self.address.clone(),
-            message: pkt.message,
+        if !self.firewall.allows(&pkt.address.ip()) {
+            return;
+        }
+
+        let size = pkt.datagram.len();
+        let (connection_id, datagram) = connection::unwrap(pkt.datagram);
+
+        // a known connection ID from another address is a possible migration
+        let migrating = match connection_id.and_then(|id| self.connections.get(&id).cloned()) {
+            Some(address) if address != pkt.address => Some(address),
+            _ => None,
+        };
+
+        let mut frame = match fragment::is_fragment(&datagram) {
+            false => datagram,
+            true => match self.reassembler.insert(pkt.address, &datagram, Instant::now()) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return,
+                Err(e) => return eprintln!("UDP: fragment dropped ({}): {}", pkt.address, e),
+            },
+        };
+
+        // each datagram, or set of fragments, holds a single complete frame
+        let decoded = match self.codec.decode_sequenced(&mut frame) {
+            Ok(Some(datagram)) => Ok(datagram),
+            Ok(None) => Err(CodecError::new("truncated datagram")),
+            Err(e) => Err(e),
+        };
+
+        let (message, sequence) = match decoded {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                // datagrams are self-contained; drop the malformed one
+                eprintln!("UDP: message error ({}): {}", pkt.address, e);
+                return self.report(pkt.address, Error::from(e));
+            }
+        };
+
+        if let Some(ref mut replay) = self.replay {
+            // replayed and stale datagrams are counted, not reported; those
+            // of a migrating session continue its sequence
+            let address = migrating.unwrap_or(pkt.address);
+            if !replay.check(address, sequence, SystemTime::now()) {
+                return;
+            }
+        }
+
+        if let Some(address) = migrating {
+            return self.migrating(address, pkt.address, message);
+        }
+
+        if let Some(session) = self.sessions.get_mut(&pkt.address) {
+            session.last_seen = Instant::now();
+        }
+
+        let message = match message {
+            Message::Hello(hello) => return self.hello(pkt.address, hello),
+            Message::HelloAck(ack) => return self.hello_ack(pkt.address, ack),
+            // unauthenticated; sessions end with a signed Goodbye
+            Message::Disconnect => return,
+            Message::Goodbye(goodbye) => return self.goodbye(pkt.address, goodbye),
+            Message::Cookie(cookie) => return self.cookie(pkt.address, cookie),
+            Message::Reliable(reliable) => return self.reliable(pkt.address, reliable),
+            Message::Ack(ack) => return self.ack(pkt.address, ack),
+            Message::Probe(probe) => {
+                if self.sessions.contains_key(&pkt.address) {
+                    let ack = ProbeAck {
+                        id: probe.id,
+                        size: size as u32,
+                    };
+                    self.send_control(pkt.address, Message::ProbeAck(ack));
+                }
+                return;
+            }
+            Message::ProbeAck(ack) => return self.probe_ack(pkt.address, ack),
+            Message::PathChallenge(challenge) => return self.path_challenge(pkt.address, challenge),
+            // late or repeated; the session is already at this address
+            Message::PathResponse(_) => return,
+            // stream sessions only
+            Message::SessionNonce(_) => return,
+            message => message,
+        };
+
+        self.deliver(pkt.address, message);
+    }
+}
+
+impl<N> StreamHandler<DiscoveryPacket, CodecError> for UdpTransport<N>
+where
+    N: Network + 'static,
+    N::Context: AsyncContext<N>,
+{
+    fn handle(&mut self, pkt: DiscoveryPacket, _: &mut Context<Self>) {
+        if !self.firewall.allows(&pkt.address.ip()) {
+            return;
+        }
+
+        let own_key = self.config.identity.public_key();
+        let peer = match self.discovery {
+            Some(ref mut discovery) => discovery.receive(pkt.address, &pkt.datagram, own_key, SystemTime::now()),
+            None => return,
+        };
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        let msg = PeerDiscovered {
+            address: pkt.address,
+            peer,
         };
 
         let future = self.logic.send(msg).map_err(|_| {});
 
         Arbiter::handle().spawn(future);
     }
+
+    fn error(&mut self, err: CodecError, _: &mut Context<Self>) -> Running {
+        // discovery is optional; the transport keeps running without it
+        eprintln!("UDP: discovery error: {}", err);
+        Running::Continue
+    }
 }
 
 impl<N> Actor for UdpTransport<N>
@@ -149,10 +1507,23 @@ where
 {
     type Context = Context<Self>;
 
-    fn started(&mut self, _: &mut <Self as Actor>::Context) {
+    fn started(&mut self, ctx: &mut <Self as Actor>::Context) {
+        run_interval(ctx, Duration::from_secs(TICK_SECS), |act: &mut Self, _| act.tick());
+        run_interval(ctx, Duration::from_millis(RETRANSMIT_MILLIS), |act: &mut Self, _| {
+            act.retransmit()
+        });
+        run_interval(ctx, Duration::from_millis(PACE_MILLIS), |act: &mut Self, _| act.pace_all());
+
+        if let Some(interval) = self.discovery.as_ref().map(Discovery::interval) {
+            self.discover();
+            run_interval(ctx, interval, |act: &mut Self, _| act.discover());
+        }
+
         let actor = Transport::Udp(self.actor.clone());
         let address = self.address.clone();
-        let msg = Listening{ actor, address };
+        let firewall = self.firewall.clone();
+        let admission = self.admission.clone();
+        let msg = Listening{ actor, address, firewall, admission };
 
         let future = self.logic.send(msg).map_err(|_| {});
 
@@ -160,6 +1531,11 @@ where
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        let addresses: Vec<_> = self.sessions.keys().cloned().collect();
+        for address in addresses {
+            self.close(address, DisconnectReason::Requested);
+        }
+
         let actor = Transport::Udp(self.actor.clone());
         let address = self.address.clone();
         let msg = Stopped{ actor, address };
@@ -170,3 +1546,350 @@ where
         Running::Stop
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bytes::{Bytes, BytesMut};
+    use futures::Future;
+    use tokio_codec::{Decoder, Encoder};
+
+    use codec::message::{Hello, ListenPorts, Message};
+    use codec::MessageCodec;
+    use event::Event;
+    use identity::{self, Identity};
+    use transport::config::TransportConfig;
+    use transport::connection;
+    use transport::message::GetNodeStats;
+    use transport::testing::{Node, TIMEOUT_SECS};
+    use transport::{DisconnectReason, TransportProtocol};
+
+    fn connected(event: &Event) -> bool {
+        match *event {
+            Event::Connected(TransportProtocol::Udp, ..) => true,
+            _ => false,
+        }
+    }
+
+    fn disconnected(event: &Event) -> bool {
+        match *event {
+            Event::Disconnected(TransportProtocol::Udp, ..) => true,
+            _ => false,
+        }
+    }
+
+    fn received(protocol_id: u16) -> impl Fn(&Event) -> bool {
+        move |event: &Event| match *event {
+            Event::Message(TransportProtocol::Udp, _, ref e, ..) => e.protocol_id == protocol_id,
+            _ => false,
+        }
+    }
+
+    fn encode(message: Message) -> Vec<u8> {
+        let mut bytes = BytesMut::new();
+        MessageCodec::default().encode(message, &mut bytes).unwrap();
+        bytes.to_vec()
+    }
+
+    fn decode(datagram: &[u8]) -> Option<Message> {
+        let (_, mut frame) = connection::unwrap(BytesMut::from(datagram));
+        MessageCodec::default().decode(&mut frame).ok().and_then(|m| m)
+    }
+
+    fn hello(identity: &Identity, cookie: Bytes) -> Message {
+        Message::Hello(Hello {
+            version: "test".to_owned(),
+            public_key: Bytes::from(identity.public_key()),
+            nonce: Bytes::from(&identity::nonce().unwrap()[..]),
+            listen_ports: ListenPorts::default(),
+            cookie,
+        })
+    }
+
+    enum Command {
+        /// Forward from the second outer address, as after a NAT rebinding
+        Rebind,
+        /// Stop forwarding
+        Cut,
+        /// Send a datagram to the target from the current outer address
+        Inject(Vec<u8>),
+        /// Hold back datagrams to the node while set, then forward them
+        Hold(bool),
+        /// Repeat the node's last datagram to the target from a third
+        /// address, as an observer spoofing its connection ID would
+        Spoof,
+    }
+
+    /// Forwards datagrams between a node and `target`, from one of two
+    /// outer addresses. Returns the inner address and the outer ones.
+    fn relay(target: SocketAddr, commands: mpsc::Receiver<Command>) -> (SocketAddr, [SocketAddr; 2]) {
+        let bind = || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+            socket
+        };
+        let inner = bind();
+        let outer = [bind(), bind()];
+        let addresses = (
+            inner.local_addr().unwrap(),
+            [outer[0].local_addr().unwrap(), outer[1].local_addr().unwrap()],
+        );
+
+        thread::spawn(move || {
+            let spoofer = bind();
+            let mut buf = vec![0u8; 65536];
+            let mut node = None;
+            let mut last = Vec::new();
+            let mut held = Vec::new();
+            let mut current = 0;
+            let mut forwarding = true;
+            let mut holding = false;
+
+            loop {
+                loop {
+                    match commands.try_recv() {
+                        Ok(Command::Rebind) => current = 1,
+                        Ok(Command::Cut) => forwarding = false,
+                        Ok(Command::Inject(datagram)) => {
+                            outer[current].send_to(&datagram, target).ok();
+                        }
+                        Ok(Command::Hold(hold)) => holding = hold,
+                        Ok(Command::Spoof) => {
+                            spoofer.send_to(&last, target).ok();
+                        }
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => return,
+                    }
+                }
+
+                if let Ok((len, from)) = inner.recv_from(&mut buf) {
+                    node = Some(from);
+                    last = buf[..len].to_vec();
+                    if forwarding {
+                        outer[current].send_to(&buf[..len], target).ok();
+                    }
+                }
+                for socket in outer.iter() {
+                    if let Ok((len, _)) = socket.recv_from(&mut buf) {
+                        held.push(buf[..len].to_vec());
+                    }
+                }
+                if let (false, Some(node)) = (holding, node) {
+                    for datagram in held.drain(..) {
+                        if forwarding {
+                            inner.send_to(&datagram, node).ok();
+                        }
+                    }
+                }
+            }
+        });
+
+        addresses
+    }
+
+    /// Server and client nodes with a session through a relay
+    fn session(server: TransportConfig, client: TransportConfig) -> (Node, Node, mpsc::Sender<Command>, SocketAddr, [SocketAddr; 2]) {
+        let server = Node::run(TransportProtocol::Udp, server);
+        let client = Node::run(TransportProtocol::Udp, client);
+        let (commands, rx) = mpsc::channel();
+        let (inner, outer) = relay(server.address, rx);
+
+        client.connect(inner);
+        match client.expect(connected) {
+            Event::Connected(_, address, true, ..) => assert_eq!(inner, address),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        match server.expect(connected) {
+            Event::Connected(_, address, false, ..) => assert_eq!(outer[0], address),
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        (server, client, commands, inner, outer)
+    }
+
+    #[test]
+    fn test_session() {
+        let (server, client, _relay, inner, outer) =
+            session(TransportConfig::generate().unwrap(), TransportConfig::generate().unwrap());
+
+        client.send(inner, 1);
+        server.expect(received(1));
+        server.send(outer[0], 2);
+        client.expect(received(2));
+
+        // the node counts its sessions without a TCP transport
+        let stats = server.network.send(GetNodeStats).wait().unwrap().unwrap();
+        assert_eq!(1, stats.sessions);
+
+        // the remote node is told with a signed Goodbye
+        client.disconnect(inner);
+        match client.expect(disconnected) {
+            Event::Disconnected(_, _, reason) => assert_eq!(DisconnectReason::Requested, reason),
+            _ => unreachable!(),
+        }
+        match server.expect(disconnected) {
+            Event::Disconnected(_, address, reason) => {
+                assert_eq!(outer[0], address);
+                assert_eq!(DisconnectReason::Closed, reason);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let config = || TransportConfig {
+            udp_idle_timeout: Duration::from_secs(2),
+            ..TransportConfig::generate().unwrap()
+        };
+        let (server, client, relay, inner, outer) = session(config(), config());
+
+        relay.send(Command::Cut).unwrap();
+        match server.expect(disconnected) {
+            Event::Disconnected(_, address, reason) => {
+                assert_eq!(outer[0], address);
+                assert_eq!(DisconnectReason::IdleTimeout, reason);
+            }
+            _ => unreachable!(),
+        }
+        match client.expect(disconnected) {
+            Event::Disconnected(_, address, reason) => {
+                assert_eq!(inner, address);
+                assert_eq!(DisconnectReason::IdleTimeout, reason);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_spoofed_teardown() {
+        let (server, client, relay, inner, outer) =
+            session(TransportConfig::generate().unwrap(), TransportConfig::generate().unwrap());
+        let impostor = Identity::generate().unwrap();
+
+        // an unsigned Disconnect, and a Hello starting the session over,
+        // sent from the client's address
+        relay.send(Command::Inject(encode(Message::Disconnect))).unwrap();
+        relay.send(Command::Inject(encode(hello(&impostor, Bytes::new())))).unwrap();
+
+        let events = server.collect(Duration::from_secs(2));
+        assert!(!events.iter().any(disconnected), "session closed: {:?}", events);
+
+        client.send(inner, 3);
+        match server.expect(received(3)) {
+            Event::Message(_, address, ..) => assert_eq!(outer[0], address),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_cookie() {
+        let server = Node::run(TransportProtocol::Udp, TransportConfig::generate().unwrap());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let identity = Identity::generate().unwrap();
+
+        // a Hello from a new address is answered with a smaller cookie
+        // datagram only; no session is started
+        let request = encode(hello(&identity, Bytes::new()));
+        socket.send_to(&request, server.address).unwrap();
+
+        let mut buf = vec![0u8; 65536];
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        assert!(len < request.len());
+        let cookie = match decode(&buf[..len]) {
+            Some(Message::Cookie(cookie)) => cookie.cookie,
+            _ => panic!("not a cookie"),
+        };
+
+        thread::sleep(Duration::from_millis(1500));
+        assert!(socket.recv_from(&mut buf).is_err(), "datagram repeated");
+
+        // the Hello is accepted with the cookie
+        socket.send_to(&encode(hello(&identity, cookie)), server.address).unwrap();
+        let mut answers = Vec::new();
+        while let Ok((len, _)) = socket.recv_from(&mut buf) {
+            if let Some(message) = decode(&buf[..len]) {
+                answers.push(message);
+            }
+            if answers.len() >= 2 {
+                break;
+            }
+        }
+        assert!(answers.iter().any(|m| match *m {
+            Message::HelloAck(_) => true,
+            _ => false,
+        }));
+        assert!(answers.iter().any(|m| match *m {
+            Message::Hello(_) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_migration() {
+        let (server, client, relay, inner, outer) =
+            session(TransportConfig::generate().unwrap(), TransportConfig::generate().unwrap());
+
+        // the client's address changes; its next datagrams trigger a
+        // challenge of the new address, answered over the session
+        relay.send(Command::Rebind).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+        let mut protocol_id = 0;
+        loop {
+            assert!(Instant::now() < deadline, "session not migrated");
+            client.send(inner, protocol_id);
+            protocol_id += 1;
+
+            match server.events.recv_timeout(Duration::from_millis(200)) {
+                Ok(Event::Migrated(TransportProtocol::Udp, from, to)) => {
+                    assert_eq!((outer[0], outer[1]), (from, to));
+                    break;
+                }
+                // the old address may still be in flight
+                Ok(Event::Message(_, address, ..)) => assert_eq!(outer[0], address),
+                _ => {}
+            }
+        }
+
+        // the session carries on at the new address
+        client.send(inner, 1000);
+        match server.expect(received(1000)) {
+            Event::Message(_, address, ..) => assert_eq!(outer[1], address),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_spoofed_migration() {
+        let (server, client, relay, inner, outer) =
+            session(TransportConfig::generate().unwrap(), TransportConfig::generate().unwrap());
+
+        // the server challenges the client's new address; the challenge
+        // is held back while a datagram with the session's connection ID
+        // arrives from elsewhere, a tick later
+        relay.send(Command::Hold(true)).unwrap();
+        relay.send(Command::Rebind).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        client.send(inner, 1);
+        thread::sleep(Duration::from_millis(1500));
+        relay.send(Command::Spoof).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // the answer to the first challenge still migrates the session
+        relay.send(Command::Hold(false)).unwrap();
+        match server.expect(|e| match *e {
+            Event::Migrated(..) => true,
+            _ => false,
+        }) {
+            Event::Migrated(TransportProtocol::Udp, from, to) => {
+                assert_eq!((outer[0], outer[1]), (from, to))
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
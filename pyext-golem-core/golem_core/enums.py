@@ -4,6 +4,8 @@ __all__ = (
     'LogLevel',
     'ErrorKind',
     'TransportProtocol',
+    'DisconnectReason',
+    'RefusalReason',
 )
 
 
@@ -50,3 +52,28 @@ class LogLevel(_IntConvertMixin, Enum):
 
     def __str__(self):
         return self.name.lower()
+
+
+class DisconnectReason(_IntConvertMixin, Enum):
+
+    Closed = 0
+    Requested = 1
+    ProtocolError = 2
+    HandshakeFailed = 3
+    HandshakeTimeout = 4
+    SelfConnection = 5
+    AuthenticationFailed = 6
+    Unauthenticated = 7
+    Banned = 8
+    ReadTimeout = 9
+    PuzzleFailed = 10
+    AccessDenied = 11
+    IdleTimeout = 12
+
+
+class RefusalReason(_IntConvertMixin, Enum):
+
+    Firewall = 0
+    TooManySessions = 1
+    TooManyFromAddress = 2
+    TooManyHandshakes = 3
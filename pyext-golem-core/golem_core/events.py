@@ -2,10 +2,10 @@ import inspect
 import sys
 
 from abc import ABCMeta
-from typing import Dict, List, Tuple, Type, Union
+from typing import Dict, List, Optional, Tuple, Type, Union
 
-from .enums import TransportProtocol, LogLevel
-from .structs import Encapsulated
+from .enums import DisconnectReason, LogLevel, RefusalReason, TransportProtocol
+from .structs import Encapsulated, PeerInfo, SignatureStatus
 
 
 __all__ = (
@@ -16,6 +16,9 @@ __all__ = (
     'Connected',
     'Disconnected',
     'Message',
+    'Refused',
+    'Migrated',
+    'PeerDiscovered',
     'Log',
 )
 
@@ -64,15 +67,27 @@ class Connected(TransportAndAddressEvent):
     def __init__(self,
                  transport_id: int,
                  address: Tuple[str, int],
-                 initiator: bool) -> None:
+                 initiator: bool,
+                 fingerprint: Optional[str],
+                 peer: Tuple[str, str, Tuple[Optional[int], Optional[int]]]) -> None:
 
         super().__init__(transport_id, address)
         self.initiator = initiator
+        self.fingerprint = fingerprint
+        self.peer = PeerInfo(*peer)
 
 
 class Disconnected(TransportAndAddressEvent):
     ID = 101
 
+    def __init__(self,
+                 transport_id: int,
+                 address: Tuple[str, int],
+                 reason: int) -> None:
+
+        super().__init__(transport_id, address)
+        self.reason = DisconnectReason.convert_from(reason)
+
 
 class Message(TransportAndAddressEvent):
     ID = 102
@@ -80,21 +95,66 @@ class Message(TransportAndAddressEvent):
     def __init__(self,
                  transport_id: int,
                  address: Tuple[str, int],
-                 encapsulated: Tuple[int, bytes]) -> None:
+                 encapsulated: Tuple[int, bytes],
+                 signature: Tuple[str, Optional[str], Optional[int],
+                                  Optional[bytes]]) -> None:
 
         super().__init__(transport_id, address)
         self.encapsulated = Encapsulated(*encapsulated)
+        self.signature = SignatureStatus(*signature)
+
+
+class Refused(TransportAndAddressEvent):
+    ID = 103
+
+    def __init__(self,
+                 transport_id: int,
+                 address: Tuple[str, int],
+                 reason: int) -> None:
+
+        super().__init__(transport_id, address)
+        self.reason = RefusalReason.convert_from(reason)
+
+
+class Migrated(TransportAndAddressEvent):
+    ID = 104
+
+    def __init__(self,
+                 transport_id: int,
+                 address: Tuple[str, int],
+                 new_address: Tuple[str, int]) -> None:
+
+        super().__init__(transport_id, address)
+        self.new_address = new_address
+
+
+class PeerDiscovered(Event):
+    ID = 105
+
+    def __init__(self,
+                 address: Tuple[str, int],
+                 peer: Tuple[str, str, Tuple[Optional[int], Optional[int]]]) -> None:
+
+        self.address = address
+        self.peer = PeerInfo(*peer)
 
 
 class Log(Event):
     ID = 200
 
     def __init__(self,
-                 log_level: LogLevel,
-                 message: str) -> None:
+                 log_level: int,
+                 message: str,
+                 origin: Optional[Tuple[int, Tuple[str, int]]]) -> None:
 
-        self.log_level = log_level
+        self.log_level = LogLevel.convert_from(log_level)
         self.message = message
+        self.transport_protocol = None
+        self.address = None
+
+        if origin:
+            transport_id, self.address = origin
+            self.transport_protocol = TransportProtocol.convert_from(transport_id)
 
 
 def _subclasses(cls) -> Dict[str, Type]:
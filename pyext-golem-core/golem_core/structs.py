@@ -1,5 +1,9 @@
+from typing import Optional, Tuple
+
 __all__ = (
     'Encapsulated',
+    'PeerInfo',
+    'SignatureStatus',
 )
 
 
@@ -8,3 +12,37 @@ class Encapsulated:
     def __init__(self, protocol_id: int, message: bytes) -> None:
         self.protocol_id = protocol_id
         self.message = message
+
+
+class PeerInfo:
+
+    def __init__(self,
+                 peer_id: str,
+                 version: str,
+                 listen_ports: Tuple[Optional[int], Optional[int]]) -> None:
+        self.peer_id = peer_id
+        self.version = version
+        self.tcp_port, self.udp_port = listen_ports
+
+
+class SignatureStatus:
+
+    UNSIGNED = 'unsigned'
+    VALID = 'valid'
+    INVALID = 'invalid'
+
+    def __init__(self,
+                 status: str,
+                 signer: Optional[str],
+                 timestamp: Optional[int],
+                 signature: Optional[bytes] = None) -> None:
+        self.status = status
+        self.signer = signer
+        self.timestamp = timestamp
+        # encoded signature, passed to `forward_signed` to forward the
+        # message along with it
+        self.signature = signature
+
+    @property
+    def valid(self) -> bool:
+        return self.status == self.VALID
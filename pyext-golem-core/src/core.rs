@@ -1,5 +1,7 @@
+use std::net::IpAddr;
 use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::Duration;
 
 use actix::prelude::*;
 use actix::Syn;
@@ -7,13 +9,22 @@ use cpython::*;
 use futures::Future;
 use spin;
 
+use net::error::{Error, ErrorKind, ErrorSeverity};
 use net::event::Event;
-use net::codec::message::{Encapsulated, Message};
+use net::codec::message::{Encapsulated, Message, Signature, Signed};
+use net::codec::psk::PreSharedKey;
+use net::identity::{verify_message, Identity, PeerId, SignatureStatus};
+use net::keystore::{KdfParams, Keystore};
+use net::network::acl::{AclEntry, AclRule};
 use net::network::NetworkCore;
+use net::network::session::{NodeStats, SessionStats};
 use net::transport::message::*;
 use net::transport::tcp::TcpTransport;
 use net::transport::udp::UdpTransport;
 use net::transport::*;
+use net::transport::config::TransportConfig;
+use net::transport::discovery::DiscoveryConfig;
+use net::transport::firewall::IpNet;
 
 use error::ModuleError;
 use python::*;
@@ -24,6 +35,13 @@ pub struct Core {
     pub network: Option<Addr<Syn, NetworkCore>>,
     // Makes the Receiver Sync; required to hand off execution to Python's VM
     pub rx: Option<Arc<spin::Mutex<mpsc::Receiver<Event>>>>,
+    /// Node identity loaded from a keystore; generated on `run` if not set.
+    /// Messages sent with `send_signed` are signed with it.
+    pub identity: Option<Arc<Identity>>,
+    /// LAN discovery started on `run`, if enabled
+    pub discovery: Option<DiscoveryConfig>,
+    /// Private network key both transports authenticate traffic with
+    pub psk: Option<PreSharedKey>,
 }
 
 impl Core {
@@ -40,19 +58,33 @@ impl Core {
         let (tx_queue, rx_queue) = mpsc::sync_channel(CHANNEL_SIZE);
         // start initialization channel
         let (tx, rx) = mpsc::channel();
+        let identity = match self.identity {
+            Some(ref identity) => identity.clone(),
+            None => Arc::new(Identity::generate()?),
+        };
+        self.identity = Some(identity.clone());
+        let discovery = self.discovery.clone();
+        let psk = self.psk.clone();
 
         // spawn the network thread
         thread::spawn(move || {
             let sys = System::new("net");
             let (unsync, syn) = NetworkCore::run(tx_queue);
+            let mut config = TransportConfig::new(identity);
+            config.discovery = discovery;
+            config.codec.psk = psk;
+            // both transports bind the same address
+            if address.port() != 0 {
+                config.listen_ports.udp = Some(address.port());
+            }
 
-            if let Err(e) = TcpTransport::run(unsync.clone(), address.clone()) {
+            if let Err(e) = TcpTransport::run(unsync.clone(), address.clone(), config.clone()) {
                 let e = ModuleError::from(e);
                 tx.send(Err(e)).ok();
                 return;
             }
 
-            if let Err(e) = UdpTransport::run(unsync.clone(), address.clone()) {
+            if let Err(e) = UdpTransport::run(unsync.clone(), address.clone(), config) {
                 let e = ModuleError::from(e);
                 tx.send(Err(e)).ok();
                 return;
@@ -91,6 +123,103 @@ impl Core {
     }
 }
 
+impl Core {
+    /// Saves a new node identity to an encrypted keystore and uses it on
+    /// `run`. Returns the peer id; an existing keystore is never replaced.
+    pub fn create_keystore(
+        &mut self,
+        py: Python,
+        py_path: PyString,
+        py_passphrase: PyString,
+    ) -> Result<String, ModuleError> {
+        let path: String = py_extract!(py, py_path)?;
+        let passphrase: String = py_extract!(py, py_passphrase)?;
+
+        let keystore = Keystore::generate()?;
+        keystore.create(&path, passphrase.as_bytes(), KdfParams::default())?;
+        Ok(self.use_identity(&keystore))
+    }
+
+    /// Announces the node to, and discovers peers on, the LAN over the
+    /// given multicast group once running
+    pub fn enable_discovery(
+        &mut self,
+        py: Python,
+        py_host: PyString,
+        py_port: PyLong,
+    ) -> Result<(), ModuleError> {
+        let group = to_socket_address(py, py_host, py_port)?;
+        if !group.ip().is_multicast() {
+            return Err(ModuleError::from(Error::new(
+                ErrorKind::Other,
+                ErrorSeverity::Low,
+                "not a multicast group address",
+            )));
+        }
+
+        self.discovery = Some(DiscoveryConfig {
+            group,
+            ..DiscoveryConfig::default()
+        });
+        Ok(())
+    }
+
+    /// Joins the private network of nodes sharing the secret once running;
+    /// traffic of other nodes is rejected
+    pub fn set_network_key(&mut self, py: Python, py_secret: PyString) -> Result<(), ModuleError> {
+        let secret: String = py_extract!(py, py_secret)?;
+        if secret.is_empty() {
+            return Err(ModuleError::from(Error::new(
+                ErrorKind::Other,
+                ErrorSeverity::Low,
+                "empty network key",
+            )));
+        }
+
+        self.psk = Some(PreSharedKey::new(secret.as_bytes()));
+        Ok(())
+    }
+
+    /// Loads the node identity from an encrypted keystore and uses it on
+    /// `run`. Returns the peer id.
+    pub fn load_keystore(
+        &mut self,
+        py: Python,
+        py_path: PyString,
+        py_passphrase: PyString,
+    ) -> Result<String, ModuleError> {
+        let path: String = py_extract!(py, py_path)?;
+        let passphrase: String = py_extract!(py, py_passphrase)?;
+
+        let keystore = Keystore::load(&path, passphrase.as_bytes())?;
+        Ok(self.use_identity(&keystore))
+    }
+
+    /// Replaces the keystore identity with a new one, signed by the old one,
+    /// and uses it on `run`. Returns the new peer id.
+    pub fn rotate_keystore(
+        &mut self,
+        py: Python,
+        py_path: PyString,
+        py_passphrase: PyString,
+    ) -> Result<String, ModuleError> {
+        let path: String = py_extract!(py, py_path)?;
+        let passphrase: String = py_extract!(py, py_passphrase)?;
+
+        let mut keystore = Keystore::load(&path, passphrase.as_bytes())?;
+        keystore.rotate()?;
+        keystore.save(&path, passphrase.as_bytes(), KdfParams::default())?;
+        Ok(self.use_identity(&keystore))
+    }
+
+    fn use_identity(&mut self, keystore: &Keystore) -> String {
+        let identity = keystore.identity();
+        let peer_id = identity.peer_id().to_hex();
+        self.identity = Some(identity);
+        peer_id
+    }
+}
+
 impl Core {
     pub fn connect(
         &self,
@@ -130,21 +259,239 @@ impl Core {
         py_message: PyBytes,
     ) -> Result<(), ModuleError> {
         let protocol: u16 = py_extract!(py, py_protocol)?;
-        let protocol_id: u16 = py_extract!(py, py_protocol_id)?;
+        let transport = TransportProtocol::from(protocol);
+        let message = Message::Encapsulated(to_encapsulated(py, py_protocol_id, py_message)?);
+
+        self.send_with(py, transport, py_host, py_port, message, Delivery::Unreliable)
+    }
+
+    /// Sends a message signed by the node identity, verifiable by every node
+    /// it is forwarded through
+    pub fn send_signed(
+        &self,
+        py: Python,
+        py_protocol: PyLong,
+        py_host: PyString,
+        py_port: PyLong,
+        py_protocol_id: PyLong,
+        py_message: PyBytes,
+    ) -> Result<(), ModuleError> {
+        let protocol: u16 = py_extract!(py, py_protocol)?;
+        let transport = TransportProtocol::from(protocol);
+        let encapsulated = to_encapsulated(py, py_protocol_id, py_message)?;
+
+        let signed = match self.identity {
+            Some(ref identity) => identity.sign_message(encapsulated),
+            None => return Err(ModuleError::not_running()),
+        };
+        self.send_with(py, transport, py_host, py_port, Message::Signed(signed), Delivery::Unreliable)
+    }
+
+    /// Forwards a message received with a valid signature, along with it
+    pub fn forward_signed(
+        &self,
+        py: Python,
+        py_protocol: PyLong,
+        py_host: PyString,
+        py_port: PyLong,
+        py_protocol_id: PyLong,
+        py_message: PyBytes,
+        py_signature: PyBytes,
+    ) -> Result<(), ModuleError> {
+        let protocol: u16 = py_extract!(py, py_protocol)?;
+        let transport = TransportProtocol::from(protocol);
+        let encapsulated = to_encapsulated(py, py_protocol_id, py_message)?;
+        let signature = Signature::decode(&py_signature.data(py).into()).map_err(Error::from)?;
+
+        let signed = Signed {
+            encapsulated,
+            signature,
+        };
+        if let SignatureStatus::Valid(..) = verify_message(&signed) {
+            return self.send_with(py, transport, py_host, py_port, Message::Signed(signed), Delivery::Unreliable);
+        }
+
+        Err(ModuleError::from(Error::new(
+            ErrorKind::Other,
+            ErrorSeverity::Low,
+            "invalid message signature",
+        )))
+    }
+
+    /// Sends a message over a UDP session, retransmitting it until
+    /// acknowledged
+    pub fn send_reliable(
+        &self,
+        py: Python,
+        py_host: PyString,
+        py_port: PyLong,
+        py_protocol_id: PyLong,
+        py_message: PyBytes,
+        py_ordered: PyBool,
+    ) -> Result<(), ModuleError> {
+        let ordered: bool = py_extract!(py, py_ordered)?;
+        let delivery = match ordered {
+            true => Delivery::Ordered,
+            false => Delivery::Reliable,
+        };
+        let message = Message::Encapsulated(to_encapsulated(py, py_protocol_id, py_message)?);
+
+        self.send_with(py, TransportProtocol::Udp, py_host, py_port, message, delivery)
+    }
+
+    fn send_with(
+        &self,
+        py: Python,
+        transport: TransportProtocol,
+        py_host: PyString,
+        py_port: PyLong,
+        message: Message,
+        delivery: Delivery,
+    ) -> Result<(), ModuleError> {
         let address = to_socket_address(py, py_host, py_port)?;
-        let message = py_message.data(py);
 
         self.forward(SendMessage {
-            transport: TransportProtocol::from(protocol),
+            transport,
             address,
-            message: Message::Encapsulated(Encapsulated {
-                protocol_id,
-                message: message.to_vec(),
-            }),
+            message,
+            delivery,
         })
     }
 }
 
+fn to_encapsulated(
+    py: Python,
+    py_protocol_id: PyLong,
+    py_message: PyBytes,
+) -> Result<Encapsulated, ModuleError> {
+    let protocol_id: u16 = py_extract!(py, py_protocol_id)?;
+    let message = py_message.data(py);
+
+    Ok(Encapsulated {
+        protocol_id,
+        message: message.into(),
+    })
+}
+
+impl Core {
+    /// Bans a host for `py_seconds`; 0 bans it until unbanned
+    pub fn ban(&self, py: Python, py_host: PyString, py_seconds: PyLong) -> Result<(), ModuleError> {
+        let host: String = py_extract!(py, py_host)?;
+        let seconds: u64 = py_extract!(py, py_seconds)?;
+        let address: IpAddr = host.parse()?;
+        let duration = match seconds {
+            0 => None,
+            s => Some(Duration::from_secs(s)),
+        };
+
+        self.forward(Ban { address, duration })
+    }
+
+    pub fn unban(&self, py: Python, py_host: PyString) -> Result<(), ModuleError> {
+        let host: String = py_extract!(py, py_host)?;
+        let address: IpAddr = host.parse()?;
+
+        self.forward(Unban { address })
+    }
+
+    /// Replaces the firewall's address ranges (CIDR): when `py_allow` is not
+    /// empty, only addresses in it are accepted; those in `py_deny` never
+    /// are. Sessions of refused addresses are closed.
+    pub fn set_firewall(&self, py: Python, py_allow: PyList, py_deny: PyList) -> Result<(), ModuleError> {
+        let allow: Vec<String> = py_extract!(py, py_allow)?;
+        let deny: Vec<String> = py_extract!(py, py_deny)?;
+
+        let allow = allow.iter().map(|r| r.parse::<IpNet>()).collect::<Result<Vec<_>, _>>()?;
+        let deny = deny.iter().map(|r| r.parse::<IpNet>()).collect::<Result<Vec<_>, _>>()?;
+        self.forward(SetFirewall { allow, deny })
+    }
+}
+
+impl Core {
+    /// Restricts a protocol id to the given peer ids, public keys (hex) and
+    /// address ranges (CIDR); ranges only match authenticated sessions
+    pub fn set_acl(
+        &self,
+        py: Python,
+        py_protocol_id: PyLong,
+        py_peers: PyList,
+        py_public_keys: PyList,
+        py_ranges: PyList,
+        py_disconnect: PyBool,
+    ) -> Result<(), ModuleError> {
+        let protocol_id: u16 = py_extract!(py, py_protocol_id)?;
+        let peers: Vec<String> = py_extract!(py, py_peers)?;
+        let public_keys: Vec<String> = py_extract!(py, py_public_keys)?;
+        let ranges: Vec<String> = py_extract!(py, py_ranges)?;
+        let disconnect: bool = py_extract!(py, py_disconnect)?;
+
+        let mut allowed = Vec::new();
+        for peer in peers {
+            let bytes = from_hex(&peer)?;
+            if bytes.len() != 32 {
+                return Err(ModuleError::from(Error::new(
+                    ErrorKind::Other,
+                    ErrorSeverity::Low,
+                    &format!("invalid peer id: {}", peer),
+                )));
+            }
+
+            let mut id = [0u8; 32];
+            id.copy_from_slice(&bytes);
+            allowed.push(AclRule::Peer(PeerId(id)));
+        }
+        for key in public_keys {
+            allowed.push(AclRule::PublicKey(from_hex(&key)?));
+        }
+        for range in ranges {
+            allowed.push(AclRule::Range(range.parse::<IpNet>()?));
+        }
+
+        let entry = Some(AclEntry {
+            allowed,
+            disconnect,
+        });
+        self.forward(SetAcl { protocol_id, entry })
+    }
+
+    /// Opens a protocol id to every sender
+    pub fn clear_acl(&self, py: Python, py_protocol_id: PyLong) -> Result<(), ModuleError> {
+        let protocol_id: u16 = py_extract!(py, py_protocol_id)?;
+        self.forward(SetAcl {
+            protocol_id,
+            entry: None,
+        })
+    }
+}
+
+impl Core {
+    pub fn session_stats(
+        &self,
+        py: Python,
+        py_protocol: PyLong,
+        py_host: PyString,
+        py_port: PyLong,
+    ) -> Result<SessionStats, ModuleError> {
+        let address = to_socket_address(py, py_host, py_port)?;
+        let protocol: u16 = py_extract!(py, py_protocol)?;
+        let transport = TransportProtocol::from(protocol);
+
+        match self.network {
+            Some(ref n) => Ok(n.send(GetSessionStats { transport, address }).wait()??),
+            None => Err(ModuleError::from(MailboxError::Closed)),
+        }
+    }
+}
+
+impl Core {
+    pub fn node_stats(&self) -> Result<NodeStats, ModuleError> {
+        match self.network {
+            Some(ref n) => Ok(n.send(GetNodeStats).wait()??),
+            None => Err(ModuleError::from(MailboxError::Closed)),
+        }
+    }
+}
+
 impl Core {
     fn forward<M>(&self, msg: M) -> Result<(), ModuleError>
     where
@@ -20,11 +20,14 @@ use cpython::*;
 
 use core::*;
 use error::ModuleError;
-use python::event_into;
+use python::{event_into, node_stats_into, stats_into};
 
 static mut CORE: Core = Core{
     network: None,
     rx: None,
+    identity: None,
+    discovery: None,
+    psk: None,
 };
 
 
@@ -58,6 +61,55 @@ py_class!(class CoreNetwork |py| {
         }
     }
 
+    def create_keystore(&self, path: PyString, passphrase: PyString) -> PyResult<String> {
+        unsafe {
+            match CORE.create_keystore(py, path, passphrase) {
+                Ok(peer_id) => Ok(peer_id),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def enable_discovery(&self, host: PyString, port: PyInt) -> PyResult<bool> {
+        unsafe {
+            match CORE.enable_discovery(py, host, port) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def set_network_key(&self, secret: PyString) -> PyResult<bool> {
+        unsafe {
+            if CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.set_network_key(py, secret) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def load_keystore(&self, path: PyString, passphrase: PyString) -> PyResult<String> {
+        unsafe {
+            match CORE.load_keystore(py, path, passphrase) {
+                Ok(peer_id) => Ok(peer_id),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def rotate_keystore(&self, path: PyString, passphrase: PyString) -> PyResult<String> {
+        unsafe {
+            match CORE.rotate_keystore(py, path, passphrase) {
+                Ok(peer_id) => Ok(peer_id),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
     def stop(&self) -> PyResult<bool> {
         unsafe {
             if !CORE.running() {
@@ -107,6 +159,120 @@ py_class!(class CoreNetwork |py| {
         }
     }
 
+    def ban(
+        &self,
+        host: PyString,
+        seconds: PyLong
+    ) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.ban(py, host, seconds) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def unban(
+        &self,
+        host: PyString
+    ) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.unban(py, host) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def set_firewall(
+        &self,
+        allow: PyList,
+        deny: PyList
+    ) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.set_firewall(py, allow, deny) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def session_stats(
+        &self,
+        protocol: PyLong,
+        host: PyString,
+        port: PyLong
+    ) -> PyResult<PyTuple> {
+        unsafe {
+            if !CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.session_stats(py, protocol, host, port) {
+                Ok(stats) => Ok(stats_into(py, stats)),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def set_acl(
+        &self,
+        protocol_id: PyLong,
+        peers: PyList,
+        public_keys: PyList,
+        ranges: PyList,
+        disconnect: PyBool
+    ) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.set_acl(py, protocol_id, peers, public_keys, ranges, disconnect) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def clear_acl(&self, protocol_id: PyLong) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.clear_acl(py, protocol_id) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def node_stats(&self) -> PyResult<PyTuple> {
+        unsafe {
+            if !CORE.running() {
+                return Err(ModuleError::not_running().into());
+            }
+
+            match CORE.node_stats() {
+                Ok(stats) => Ok(node_stats_into(py, stats)),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
     def send(
         &self,
         protocol: PyLong,
@@ -127,6 +293,67 @@ py_class!(class CoreNetwork |py| {
         }
     }
 
+    def send_signed(
+        &self,
+        protocol: PyLong,
+        host: PyString,
+        port: PyLong,
+        protocol_id: PyLong,
+        message: PyBytes
+    ) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Ok(false);
+            }
+
+            match CORE.send_signed(py, protocol, host, port, protocol_id, message) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def forward_signed(
+        &self,
+        protocol: PyLong,
+        host: PyString,
+        port: PyLong,
+        protocol_id: PyLong,
+        message: PyBytes,
+        signature: PyBytes
+    ) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Ok(false);
+            }
+
+            match CORE.forward_signed(py, protocol, host, port, protocol_id, message, signature) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
+    def send_reliable(
+        &self,
+        host: PyString,
+        port: PyLong,
+        protocol_id: PyLong,
+        message: PyBytes,
+        ordered: PyBool
+    ) -> PyResult<bool> {
+        unsafe {
+            if !CORE.running() {
+                return Ok(false);
+            }
+
+            match CORE.send_reliable(py, host, port, protocol_id, message, ordered) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+
     def poll(&self, timeout: PyLong) -> PyResult<Option<PyTuple>> {
         unsafe {
             if !CORE.running() {
@@ -1,10 +1,15 @@
 use std::error::Error;
 use std::net::SocketAddr;
+use std::str;
 use std::sync::{Arc, Mutex};
 
 use cpython::*;
+use net::error::{ErrorKind, ErrorSeverity};
 use net::socket_address;
 use net::event::Event;
+use net::codec::message::Signature;
+use net::identity::SignatureStatus;
+use net::network::session::{NodeStats, SessionStats};
 
 use error::*;
 use logging::*;
@@ -94,6 +99,50 @@ pub fn from_socket_address(py: Python, address: SocketAddr) -> (PyString, PyInt)
     (py_host, py_port)
 }
 
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, ModuleError> {
+    let invalid = || {
+        let message = format!("invalid hex string: {}", hex);
+        ModuleError::from(net::error::Error::new(ErrorKind::Other, ErrorSeverity::Low, &message))
+    };
+
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digits = str::from_utf8(pair).map_err(|_| invalid())?;
+            u8::from_str_radix(digits, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+/// (status, signer, timestamp, signature), where status is "unsigned",
+/// "valid" or "invalid" and signature the encoded one, to forward the
+/// message with
+fn signature_status(
+    py: Python,
+    status: SignatureStatus,
+    signature: Option<Signature>,
+) -> (&'static str, Option<String>, Option<u64>, Option<PyBytes>) {
+    let signature = signature
+        .and_then(|s| s.encode().ok())
+        .map(|bytes| PyBytes::new(py, &bytes[..]));
+
+    match status {
+        SignatureStatus::Unsigned => ("unsigned", None, None, None),
+        SignatureStatus::Valid(signer, timestamp) => {
+            ("valid", Some(signer.to_hex()), Some(timestamp), signature)
+        }
+        SignatureStatus::Invalid(signer) => ("invalid", Some(signer.to_hex()), None, signature),
+    }
+}
+
 // Event
 
 struct EventWrapper {
@@ -118,22 +167,47 @@ impl ToPyObject for EventWrapper {
             Event::Stopped(transport, address) => {
                 py_wrap!(py, (2, transport as u16, host_port(&address)))
             }
-            Event::Connected(transport, address, initiator) => {
-                py_wrap!(py, (100, transport as u16, host_port(&address), initiator))
+            Event::Connected(transport, address, initiator, fingerprint, peer) => {
+                let fingerprint = fingerprint.map(|f| to_hex(&f));
+                let ports = (peer.listen_ports.tcp, peer.listen_ports.udp);
+                let peer = (peer.peer_id.to_hex(), peer.version, ports);
+                py_wrap!(py, (100, transport as u16, host_port(&address), initiator, fingerprint, peer))
             }
-            Event::Disconnected(transport, address) => {
-                py_wrap!(py, (101, transport as u16, host_port(&address)))
+            Event::Disconnected(transport, address, reason) => {
+                py_wrap!(py, (101, transport as u16, host_port(&address), reason as u16))
             }
-            Event::Message(transport, address, encapsulated) => {
+            Event::Message(transport, address, encapsulated, status, signature) => {
                 let bytes: PyBytes = PyBytes::new(py, &encapsulated.message[..]);
                 let message = py_wrap!(py, (encapsulated.protocol_id, bytes));
-                py_wrap!(py, (102, transport as u16, host_port(&address), message))
+                let signature = signature_status(py, status, signature);
+                py_wrap!(py, (102, transport as u16, host_port(&address), message, signature))
             }
-            Event::Error(e) => {
+            Event::Typed(transport, address, typed, status, signature) => {
+                let encapsulated = typed.raw;
+                let bytes: PyBytes = PyBytes::new(py, &encapsulated.message[..]);
+                let message = py_wrap!(py, (encapsulated.protocol_id, bytes));
+                let signature = signature_status(py, status, signature);
+                py_wrap!(py, (102, transport as u16, host_port(&address), message, signature))
+            }
+            Event::Error(origin, e) => {
                 let level = LogLevel::from(e.severity);
                 let message = e.description();
-                py_wrap!(py, (200, level, message))
+                let origin = origin.map(|(transport, address)| {
+                    (transport as u16, host_port(&address))
+                });
+                py_wrap!(py, (200, level, message, origin))
             },
+            Event::Refused(transport, address, reason) => {
+                py_wrap!(py, (103, transport as u16, host_port(&address), reason as u16))
+            }
+            Event::Migrated(transport, from, to) => {
+                py_wrap!(py, (104, transport as u16, host_port(&from), host_port(&to)))
+            }
+            Event::PeerDiscovered(address, peer) => {
+                let ports = (peer.listen_ports.tcp, peer.listen_ports.udp);
+                let peer = (peer.peer_id.to_hex(), peer.version, ports);
+                py_wrap!(py, (105, host_port(&address), peer))
+            }
         }
     }
 }
@@ -142,6 +216,31 @@ pub fn event_into(py: Python, event: Event) -> PyTuple {
     EventWrapper{ event }.into_py_object(py)
 }
 
+// Stats
+
+pub fn stats_into(py: Python, stats: SessionStats) -> PyTuple {
+    let compression = stats.compression.map(|c| format!("{:?}", c).to_lowercase());
+    let c = stats.compression_stats;
+    // UDP sessions only
+    let window = stats.congestion.map(|c| c.window);
+    let path_mtu = stats.congestion.map(|c| c.path_mtu);
+
+    py_wrap!(py, (
+        stats.peer_id.to_hex(),
+        compression,
+        c.frames_compressed_tx,
+        c.bytes_saved_tx,
+        c.frames_compressed_rx,
+        c.bytes_saved_rx,
+        window,
+        path_mtu
+    ))
+}
+
+pub fn node_stats_into(py: Python, stats: NodeStats) -> PyTuple {
+    py_wrap!(py, (stats.sessions, stats.handshakes, stats.puzzle_difficulty))
+}
+
 //
 // Tests
 //
@@ -0,0 +1,109 @@
+import sys
+import types
+import unittest
+
+try:
+    import libgolem_core  # noqa: F401
+except ImportError:
+    # events are plain tuples; the extension is not needed to convert them
+    stub = types.ModuleType('libgolem_core')
+    stub.CoreNetwork = stub.CoreError = None
+    sys.modules['libgolem_core'] = stub
+
+from golem_core.enums import (
+    DisconnectReason, LogLevel, RefusalReason, TransportProtocol
+)
+from golem_core.events import (
+    Connected, Disconnected, Event, Exiting, Log, Message, Migrated,
+    PeerDiscovered, Refused, Started, Stopped
+)
+
+
+ADDRESS = ('10.0.0.1', 40102)
+PEER = ('ab' * 32, '0.1.0', (40102, None))
+
+
+class TestEvents(unittest.TestCase):
+
+    def test_exiting(self):
+        self.assertIsInstance(Event.convert_from((0,)), Exiting)
+
+    def test_started_stopped(self):
+        for cls, event_id in ((Started, 1), (Stopped, 2)):
+            event = Event.convert_from((event_id, 17, ADDRESS))
+            self.assertIsInstance(event, cls)
+            self.assertEqual(event.transport_protocol, TransportProtocol.Udp)
+            self.assertEqual(event.address, ADDRESS)
+
+    def test_connected(self):
+        event = Event.convert_from((100, 6, ADDRESS, True, 'ff' * 32, PEER))
+
+        self.assertIsInstance(event, Connected)
+        self.assertEqual(event.transport_protocol, TransportProtocol.Tcp)
+        self.assertTrue(event.initiator)
+        self.assertEqual(event.fingerprint, 'ff' * 32)
+        self.assertEqual(event.peer.peer_id, 'ab' * 32)
+        self.assertEqual(event.peer.version, '0.1.0')
+        self.assertEqual(event.peer.tcp_port, 40102)
+        self.assertIsNone(event.peer.udp_port)
+
+    def test_disconnected(self):
+        event = Event.convert_from((101, 17, ADDRESS, 12))
+
+        self.assertIsInstance(event, Disconnected)
+        self.assertEqual(event.reason, DisconnectReason.IdleTimeout)
+
+    def test_message(self):
+        signature = ('valid', 'ab' * 32, 1500000000, b'signature')
+        event = Event.convert_from((102, 6, ADDRESS, (7, b'payload'), signature))
+
+        self.assertIsInstance(event, Message)
+        self.assertEqual(event.encapsulated.protocol_id, 7)
+        self.assertEqual(event.encapsulated.message, b'payload')
+        self.assertTrue(event.signature.valid)
+        self.assertEqual(event.signature.signer, 'ab' * 32)
+        self.assertEqual(event.signature.timestamp, 1500000000)
+        self.assertEqual(event.signature.signature, b'signature')
+
+        unsigned = ('unsigned', None, None, None)
+        event = Event.convert_from((102, 6, ADDRESS, (7, b''), unsigned))
+        self.assertFalse(event.signature.valid)
+        self.assertIsNone(event.signature.signature)
+
+    def test_refused(self):
+        event = Event.convert_from((103, 6, ADDRESS, 3))
+
+        self.assertIsInstance(event, Refused)
+        self.assertEqual(event.reason, RefusalReason.TooManyHandshakes)
+
+    def test_migrated(self):
+        new_address = ('10.0.0.2', 40103)
+        event = Event.convert_from((104, 17, ADDRESS, new_address))
+
+        self.assertIsInstance(event, Migrated)
+        self.assertEqual(event.address, ADDRESS)
+        self.assertEqual(event.new_address, new_address)
+
+    def test_peer_discovered(self):
+        event = Event.convert_from((105, ADDRESS, PEER))
+
+        self.assertIsInstance(event, PeerDiscovered)
+        self.assertEqual(event.address, ADDRESS)
+        self.assertEqual(event.peer.peer_id, 'ab' * 32)
+
+    def test_log(self):
+        event = Event.convert_from((200, 2, 'message', (6, ADDRESS)))
+
+        self.assertIsInstance(event, Log)
+        self.assertEqual(event.log_level, LogLevel.Warning)
+        self.assertEqual(event.message, 'message')
+        self.assertEqual(event.transport_protocol, TransportProtocol.Tcp)
+        self.assertEqual(event.address, ADDRESS)
+
+        event = Event.convert_from((200, 3, 'message', None))
+        self.assertIsNone(event.transport_protocol)
+        self.assertIsNone(event.address)
+
+
+if __name__ == '__main__':
+    unittest.main()